@@ -0,0 +1,63 @@
+extern crate cc;
+
+use std::env;
+use std::process::Command;
+
+// (prefix variable of llvm-sys, suffix of the versioned llvm-config) for the version
+// selected by the llvm-* feature
+fn llvm_version() -> (&'static str, &'static str) {
+    let versions = [
+        ("CARGO_FEATURE_LLVM_15", "LLVM_SYS_150_PREFIX", "15"),
+        ("CARGO_FEATURE_LLVM_16", "LLVM_SYS_160_PREFIX", "16"),
+        ("CARGO_FEATURE_LLVM_17", "LLVM_SYS_170_PREFIX", "17"),
+        ("CARGO_FEATURE_LLVM_18", "LLVM_SYS_181_PREFIX", "18"),
+    ];
+    for &(feature, var, version) in &versions {
+        if env::var(feature).is_ok() {
+            return (var, version);
+        }
+    }
+    ("LLVM_SYS_50_PREFIX", "5.0")
+}
+
+// the same llvm-config as llvm-sys uses
+fn llvm_config(arg: &str) -> String {
+    let (var, version) = llvm_version();
+    let paths = match env::var(var) {
+        Ok(prefix) => vec![format!("{}/bin/llvm-config", prefix)],
+        // distributions install llvm-config-<version> for each version
+        Err(_) => vec![format!("llvm-config-{}", version), "llvm-config".to_owned()],
+    };
+    for path in paths {
+        if let Ok(output) = Command::new(path).arg(arg).output() {
+            return String::from_utf8(output.stdout).unwrap();
+        }
+    }
+    panic!("failed to run llvm-config");
+}
+
+fn main() {
+    // the shims are only for the LLVM backend
+    if env::var("CARGO_FEATURE_LLVM").is_err() {
+        return;
+    }
+
+    let mut build = cc::Build::new();
+    build
+        .cpp(true)
+        .file("src/shim/gdb.cpp")
+        .file("src/shim/cache.cpp");
+    for flag in llvm_config("--cxxflags").split_whitespace() {
+        build.flag(flag);
+    }
+    build.compile("cbpf_shim");
+
+    // the object cache is invalidated when LLVM is updated
+    println!(
+        "cargo:rustc-env=CBPF_LLVM_VERSION={}",
+        llvm_config("--version").trim()
+    );
+    println!("cargo:rerun-if-changed=src/shim/gdb.cpp");
+    println!("cargo:rerun-if-changed=src/shim/cache.cpp");
+    println!("cargo:rerun-if-env-changed={}", llvm_version().0);
+}
@@ -0,0 +1,42 @@
+#ifndef CBPF_JIT_H
+#define CBPF_JIT_H
+
+/* Warning, this file is autogenerated by cbindgen. Don't modify this manually. */
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#if defined(__linux__)
+#include <linux/filter.h>
+#else
+/* the same layout as struct sock_filter of <linux/filter.h> and struct bpf_insn of libpcap */
+struct sock_filter {
+  uint16_t code;
+  uint8_t jt;
+  uint8_t jf;
+  uint32_t k;
+};
+#endif
+
+
+typedef struct cbpf_jit cbpf_jit;
+
+typedef struct cbpf_jit_options {
+  bool optimize;
+  bool cbpf_optimize;
+  const char *name;
+  bool perf_map;
+  bool gdb_jit;
+  const char *cache_dir;
+} cbpf_jit_options;
+
+cbpf_jit *cbpf_jit_compile(const struct sock_filter *insns, size_t len, const cbpf_jit_options *options, char **err);
+
+void cbpf_jit_free(cbpf_jit *handle);
+
+void cbpf_jit_free_error(char *err);
+
+uint32_t cbpf_jit_run(const cbpf_jit *handle, const uint8_t *data, size_t len);
+
+#endif /* CBPF_JIT_H */
@@ -0,0 +1,311 @@
+// static analysis of a cBPF program
+//
+// This reports what a filter touches before it is deployed: the packet bytes it reads,
+// the values it can return, the instructions which can never be executed, the longest
+// path and the scratch memory slots it uses.
+
+use cbpf::opcode::*;
+use bounds::{self, load_size};
+use cfg;
+use frontend;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterReport {
+    // [start, end) of bytes read with absolute offsets (including `ldx msh`)
+    pub abs_ranges: Vec<(u64, u64)>,
+    // [start, end) of bytes read relative to X
+    pub ind_ranges: Vec<(u64, u64)>,
+    // minimum packet length to read all bytes the filter may read, if it is known
+    pub snaplen: Option<u64>,
+    // [lo, hi] of possible return values
+    pub returns: Vec<(u32, u32)>,
+    pub unreachable: Vec<usize>,
+    // number of instructions on the longest path
+    pub max_depth: usize,
+    pub scratch: Vec<u32>,
+}
+
+impl FilterReport {
+    pub fn to_json(&self) -> String {
+        let ranges = |rs: &[(u64, u64)]| {
+            rs.iter()
+                .map(|&(s, e)| format!("{{\"start\":{},\"end\":{}}}", s, e))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        let returns = self.returns
+            .iter()
+            .map(|&(lo, hi)| format!("{{\"lo\":{},\"hi\":{}}}", lo, hi))
+            .collect::<Vec<_>>()
+            .join(",");
+        let list = |v: Vec<String>| v.join(",");
+        format!(
+            "{{\"abs_ranges\":[{}],\"ind_ranges\":[{}],\"snaplen\":{},\"returns\":[{}],\
+             \"unreachable\":[{}],\"max_depth\":{},\"scratch\":[{}]}}",
+            ranges(&self.abs_ranges),
+            ranges(&self.ind_ranges),
+            self.snaplen
+                .map_or_else(|| "null".to_string(), |l| l.to_string()),
+            returns,
+            list(self.unreachable.iter().map(|i| i.to_string()).collect()),
+            self.max_depth,
+            list(self.scratch.iter().map(|i| i.to_string()).collect()),
+        )
+    }
+}
+
+impl std::fmt::Display for FilterReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let ranges = |rs: &[(u64, u64)], prefix: &str| {
+            rs.iter()
+                .map(|&(s, e)| format!("{}[{}:{}]", prefix, s, e))
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        writeln!(f, "absolute reads: {}", ranges(&self.abs_ranges, ""))?;
+        writeln!(f, "indirect reads: {}", ranges(&self.ind_ranges, "X+"))?;
+        match self.snaplen {
+            Some(l) => writeln!(f, "snaplen: {}", l)?,
+            None => writeln!(f, "snaplen: unknown")?,
+        }
+        let returns = self.returns
+            .iter()
+            .map(|&(lo, hi)| {
+                if lo == hi {
+                    format!("{}", lo)
+                } else {
+                    format!("{}..{}", lo, hi)
+                }
+            })
+            .collect::<Vec<_>>();
+        writeln!(f, "returns: {}", returns.join(" "))?;
+        writeln!(f, "unreachable: {:?}", self.unreachable)?;
+        writeln!(f, "max depth: {}", self.max_depth)?;
+        write!(f, "scratch: {:?}", self.scratch)
+    }
+}
+
+// sort and merge overlapping or adjacent ranges
+fn coalesce<T: Ord + Copy>(mut rs: Vec<(T, T)>, adjacent: fn(T, T) -> bool) -> Vec<(T, T)> {
+    rs.sort();
+    let mut result: Vec<(T, T)> = vec![];
+    for (s, e) in rs {
+        match result.last_mut() {
+            Some(last) if adjacent(last.1, s) => {
+                last.1 = std::cmp::max(last.1, e);
+                continue;
+            }
+            _ => {}
+        }
+        result.push((s, e));
+    }
+    result
+}
+
+pub fn analyze(insns: &[BpfInsn]) -> Result<FilterReport, String> {
+    frontend::decode(insns)?;
+
+    let n = insns.len();
+    let ranges = bounds::ranges(insns);
+
+    let mut abs_ranges = vec![];
+    let mut ind_ranges = vec![];
+    let mut snaplen = Some(0);
+    let mut returns = vec![];
+    let mut unreachable = vec![];
+    let mut scratch = vec![];
+
+    for (i, insn) in insns.iter().enumerate() {
+        let code = insn.code;
+        let k = insn.k as u64;
+        let r = match ranges[i] {
+            Some(ref r) => r,
+            None => {
+                unreachable.push(i);
+                continue;
+            }
+        };
+        let read = match (bpf_class(code), bpf_mode(code)) {
+            (BPF_LD, BPF_ABS) => {
+                let end = k + load_size(bpf_size(code));
+                abs_ranges.push((k, end));
+                Some(end)
+            }
+            (BPF_LD, BPF_IND) => {
+                let size = load_size(bpf_size(code));
+                ind_ranges.push((k, k + size));
+                if r.x.hi + k > u32::max_value() as u64 {
+                    // the offset may overflow
+                    None
+                } else {
+                    Some(r.x.hi + k + size)
+                }
+            }
+            (BPF_LDX, BPF_MSH) => {
+                abs_ranges.push((k, k + 1));
+                Some(k + 1)
+            }
+            _ => Some(0),
+        };
+        snaplen = match (snaplen, read) {
+            (Some(a), Some(b)) => Some(std::cmp::max(a, b)),
+            _ => None,
+        };
+
+        match (bpf_class(code), bpf_mode(code)) {
+            (BPF_LD, BPF_MEM) | (BPF_LDX, BPF_MEM) | (BPF_ST, _) | (BPF_STX, _) => {
+                scratch.push(insn.k)
+            }
+            _ => {}
+        }
+
+        // the filter returns 0 when a packet load is out of bounds or the divisor is 0
+        let faults = match (bpf_class(code), bpf_mode(code)) {
+            (BPF_LD, BPF_ABS) | (BPF_LD, BPF_IND) | (BPF_LDX, BPF_MSH) => true,
+            (BPF_ALU, _) if bpf_op(code) == BPF_DIV || bpf_op(code) == BPF_MOD => {
+                if bpf_src(code) == BPF_X {
+                    r.x.lo == 0
+                } else {
+                    insn.k == 0
+                }
+            }
+            _ => false,
+        };
+        if faults {
+            returns.push((0, 0));
+        }
+
+        if bpf_class(code) == BPF_RET {
+            if bpf_rval(code) == BPF_A {
+                returns.push((r.a.lo as u32, r.a.hi as u32));
+            } else {
+                returns.push((insn.k, insn.k));
+            }
+        }
+    }
+
+    // jumps are always forward, so the longest path is computed in one backward pass
+    let mut depth = vec![0usize; n];
+    for i in (0..n).rev() {
+        depth[i] = 1 + cfg::successors(insns, i)
+            .into_iter()
+            .filter(|&succ| succ < n)
+            .map(|succ| depth[succ])
+            .max()
+            .unwrap_or(0);
+    }
+
+    scratch.sort();
+    scratch.dedup();
+
+    Ok(FilterReport {
+        abs_ranges: coalesce(abs_ranges, |end, start| start <= end),
+        ind_ranges: coalesce(ind_ranges, |end, start| start <= end),
+        snaplen,
+        returns: coalesce(returns, |hi, lo| lo <= hi.saturating_add(1)),
+        unreachable,
+        max_depth: depth.first().cloned().unwrap_or(0),
+        scratch,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report() {
+        // ldh [12]; jeq #0x800, L1, L4; L1: ldx msh [14]; ldh [x + 16]; jeq #80, L2, L4;
+        // L2: st M[3]; ret #0xffff; ja L4; L4: ret #0
+        let insns = [
+            BpfInsn::new(BPF_LD_H_ABS, 0, 0, 12),
+            BpfInsn::new(BPF_JEQ_K, 0, 6, 0x0800),
+            BpfInsn::new(BPF_LDX_B_MSH, 0, 0, 14),
+            BpfInsn::new(BPF_LD_H_IND, 0, 0, 16),
+            BpfInsn::new(BPF_JEQ_K, 0, 3, 80),
+            BpfInsn::new(BPF_ST, 0, 0, 3),
+            BpfInsn::new(BPF_RET_K, 0, 0, 0xffff),
+            BpfInsn::new(BPF_JMP_JA, 0, 0, 0),
+            BpfInsn::new(BPF_RET_K, 0, 0, 0),
+        ];
+        let report = analyze(&insns).unwrap();
+        assert_eq!(report.abs_ranges, vec![(12, 15)]);
+        assert_eq!(report.ind_ranges, vec![(16, 18)]);
+        assert_eq!(report.snaplen, Some(78));
+        assert_eq!(report.returns, vec![(0, 0), (0xffff, 0xffff)]);
+        assert_eq!(report.unreachable, vec![7]);
+        assert_eq!(report.max_depth, 7);
+        assert_eq!(report.scratch, vec![3]);
+        assert_eq!(
+            report.to_json(),
+            "{\"abs_ranges\":[{\"start\":12,\"end\":15}],\
+             \"ind_ranges\":[{\"start\":16,\"end\":18}],\"snaplen\":78,\
+             \"returns\":[{\"lo\":0,\"hi\":0},{\"lo\":65535,\"hi\":65535}],\
+             \"unreachable\":[7],\"max_depth\":7,\"scratch\":[3]}"
+        );
+    }
+
+    #[test]
+    fn return_a() {
+        // ldb [0]; and #0xf; ret a
+        let insns = [
+            BpfInsn::new(BPF_LD_B_ABS, 0, 0, 0),
+            BpfInsn::new(BPF_AND_K, 0, 0, 0xf),
+            BpfInsn::new(BPF_RET_A, 0, 0, 0),
+        ];
+        let report = analyze(&insns).unwrap();
+        assert_eq!(report.returns, vec![(0, 0xf)]);
+        assert_eq!(report.snaplen, Some(1));
+    }
+
+    #[test]
+    fn implicit_zero() {
+        // ldh [12]; ret #0xffff
+        let insns = [
+            BpfInsn::new(BPF_LD_H_ABS, 0, 0, 12),
+            BpfInsn::new(BPF_RET_K, 0, 0, 0xffff),
+        ];
+        assert_eq!(analyze(&insns).unwrap().returns, vec![(0, 0), (0xffff, 0xffff)]);
+
+        // ld #10; tax; ld #100; div x; ret #2
+        let mut insns = vec![
+            BpfInsn::new(BPF_LD_IMM, 0, 0, 10),
+            BpfInsn::new(BPF_MISC_TAX, 0, 0, 0),
+            BpfInsn::new(BPF_LD_IMM, 0, 0, 100),
+            BpfInsn::new(BPF_DIV_X, 0, 0, 0),
+            BpfInsn::new(BPF_RET_K, 0, 0, 2),
+        ];
+        assert_eq!(analyze(&insns).unwrap().returns, vec![(2, 2)]);
+        // X is 0 without tax
+        insns.remove(1);
+        assert_eq!(analyze(&insns).unwrap().returns, vec![(0, 0), (2, 2)]);
+
+        // ld #100; mod #0; ret #2
+        let insns = [
+            BpfInsn::new(BPF_LD_IMM, 0, 0, 100),
+            BpfInsn::new(BPF_ALU | BPF_MOD | BPF_K, 0, 0, 0),
+            BpfInsn::new(BPF_RET_K, 0, 0, 2),
+        ];
+        assert_eq!(analyze(&insns).unwrap().returns, vec![(0, 0), (2, 2)]);
+    }
+
+    #[test]
+    fn invalid() {
+        let invalid: Vec<Vec<BpfInsn>> = vec![
+            vec![],
+            // ld [0] of an invalid size
+            vec![
+                BpfInsn::new(BPF_LD | 0x18 | BPF_ABS, 0, 0, 0),
+                BpfInsn::new(BPF_RET_A, 0, 0, 0),
+            ],
+            // jeq out of the program
+            vec![
+                BpfInsn::new(BPF_JEQ_K, 0, 5, 0),
+                BpfInsn::new(BPF_RET_K, 0, 0, 0),
+            ],
+        ];
+        for insns in &invalid {
+            assert!(analyze(insns).is_err());
+        }
+    }
+}
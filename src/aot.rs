@@ -0,0 +1,153 @@
+// ahead-of-time compilation
+//
+// Each filter is compiled to an object file which defines
+// `uint32_t filter_<name>(const uint8_t *pkt, uint32_t len)` (see `Converter::emit_object()`),
+// and the objects are bundled into a static library with `ar`.
+
+#[cfg(feature = "llvm")]
+use llvm::target_machine::*;
+use std::env;
+#[cfg(feature = "llvm")]
+use std::ffi::{CStr, CString};
+use std::process::Command;
+#[cfg(feature = "llvm")]
+use std::ptr;
+
+// target machine of the host
+#[cfg(feature = "llvm")]
+pub fn target_machine(
+    reloc: LLVMRelocMode,
+    code_model: LLVMCodeModel,
+) -> Result<LLVMTargetMachineRef, String> {
+    unsafe {
+        ::llvm::target::LLVM_InitializeNativeTarget();
+        ::llvm::target::LLVM_InitializeNativeAsmPrinter();
+
+        let triple = LLVMGetDefaultTargetTriple();
+        let host = CStr::from_ptr(triple).to_string_lossy().into_owned();
+        ::llvm::core::LLVMDisposeMessage(triple);
+        target_machine_for(&host, reloc, code_model)
+    }
+}
+
+// target machine of wasm32-unknown-unknown. the WebAssembly backend of LLVM 5 is
+// experimental and not built by default
+#[cfg(all(feature = "llvm", not(feature = "llvm-5")))]
+pub fn wasm_target_machine() -> Result<LLVMTargetMachineRef, String> {
+    unsafe {
+        ::llvm::target::LLVMInitializeWebAssemblyTargetInfo();
+        ::llvm::target::LLVMInitializeWebAssemblyTarget();
+        ::llvm::target::LLVMInitializeWebAssemblyTargetMC();
+        ::llvm::target::LLVMInitializeWebAssemblyAsmPrinter();
+    }
+    target_machine_for(
+        "wasm32-unknown-unknown",
+        LLVMRelocMode::LLVMRelocDefault,
+        LLVMCodeModel::LLVMCodeModelDefault,
+    )
+}
+
+#[cfg(feature = "llvm-5")]
+pub fn wasm_target_machine() -> Result<LLVMTargetMachineRef, String> {
+    Err("the WebAssembly backend is not available in LLVM 5".to_owned())
+}
+
+// target machine of `triple`, whose target must be initialized
+#[cfg(feature = "llvm")]
+pub fn target_machine_for(
+    triple: &str,
+    reloc: LLVMRelocMode,
+    code_model: LLVMCodeModel,
+) -> Result<LLVMTargetMachineRef, String> {
+    let triple = CString::new(triple).unwrap();
+    unsafe {
+        let mut target = ptr::null_mut();
+        let mut err_msg = ptr::null_mut();
+        if LLVMGetTargetFromTriple(triple.as_ptr(), &mut target, &mut err_msg) != 0 {
+            let err = CStr::from_ptr(err_msg).to_string_lossy().into_owned();
+            ::llvm::core::LLVMDisposeMessage(err_msg);
+            return Err(err);
+        }
+        let tm = LLVMCreateTargetMachine(
+            target,
+            triple.as_ptr(),
+            cstr!(),
+            cstr!(),
+            LLVMCodeGenOptLevel::LLVMCodeGenLevelDefault,
+            reloc,
+            code_model,
+        );
+        if tm.is_null() {
+            return Err("failed to create a target machine".to_owned());
+        }
+        Ok(tm)
+    }
+}
+
+// whether `name` can be a part of C function names
+pub fn is_identifier(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+pub fn function_name(name: &str) -> String {
+    format!("filter_{}", name)
+}
+
+// C header declaring the filters. `filters` is a list of (name, expression)
+pub fn header(guard: &str, filters: &[(String, String)]) -> String {
+    let mut h = String::new();
+    h += &format!("#ifndef {0}\n#define {0}\n\n", guard);
+    h += "#include <stdint.h>\n\n";
+    h += "#ifdef __cplusplus\nextern \"C\" {\n#endif\n\n";
+    for &(ref name, ref expression) in filters {
+        h += &format!("/* {} */\n", expression.replace("*/", "* /"));
+        h += &format!(
+            "uint32_t {}(const uint8_t *pkt, uint32_t len);\n\n",
+            function_name(name)
+        );
+    }
+    h += "#ifdef __cplusplus\n}\n#endif\n\n";
+    h += &format!("#endif /* {} */\n", guard);
+    h
+}
+
+// bundle the objects into a static library with `ar` (or $AR)
+pub fn archive(lib: &str, objects: &[String]) -> Result<(), String> {
+    let ar = env::var("AR").unwrap_or_else(|_| "ar".to_owned());
+    // `ar r` adds members to an existing archive
+    let _ = ::std::fs::remove_file(lib);
+    let status = Command::new(&ar)
+        .arg("rcs")
+        .arg(lib)
+        .args(objects)
+        .status()
+        .map_err(|e| format!("failed to run {}: {}", ar, e))?;
+    if !status.success() {
+        return Err(format!("{} failed: {}", ar, status));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn c_header() {
+        let filters = vec![
+            ("http".to_owned(), "tcp port 80".to_owned()),
+            ("dns".to_owned(), "udp port 53".to_owned()),
+        ];
+        let h = header("FILTERS_H", &filters);
+        assert!(h.starts_with("#ifndef FILTERS_H\n#define FILTERS_H\n"));
+        assert!(h.contains(
+            "/* tcp port 80 */\nuint32_t filter_http(const uint8_t *pkt, uint32_t len);\n"
+        ));
+        assert!(h.contains("uint32_t filter_dns(const uint8_t *pkt, uint32_t len);\n"));
+        assert!(h.ends_with("#endif /* FILTERS_H */\n"));
+
+        assert!(is_identifier("http_2"));
+        assert!(!is_identifier("tcp port 80"));
+        assert!(!is_identifier(""));
+    }
+}
@@ -2,15 +2,18 @@ extern crate cbpf;
 extern crate cbpf_to_llvm_ir;
 #[macro_use]
 extern crate error_chain;
+extern crate libc;
 extern crate pcap;
 extern crate structopt;
 #[macro_use]
 extern crate structopt_derive;
 
 use std::fs;
-use std::io::{BufWriter, Write};
+use std::io::{BufWriter, Read, Write};
+use cbpf::interpreter::{Interpreter, Simple};
 use cbpf::opcode::BpfInsn;
-use cbpf_to_llvm_ir::Converter;
+use cbpf_to_llvm_ir::{Action, Backend, BranchProfile, CompiledFilter, Converter, EbpfTarget, MapType,
+                      Options, ProgramType, RedirectMap};
 use structopt::StructOpt;
 
 mod errors {
@@ -28,30 +31,644 @@ use errors::*;
 #[structopt(name = "cbpf2ir", about = "Convert cBPF program to LLVM IR from libpcap's expression")]
 struct Opt {
     #[structopt(short = "n", long = "noopt", help = "no optimization")] noopt: bool,
+    #[structopt(short = "O", long = "cbpf-opt", help = "optimize cBPF program before conversion")]
+    cbpf_opt: bool,
+    #[structopt(long = "passes",
+                help = "Pass pipeline run instead of default<O2> and lto<O2> (LLVM 15+)")]
+    passes: Option<String>,
+    #[structopt(long = "time-passes", help = "Print the time of each pass pipeline")]
+    time_passes: bool,
     #[structopt(short = "d", long = "debug", help = "Activate debug mode")] debug: bool,
-    #[structopt(short = "o", long = "outfile", help = "Output file")] outfile: String,
+    #[structopt(short = "g", long = "debug-info",
+                help = "Emit DWARF debug info and write the disassembly to ./filter.bpf")]
+    debug_info: bool,
+    #[structopt(short = "o", long = "outfile", help = "Output file")] outfile: Option<String>,
+    #[structopt(long = "emit", help = "Output format (llvm, c, wasm or wasm-llvm)",
+                default_value = "llvm")]
+    emit: String,
+    #[structopt(long = "profile-data", help = "Branch profile recorded by `cbpf2ir pgo-record`")]
+    profile_data: Option<String>,
     #[structopt(short = "l", long = "linktype", /* default is ethernet */
                 help = "LinkType (http://www.tcpdump.org/linktypes.html)", default_value = "1")]
     linktype: i32,
-    #[structopt(help = "cBPF filter expression")] expression: String,
+    #[structopt(help = "cBPF filter expression")] expression: Option<String>,
+    #[structopt(subcommand)] cmd: Option<Command>,
 }
 
-fn run() -> Result<()> {
-    let args = Opt::from_args();
+#[derive(StructOpt, Debug)]
+enum Command {
+    #[structopt(name = "analyze", about = "Report what a cBPF program accesses")]
+    Analyze {
+        #[structopt(long = "json", help = "Output in JSON")] json: bool,
+        #[structopt(short = "O", long = "cbpf-opt", help = "optimize cBPF program before analysis")]
+        cbpf_opt: bool,
+        #[structopt(short = "l", long = "linktype",
+                    help = "LinkType (http://www.tcpdump.org/linktypes.html)", default_value = "1")]
+        linktype: i32,
+        #[structopt(help = "cBPF filter expression")] expression: String,
+    },
+    #[structopt(name = "cfg", about = "Render the control flow graph of a cBPF program")]
+    Cfg {
+        #[structopt(short = "f", long = "format", help = "Output format", default_value = "dot")]
+        format: String,
+        #[structopt(long = "llvm", help = "Render the LLVM function instead of the cBPF program")]
+        llvm: bool,
+        #[structopt(short = "n", long = "noopt", help = "no optimization (with --llvm)")]
+        noopt: bool,
+        #[structopt(short = "O", long = "cbpf-opt",
+                    help = "optimize cBPF program before rendering")]
+        cbpf_opt: bool,
+        #[structopt(short = "l", long = "linktype",
+                    help = "LinkType (http://www.tcpdump.org/linktypes.html)", default_value = "1")]
+        linktype: i32,
+        #[structopt(help = "cBPF filter expression")] expression: String,
+    },
+    #[structopt(name = "run", about = "Run a JIT-compiled cBPF program on packets of a pcap file")]
+    Run {
+        #[structopt(short = "p", long = "profile", help = "Print hit counts of each instruction")]
+        profile: bool,
+        #[structopt(long = "perf-map", help = "Write /tmp/perf-<pid>.map for perf")]
+        perf_map: bool,
+        #[structopt(long = "gdb", help = "Register the compiled code to GDB")] gdb_jit: bool,
+        #[structopt(long = "cache-dir", help = "Reuse compiled code stored in this directory")]
+        cache_dir: Option<String>,
+        #[structopt(short = "t", long = "trace", help = "Print the execution trace of a packet")]
+        trace: bool,
+        #[structopt(long = "packet", help = "Packet to trace (1-origin)", default_value = "1")]
+        packet: usize,
+        #[structopt(long = "interpret",
+                    help = "Trace with the interpreter instead of the compiled code \
+                            (with --trace)")]
+        interpret: bool,
+        #[structopt(short = "n", long = "noopt", help = "no optimization")] noopt: bool,
+        #[structopt(short = "O", long = "cbpf-opt",
+                    help = "optimize cBPF program before conversion")]
+        cbpf_opt: bool,
+        #[structopt(short = "l", long = "linktype",
+                    help = "LinkType (http://www.tcpdump.org/linktypes.html)", default_value = "1")]
+        linktype: i32,
+        #[structopt(long = "backend", help = "Code generator (llvm, cranelift or interp)",
+                    default_value = "llvm")]
+        backend: Backend,
+        #[structopt(help = "cBPF filter expression")] expression: String,
+        #[structopt(help = "pcap file")] pcapfile: String,
+    },
+    #[structopt(name = "pgo-record",
+                about = "Record how many times each jump is taken on packets of a pcap file")]
+    PgoRecord {
+        #[structopt(short = "r", long = "read", help = "pcap file")] pcapfile: String,
+        #[structopt(short = "o", long = "outfile", help = "Output file")] outfile: String,
+        #[structopt(short = "O", long = "cbpf-opt",
+                    help = "optimize cBPF program before conversion")]
+        cbpf_opt: bool,
+        #[structopt(short = "l", long = "linktype",
+                    help = "LinkType (http://www.tcpdump.org/linktypes.html)", default_value = "1")]
+        linktype: i32,
+        #[structopt(help = "cBPF filter expression")] expression: String,
+    },
+    #[structopt(name = "build",
+                about = "Compile cBPF programs to a static library of `filter_<name>` functions")]
+    Build {
+        #[structopt(long = "lib", help = "Output static library")] lib: String,
+        #[structopt(long = "header", help = "Output C header")] header: Option<String>,
+        #[structopt(short = "f", long = "filter", help = "Filter to compile (name=expression)")]
+        filters: Vec<String>,
+        #[structopt(short = "n", long = "noopt", help = "no optimization")] noopt: bool,
+        #[structopt(short = "O", long = "cbpf-opt",
+                    help = "optimize cBPF program before conversion")]
+        cbpf_opt: bool,
+        #[structopt(short = "l", long = "linktype",
+                    help = "LinkType (http://www.tcpdump.org/linktypes.html)", default_value = "1")]
+        linktype: i32,
+    },
+    #[structopt(name = "ebpf", about = "Compile a cBPF program to an eBPF object")]
+    Ebpf {
+        #[structopt(short = "o", long = "outfile", help = "Output object file")] outfile: String,
+        #[structopt(short = "t", long = "type", help = "Program type (xdp, classifier or socket)",
+                    default_value = "xdp")]
+        program_type: String,
+        #[structopt(long = "big-endian", help = "Generate big endian code (bpfeb)")]
+        big_endian: bool,
+        #[structopt(long = "name", help = "Name of the program", default_value = "cbpf_filter")]
+        name: String,
+        #[structopt(short = "n", long = "noopt", help = "no optimization")] noopt: bool,
+        #[structopt(short = "O", long = "cbpf-opt",
+                    help = "optimize cBPF program before conversion")]
+        cbpf_opt: bool,
+        #[structopt(short = "l", long = "linktype",
+                    help = "LinkType (http://www.tcpdump.org/linktypes.html)", default_value = "1")]
+        linktype: i32,
+        #[structopt(help = "cBPF filter expression")] expression: String,
+    },
+    #[structopt(name = "xdp",
+                about = "Compile a filter to an XDP program which passes, drops or redirects \
+                         packets")]
+    Xdp {
+        #[structopt(short = "o", long = "outfile", help = "Output object file")] outfile: String,
+        #[structopt(long = "on-match", help = "Action for matched packets (pass, drop or redirect)",
+                    default_value = "pass")]
+        on_match: String,
+        #[structopt(long = "otherwise",
+                    help = "Action for the other packets (pass, drop or redirect)",
+                    default_value = "drop")]
+        otherwise: String,
+        #[structopt(long = "map", help = "Map to redirect packets to",
+                    default_value = "redirect_map")]
+        map: String,
+        #[structopt(long = "map-type", help = "Type of the map (devmap, cpumap or xskmap)",
+                    default_value = "devmap")]
+        map_type: String,
+        #[structopt(long = "map-size", help = "Number of entries of the map", default_value = "64")]
+        map_size: u32,
+        #[structopt(long = "key", help = "Key of the map entry to redirect packets to",
+                    default_value = "0")]
+        key: u32,
+        #[structopt(long = "iproute2",
+                    help = "Define the map for iproute2, which redirect requires")]
+        iproute2: bool,
+        #[structopt(long = "big-endian", help = "Generate big endian code (bpfeb)")]
+        big_endian: bool,
+        #[structopt(long = "name", help = "Name of the program", default_value = "xdp_filter")]
+        name: String,
+        #[structopt(short = "n", long = "noopt", help = "no optimization")] noopt: bool,
+        #[structopt(short = "O", long = "cbpf-opt",
+                    help = "optimize cBPF program before conversion")]
+        cbpf_opt: bool,
+        #[structopt(short = "l", long = "linktype",
+                    help = "LinkType (http://www.tcpdump.org/linktypes.html)", default_value = "1")]
+        linktype: i32,
+        #[structopt(help = "cBPF filter expression")] expression: String,
+    },
+}
 
-    let pcap = pcap::Capture::dead(pcap::Linktype(args.linktype))?;
-    let bpf_prog = pcap.compile(&args.expression)?;
+fn compile(linktype: i32, expression: &str) -> Result<Vec<BpfInsn>> {
+    let pcap = pcap::Capture::dead(pcap::Linktype(linktype))?;
+    let bpf_prog = pcap.compile(expression)?;
     // we do this since pcap crate does not expose internal bpf structure
     let insns: &[BpfInsn] = unsafe { std::mem::transmute(bpf_prog.get_instructions()) };
+    Ok(insns.to_vec())
+}
+
+fn analyze(json: bool, cbpf_opt: bool, linktype: i32, expression: &str) -> Result<()> {
+    let mut insns = compile(linktype, expression)?;
+    if cbpf_opt {
+        insns = cbpf_to_llvm_ir::optimize(&insns);
+    }
+
+    let report = cbpf_to_llvm_ir::analyze(&insns)?;
+    if json {
+        println!("{}", report.to_json());
+    } else {
+        println!("{}", report);
+    }
+
+    Ok(())
+}
+
+fn cfg(
+    format: &str,
+    llvm: bool,
+    noopt: bool,
+    cbpf_opt: bool,
+    linktype: i32,
+    expression: &str,
+) -> Result<()> {
+    if format != "dot" {
+        return Err(format!("unsupported format: {}", format).into());
+    }
+
+    let mut insns = compile(linktype, expression)?;
+    if cbpf_opt {
+        insns = cbpf_to_llvm_ir::optimize(&insns);
+    }
+
+    if llvm {
+        let mut converter = Converter::new();
+        converter.convert(&insns, !noopt)?;
+        print!("{}", converter.to_dot());
+    } else {
+        print!("{}", cbpf_to_llvm_ir::to_dot(&insns));
+    }
+
+    Ok(())
+}
+
+// run the filter on each packet of the pcap file. returns (total, matched)
+fn run_pcap(filter: &CompiledFilter, pcapfile: &str) -> Result<(usize, usize)> {
+    let mut capture = pcap::Capture::from_file(pcapfile)?;
+    let (mut total, mut matched) = (0, 0);
+    loop {
+        let packet = match capture.next() {
+            Ok(packet) => packet,
+            Err(pcap::Error::NoMorePackets) => break,
+            Err(e) => return Err(e.into()),
+        };
+        total += 1;
+        if filter.run(packet.data) != 0 {
+            matched += 1;
+        }
+    }
+    Ok((total, matched))
+}
+
+fn pgo_record(
+    pcapfile: &str,
+    outfile: &str,
+    cbpf_opt: bool,
+    linktype: i32,
+    expression: &str,
+) -> Result<()> {
+    let mut insns = compile(linktype, expression)?;
+    if cbpf_opt {
+        insns = cbpf_to_llvm_ir::optimize(&insns);
+    }
+
+    let options = Options {
+        profile: true,
+        ..Default::default()
+    };
+    let filter = CompiledFilter::new(&insns, options, true)?;
+    run_pcap(&filter, pcapfile)?;
+
+    let profile = filter.branch_profile().unwrap();
+    let mut f = BufWriter::new(fs::File::create(outfile)?);
+    f.write_all(profile.to_text(&insns).as_bytes())?;
+
+    Ok(())
+}
+
+fn build(
+    lib: &str,
+    header: Option<&str>,
+    specs: &[String],
+    noopt: bool,
+    cbpf_opt: bool,
+    linktype: i32,
+) -> Result<()> {
+    let mut filters = vec![];
+    for f in specs {
+        let mut kv = f.splitn(2, '=');
+        let name = kv.next().unwrap();
+        let expression = match kv.next() {
+            Some(expression) => expression,
+            None => return Err(format!("expected name=expression: {}", f).into()),
+        };
+        if !cbpf_to_llvm_ir::is_identifier(name) {
+            return Err(format!("invalid filter name: {}", name).into());
+        }
+        if filters.iter().any(|&(ref n, _)| n == name) {
+            return Err(format!("duplicate filter name: {}", name).into());
+        }
+        filters.push((name.to_owned(), expression.to_owned()));
+    }
+    if filters.is_empty() {
+        return Err("no filter is given".into());
+    }
+
+    let dir = std::env::temp_dir().join(format!("cbpf2ir-build-{}", unsafe { libc::getpid() }));
+    fs::create_dir_all(&dir)?;
+    let mut objects = vec![];
+    let result = (|| -> Result<()> {
+        for &(ref name, ref expression) in &filters {
+            let mut insns = compile(linktype, expression)?;
+            if cbpf_opt {
+                insns = cbpf_to_llvm_ir::optimize(&insns);
+            }
+            let mut converter = Converter::new();
+            converter.convert(&insns, !noopt)?;
+            let object = dir.join(format!("{}.o", name)).to_string_lossy().into_owned();
+            converter.emit_object(&format!("filter_{}", name), &object)?;
+            objects.push(object);
+        }
+        cbpf_to_llvm_ir::write_archive(lib, &objects)?;
+        Ok(())
+    })();
+    let _ = fs::remove_dir_all(&dir);
+    result?;
+
+    if let Some(header) = header {
+        let guard: String = std::path::Path::new(header)
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            })
+            .collect();
+        let mut f = BufWriter::new(fs::File::create(header)?);
+        f.write_all(cbpf_to_llvm_ir::c_header(&guard, &filters).as_bytes())?;
+    }
+
+    Ok(())
+}
+
+// compile the expression to an eBPF object
+fn emit_ebpf(
+    target: EbpfTarget,
+    expression: &str,
+    linktype: i32,
+    cbpf_opt: bool,
+    noopt: bool,
+    name: &str,
+    outfile: &str,
+) -> Result<()> {
+    if !cbpf_to_llvm_ir::is_identifier(name) {
+        return Err(format!("invalid program name: {}", name).into());
+    }
+    let target = EbpfTarget {
+        offset: cbpf_to_llvm_ir::linktype_offset(linktype)?,
+        ..target
+    };
+
+    let mut insns = compile(linktype, expression)?;
+    if cbpf_opt {
+        insns = cbpf_to_llvm_ir::optimize(&insns);
+    }
 
-    let mut converter = Converter::new();
+    let options = Options {
+        ebpf: Some(target),
+        ..Default::default()
+    };
+    let mut converter = Converter::with_options(options);
+    converter.convert(&insns, !noopt)?;
+    converter.emit_object(name, outfile)?;
+
+    Ok(())
+}
+
+fn program_type(program_type: &str) -> Result<ProgramType> {
+    match program_type {
+        "xdp" => Ok(ProgramType::Xdp),
+        "classifier" | "tc" => Ok(ProgramType::Classifier),
+        "socket" => Ok(ProgramType::SocketFilter),
+        t => Err(format!("unknown program type: {}", t).into()),
+    }
+}
+
+fn map_type(map_type: &str) -> Result<MapType> {
+    match map_type {
+        "devmap" => Ok(MapType::Devmap),
+        "cpumap" => Ok(MapType::Cpumap),
+        "xskmap" => Ok(MapType::Xskmap),
+        t => Err(format!("unknown map type: {}", t).into()),
+    }
+}
+
+fn action(action: &str, map: &RedirectMap) -> Result<Action> {
+    match action {
+        "pass" => Ok(Action::Pass),
+        "drop" => Ok(Action::Drop),
+        "redirect" => Ok(Action::Redirect(map.clone())),
+        a => Err(format!("unknown action: {}", a).into()),
+    }
+}
+
+// print the trace of the packet, which can be diffed between the compiled code
+// and the interpreter (--interpret)
+fn trace_packet(
+    filter: &CompiledFilter,
+    pcapfile: &str,
+    packet: usize,
+    interpret: bool,
+) -> Result<()> {
+    let mut capture = pcap::Capture::from_file(pcapfile)?;
+    for _ in 1..packet {
+        capture.next()?;
+    }
+    let data = capture.next()?.data.to_vec();
+
+    let insns = filter.insns();
+    let (steps, result) = if interpret {
+        let (steps, result) = cbpf_to_llvm_ir::interpret_traced(insns, &data);
+        let simple = Simple::run(insns, &data).ok();
+        if result != simple {
+            eprintln!("warning: cbpf::interpreter::Simple returns {:?}", simple);
+        }
+        (steps, result)
+    } else {
+        let steps = filter
+            .run_traced(&data)
+            .ok_or("the filter is built without tracing")?;
+        (steps, Some(filter.run(&data)))
+    };
+
+    print!("{}", cbpf_to_llvm_ir::format_trace(insns, &steps));
+    match result {
+        Some(result) => println!("result: {}", result),
+        None => println!("result: invalid program"),
+    }
+    Ok(())
+}
+
+fn run_filter(filter: &CompiledFilter, pcapfile: &str, profile: bool) -> Result<()> {
+    let (total, matched) = run_pcap(filter, pcapfile)?;
+
+    if profile {
+        print!("{}", filter.annotate());
+    }
+    println!("{} / {} packets matched", matched, total);
+
+    Ok(())
+}
+
+fn run() -> Result<()> {
+    let mut args = Opt::from_args();
+    let cmd = match args.cmd.take() {
+        Some(cmd) => cmd,
+        None => return convert(&args),
+    };
+
+    match cmd {
+        Command::Analyze {
+            json,
+            cbpf_opt,
+            linktype,
+            expression,
+        } => analyze(json, cbpf_opt, linktype, &expression),
+        Command::Cfg {
+            format,
+            llvm,
+            noopt,
+            cbpf_opt,
+            linktype,
+            expression,
+        } => cfg(&format, llvm, noopt, cbpf_opt, linktype, &expression),
+        Command::Run {
+            profile,
+            perf_map,
+            gdb_jit,
+            cache_dir,
+            trace,
+            packet,
+            interpret,
+            noopt,
+            cbpf_opt,
+            linktype,
+            backend,
+            expression,
+            pcapfile,
+        } => {
+            let mut insns = compile(linktype, &expression)?;
+            if cbpf_opt {
+                insns = cbpf_to_llvm_ir::optimize(&insns);
+            }
+
+            let options = Options {
+                name: expression,
+                perf_map,
+                gdb_jit,
+                profile,
+                trace,
+                cache_dir,
+                backend,
+                ..Default::default()
+            };
+            let filter = CompiledFilter::new(&insns, options, !noopt)?;
+
+            if trace {
+                trace_packet(&filter, &pcapfile, packet, interpret)
+            } else {
+                run_filter(&filter, &pcapfile, profile)
+            }
+        }
+        Command::PgoRecord {
+            pcapfile,
+            outfile,
+            cbpf_opt,
+            linktype,
+            expression,
+        } => pgo_record(&pcapfile, &outfile, cbpf_opt, linktype, &expression),
+        Command::Build {
+            lib,
+            header,
+            filters,
+            noopt,
+            cbpf_opt,
+            linktype,
+        } => build(
+            &lib,
+            header.as_ref().map(|h| h.as_str()),
+            &filters,
+            noopt,
+            cbpf_opt,
+            linktype,
+        ),
+        Command::Ebpf {
+            outfile,
+            program_type: t,
+            big_endian,
+            name,
+            noopt,
+            cbpf_opt,
+            linktype,
+            expression,
+        } => {
+            let target = EbpfTarget {
+                big_endian,
+                ..EbpfTarget::new(program_type(&t)?)
+            };
+            emit_ebpf(target, &expression, linktype, cbpf_opt, noopt, &name, &outfile)
+        }
+        Command::Xdp {
+            outfile,
+            on_match,
+            otherwise,
+            map,
+            map_type: t,
+            map_size,
+            key,
+            iproute2,
+            big_endian,
+            name,
+            noopt,
+            cbpf_opt,
+            linktype,
+            expression,
+        } => {
+            let map = RedirectMap {
+                name: map,
+                map_type: map_type(&t)?,
+                max_entries: map_size,
+                key,
+            };
+            let target = EbpfTarget {
+                big_endian,
+                accept: action(&on_match, &map)?,
+                reject: action(&otherwise, &map)?,
+                iproute2_maps: iproute2,
+                ..EbpfTarget::new(ProgramType::Xdp)
+            };
+            emit_ebpf(target, &expression, linktype, cbpf_opt, noopt, &name, &outfile)
+        }
+    }
+}
+
+// convert the expression to LLVM IR (or C, WebAssembly or a wasm object) without a subcommand
+fn convert(args: &Opt) -> Result<()> {
+    let (expression, outfile) = match (args.expression.as_ref(), args.outfile.as_ref()) {
+        (Some(expression), Some(outfile)) => (expression, outfile),
+        _ => return Err("an expression and --outfile are required (see --help)".into()),
+    };
+
+    let insns = compile(args.linktype, expression)?;
+    let insns = &insns[..];
+    let optimized;
+    let insns = if args.cbpf_opt {
+        optimized = cbpf_to_llvm_ir::optimize(insns);
+        &optimized[..]
+    } else {
+        insns
+    };
+
+    match args.emit.as_str() {
+        "llvm" | "wasm-llvm" => {}
+        "c" => {
+            let c = cbpf_to_llvm_ir::to_c(insns, "filter")?;
+            let mut f = BufWriter::new(fs::File::create(outfile)?);
+            f.write_all(c.as_bytes())?;
+            return Ok(());
+        }
+        "wasm" => {
+            let wasm = cbpf_to_llvm_ir::to_wasm(insns)?;
+            let mut f = BufWriter::new(fs::File::create(outfile)?);
+            f.write_all(&wasm)?;
+            return Ok(());
+        }
+        emit => return Err(format!("unknown output format: {}", emit).into()),
+    }
+
+    let branch_profile = match args.profile_data {
+        Some(ref path) => {
+            let mut text = String::new();
+            fs::File::open(path)?.read_to_string(&mut text)?;
+            Some(BranchProfile::parse(&text, insns)?)
+        }
+        None => None,
+    };
+    let options = Options {
+        debug_info: args.debug_info,
+        branch_profile,
+        passes: args.passes.clone(),
+        ..Default::default()
+    };
+    let mut converter = Converter::with_options(options);
     let ir = converter.convert(insns, !args.noopt);
     if ir.is_err() {
         return Err(format!("{}", ir.err().unwrap()).into());
     }
 
+    if args.time_passes {
+        for &(ref pipeline, time) in converter.pass_timings() {
+            let ms = time.as_secs() as f64 * 1e3 + time.subsec_nanos() as f64 / 1e6;
+            eprintln!("{}: {:.3} ms", pipeline, ms);
+        }
+    }
+
     if args.debug {
-        println!("expression: {}", args.expression);
+        println!("expression: {}", expression);
         println!("length: {:?}", insns.len());
         println!("cBPF program:");
         for insn in insns {
@@ -62,8 +679,17 @@ fn run() -> Result<()> {
         converter.dump_module();
     }
 
-    let mut f = BufWriter::new(fs::File::create(args.outfile)?);
-    f.write_all(ir.unwrap().as_bytes())?;
+    if args.emit == "wasm-llvm" {
+        converter.emit_wasm_object("filter", outfile)?;
+    } else {
+        let mut f = BufWriter::new(fs::File::create(outfile)?);
+        f.write_all(ir.unwrap().as_bytes())?;
+    }
+
+    if args.debug_info {
+        let mut f = BufWriter::new(fs::File::create("filter.bpf")?);
+        f.write_all(cbpf_to_llvm_ir::debug_source(insns).as_bytes())?;
+    }
 
     Ok(())
 }
@@ -0,0 +1,329 @@
+// placement of packet bounds checks
+//
+// A load out of bounds makes the filter return 0. So if every path from an instruction
+// reaches such a load before any return, we can check the packet length once at that
+// instruction and return 0 early. We compute for each instruction the minimum packet
+// length which is required on all paths from there (`need`), and insert a check only
+// where the length which is already checked on all incoming paths is not enough.
+
+use cbpf::opcode::*;
+use cfg;
+
+pub fn load_size(size: u16) -> u64 {
+    match size {
+        BPF_W => 4,
+        BPF_H => 2,
+        BPF_B => 1,
+        _ => panic!("InvalidSize"),
+    }
+}
+
+#[cfg_attr(not(feature = "llvm"), allow(dead_code))]
+pub struct BoundsPlan {
+    // packet length to check before the instruction
+    pub guards: Vec<Option<u64>>,
+    // true if a load with indirect offset has to be checked at runtime
+    pub ind_checks: Vec<bool>,
+}
+
+// range of possible values of a register [lo, hi]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Range {
+    pub lo: u64,
+    pub hi: u64,
+}
+
+impl Range {
+    fn new(lo: u64, hi: u64) -> Self {
+        Range { lo, hi }
+    }
+
+    fn konst(k: u32) -> Self {
+        Range::new(k as u64, k as u64)
+    }
+
+    fn full() -> Self {
+        Range::new(0, u32::max_value() as u64)
+    }
+
+    fn join(&self, other: &Range) -> Range {
+        Range::new(
+            std::cmp::min(self.lo, other.lo),
+            std::cmp::max(self.hi, other.hi),
+        )
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Ranges {
+    pub a: Range,
+    pub x: Range,
+    pub mem: Vec<Range>,
+}
+
+impl Ranges {
+    fn join(&self, other: &Ranges) -> Ranges {
+        Ranges {
+            a: self.a.join(&other.a),
+            x: self.x.join(&other.x),
+            mem: self.mem
+                .iter()
+                .zip(other.mem.iter())
+                .map(|(a, b)| a.join(b))
+                .collect(),
+        }
+    }
+}
+
+fn merge<T: Clone, F: Fn(&T, &T) -> T>(states: &mut Vec<Option<T>>, i: usize, v: T, join: F) {
+    if i >= states.len() {
+        return;
+    }
+    let merged = match states[i].take() {
+        Some(s) => join(&s, &v),
+        None => v,
+    };
+    states[i] = Some(merged);
+}
+
+fn mem_range(ranges: &Ranges, k: u32) -> Range {
+    ranges
+        .mem
+        .get(k as usize)
+        .cloned()
+        .unwrap_or_else(Range::full)
+}
+
+// possible values of registers before each instruction (None if unreachable).
+// after `ldx msh` X is always a multiple of 4 in 0..60, which is what libpcap uses
+// for indirect loads
+pub fn ranges(insns: &[BpfInsn]) -> Vec<Option<Ranges>> {
+    let n = insns.len();
+    let mut states: Vec<Option<Ranges>> = vec![None; n];
+    if n > 0 {
+        states[0] = Some(Ranges {
+            a: Range::konst(0),
+            x: Range::konst(0),
+            mem: vec![Range::full(); BPF_MEMWORDS as usize],
+        });
+    }
+
+    let mut result = vec![None; n];
+    for i in 0..n {
+        let mut s = match states[i].take() {
+            Some(s) => s,
+            None => continue,
+        };
+        result[i] = Some(s.clone());
+
+        let insn = insns[i];
+        let code = insn.code;
+        let k = insn.k;
+        match bpf_class(code) {
+            BPF_LD => {
+                s.a = match bpf_mode(code) {
+                    BPF_IMM => Range::konst(k),
+                    BPF_MEM => mem_range(&s, k),
+                    BPF_ABS | BPF_IND => match bpf_size(code) {
+                        BPF_B => Range::new(0, 0xff),
+                        BPF_H => Range::new(0, 0xffff),
+                        _ => Range::full(),
+                    },
+                    _ => Range::full(),
+                }
+            }
+            BPF_LDX => {
+                s.x = match bpf_mode(code) {
+                    BPF_IMM => Range::konst(k),
+                    BPF_MEM => mem_range(&s, k),
+                    BPF_MSH => Range::new(0, 60),
+                    _ => Range::full(),
+                }
+            }
+            BPF_ST => if let Some(m) = s.mem.get_mut(k as usize) {
+                *m = s.a;
+            },
+            BPF_STX => if let Some(m) = s.mem.get_mut(k as usize) {
+                *m = s.x;
+            },
+            BPF_ALU => {
+                s.a = match (bpf_op(code), bpf_src(code)) {
+                    (BPF_ADD, BPF_K) if s.a.hi + k as u64 <= u32::max_value() as u64 => {
+                        Range::new(s.a.lo + k as u64, s.a.hi + k as u64)
+                    }
+                    (BPF_ADD, BPF_X) if s.a.hi + s.x.hi <= u32::max_value() as u64 => {
+                        Range::new(s.a.lo + s.x.lo, s.a.hi + s.x.hi)
+                    }
+                    (BPF_AND, BPF_K) => Range::new(0, std::cmp::min(s.a.hi, k as u64)),
+                    (BPF_RSH, BPF_K) if k < 32 => Range::new(s.a.lo >> k, s.a.hi >> k),
+                    _ => Range::full(),
+                }
+            }
+            BPF_MISC => if bpf_miscop(code) == BPF_TAX {
+                s.x = s.a;
+            } else {
+                s.a = s.x;
+            },
+            _ => {}
+        }
+
+        for succ in cfg::successors(insns, i) {
+            merge(&mut states, succ, s.clone(), Ranges::join);
+        }
+    }
+    result
+}
+
+// (lowest, highest) end offset of the packet access of the instruction
+#[cfg_attr(not(feature = "llvm"), allow(dead_code))]
+fn access(insn: &BpfInsn, x: &Range) -> Option<(u64, u64)> {
+    let code = insn.code;
+    let k = insn.k as u64;
+    match (bpf_class(code), bpf_mode(code)) {
+        (BPF_LD, BPF_ABS) => {
+            let end = k + load_size(bpf_size(code));
+            Some((end, end))
+        }
+        (BPF_LD, BPF_IND) => {
+            let size = load_size(bpf_size(code));
+            Some((x.lo + k + size, x.hi + k + size))
+        }
+        (BPF_LDX, BPF_MSH) => Some((k + 1, k + 1)),
+        _ => None,
+    }
+}
+
+#[cfg_attr(not(feature = "llvm"), allow(dead_code))]
+pub fn plan(insns: &[BpfInsn]) -> BoundsPlan {
+    let n = insns.len();
+    let accesses: Vec<_> = insns
+        .iter()
+        .zip(ranges(insns))
+        .map(|(insn, r)| access(insn, &r.map_or_else(Range::full, |r| r.x)))
+        .collect();
+
+    // need[i]: packet length required on all paths from i
+    let mut need = vec![0u64; n];
+    for i in (0..n).rev() {
+        let succ_need = cfg::successors(insns, i)
+            .into_iter()
+            .filter(|&succ| succ < n)
+            .map(|succ| need[succ])
+            .min()
+            .unwrap_or(0);
+        need[i] = match accesses[i] {
+            Some((lo, _)) => std::cmp::max(lo, succ_need),
+            None => succ_need,
+        };
+    }
+
+    // checked[i]: packet length already checked on all paths to i
+    let mut checked: Vec<Option<u64>> = vec![None; n];
+    let mut guards = vec![None; n];
+    let mut ind_checks = vec![false; n];
+    if n > 0 {
+        checked[0] = Some(0);
+    }
+    for i in 0..n {
+        // unreachable instructions are still converted, so be conservative
+        let mut len = checked[i].unwrap_or(0);
+        if let Some((lo, hi)) = accesses[i] {
+            if hi > len && need[i] > len {
+                guards[i] = Some(need[i]);
+                len = need[i];
+            }
+            if hi > len {
+                ind_checks[i] = true;
+                len = std::cmp::max(len, lo);
+            }
+        }
+        for succ in cfg::successors(insns, i) {
+            merge(&mut checked, succ, len, |a, b| std::cmp::min(*a, *b));
+        }
+    }
+
+    BoundsPlan { guards, ind_checks }
+}
+
+// check each load by itself, so that the program fails exactly at the failing load
+#[cfg_attr(not(feature = "llvm"), allow(dead_code))]
+pub fn unhoisted(insns: &[BpfInsn]) -> BoundsPlan {
+    let mut guards = vec![None; insns.len()];
+    let mut ind_checks = vec![false; insns.len()];
+    for (i, insn) in insns.iter().enumerate() {
+        match access(insn, &Range::full()) {
+            Some((lo, hi)) if lo == hi => guards[i] = Some(lo),
+            Some(_) => ind_checks[i] = true,
+            None => {}
+        }
+    }
+    BoundsPlan { guards, ind_checks }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hoist_checks() {
+        // ldh [12]; jeq #0x800, L1, L2; L1: ldb [23]; ldh [20]; ret a; L2: ret #0
+        let insns = [
+            BpfInsn::new(BPF_LD_H_ABS, 0, 0, 12),
+            BpfInsn::new(BPF_JEQ_K, 0, 3, 0x0800),
+            BpfInsn::new(BPF_LD_B_ABS, 0, 0, 23),
+            BpfInsn::new(BPF_LD_H_ABS, 0, 0, 20),
+            BpfInsn::new(BPF_RET_A, 0, 0, 0),
+            BpfInsn::new(BPF_RET_K, 0, 0, 0),
+        ];
+        let bounds = plan(&insns);
+        assert_eq!(
+            bounds.guards,
+            vec![Some(14), None, Some(24), None, None, None]
+        );
+        assert!(bounds.ind_checks.iter().all(|&c| !c));
+    }
+
+    #[test]
+    fn both_paths_need_the_same() {
+        // ldh [12]; jeq #0x800, L1, L2; L1: ldb [23]; ret a; L2: ldb [30]; ret a
+        let insns = [
+            BpfInsn::new(BPF_LD_H_ABS, 0, 0, 12),
+            BpfInsn::new(BPF_JEQ_K, 0, 2, 0x0800),
+            BpfInsn::new(BPF_LD_B_ABS, 0, 0, 23),
+            BpfInsn::new(BPF_RET_A, 0, 0, 0),
+            BpfInsn::new(BPF_LD_B_ABS, 0, 0, 30),
+            BpfInsn::new(BPF_RET_A, 0, 0, 0),
+        ];
+        let bounds = plan(&insns);
+        assert_eq!(
+            bounds.guards,
+            vec![Some(24), None, None, None, Some(31), None]
+        );
+    }
+
+    #[test]
+    fn indirect_loads() {
+        // ldx msh [14]; ldh [x + 14]; ldb [90]; ldh [x + 16]; ret a
+        let insns = [
+            BpfInsn::new(BPF_LDX_B_MSH, 0, 0, 14),
+            BpfInsn::new(BPF_LD_H_IND, 0, 0, 14),
+            BpfInsn::new(BPF_LD_B_ABS, 0, 0, 90),
+            BpfInsn::new(BPF_LD_H_IND, 0, 0, 16),
+            BpfInsn::new(BPF_RET_A, 0, 0, 0),
+        ];
+        let bounds = plan(&insns);
+        // X is in 0..60, the last load is covered by the check of ldb [90]
+        assert_eq!(bounds.guards, vec![Some(91), None, None, None, None]);
+        assert_eq!(bounds.ind_checks, vec![false, false, false, false, false]);
+
+        // ldx msh [14]; ldh [x + 14]; ret a
+        let insns = [
+            BpfInsn::new(BPF_LDX_B_MSH, 0, 0, 14),
+            BpfInsn::new(BPF_LD_H_IND, 0, 0, 14),
+            BpfInsn::new(BPF_RET_A, 0, 0, 0),
+        ];
+        let bounds = plan(&insns);
+        assert_eq!(bounds.guards, vec![Some(16), None, None]);
+        assert_eq!(bounds.ind_checks, vec![false, true, false]);
+    }
+}
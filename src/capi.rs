@@ -0,0 +1,237 @@
+// C API
+//
+// include/cbpf_jit.h is generated from this file by cbindgen (see cbindgen.toml).
+// The verdicts are the same as those of `bpf_filter()` of libpcap: out of bounds loads and
+// division by zero return 0.
+
+#![allow(non_camel_case_types)]
+
+use cbpf::opcode::BpfInsn;
+use filter::CompiledFilter;
+use std::ffi::{CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+use {optimize, Options};
+
+// struct sock_filter of <linux/filter.h>, which has the same layout as struct bpf_insn of libpcap
+#[repr(C)]
+pub struct sock_filter {
+    pub code: u16,
+    pub jt: u8,
+    pub jf: u8,
+    pub k: u32,
+}
+
+#[repr(C)]
+pub struct cbpf_jit_options {
+    // run LLVM optimization passes
+    pub optimize: bool,
+    // optimize the cBPF program before conversion
+    pub cbpf_optimize: bool,
+    // name of the filter for perf and GDB (may be NULL)
+    pub name: *const ::libc::c_char,
+    pub perf_map: bool,
+    pub gdb_jit: bool,
+    // directory of the compiled code cache (may be NULL)
+    pub cache_dir: *const ::libc::c_char,
+}
+
+// compiled filter
+pub struct cbpf_jit {
+    filter: CompiledFilter,
+}
+
+unsafe fn string(s: *const ::libc::c_char) -> Option<String> {
+    if s.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(s).to_string_lossy().into_owned())
+    }
+}
+
+unsafe fn compile(
+    insns: *const sock_filter,
+    len: usize,
+    options: *const cbpf_jit_options,
+) -> Result<CompiledFilter, String> {
+    if insns.is_null() || len == 0 {
+        return Err("empty program".to_owned());
+    }
+    let insns: Vec<_> = slice::from_raw_parts(insns, len)
+        .iter()
+        .map(|i| BpfInsn::new(i.code, i.jt, i.jf, i.k))
+        .collect();
+    let (optimization, cbpf_optimize, filter_options) = if options.is_null() {
+        (true, false, Options::default())
+    } else {
+        let o = &*options;
+        let filter_options = Options {
+            name: string(o.name).unwrap_or_default(),
+            perf_map: o.perf_map,
+            gdb_jit: o.gdb_jit,
+            cache_dir: string(o.cache_dir),
+            ..Default::default()
+        };
+        (o.optimize, o.cbpf_optimize, filter_options)
+    };
+
+    // invalid programs are rejected without a panic, but a panic must not unwind into C
+    match panic::catch_unwind(AssertUnwindSafe(|| {
+        let insns = if cbpf_optimize {
+            optimize(&insns)
+        } else {
+            insns
+        };
+        CompiledFilter::new(&insns, filter_options, optimization)
+    })) {
+        Ok(r) => r,
+        Err(_) => Err("invalid program".to_owned()),
+    }
+}
+
+// compile the program. `options` may be NULL, which means LLVM optimization only.
+// on failure, NULL is returned and the message is stored to `*err` (if `err` is not NULL),
+// which must be freed with `cbpf_jit_free_error()`
+#[no_mangle]
+pub unsafe extern "C" fn cbpf_jit_compile(
+    insns: *const sock_filter,
+    len: usize,
+    options: *const cbpf_jit_options,
+    err: *mut *mut ::libc::c_char,
+) -> *mut cbpf_jit {
+    if !err.is_null() {
+        *err = ptr::null_mut();
+    }
+    match compile(insns, len, options) {
+        Ok(filter) => Box::into_raw(Box::new(cbpf_jit { filter })),
+        Err(e) => {
+            if !err.is_null() {
+                *err = CString::new(e.replace('\0', " ")).unwrap().into_raw();
+            }
+            ptr::null_mut()
+        }
+    }
+}
+
+// run the filter on a packet of `len` bytes. `handle` can be used from multiple threads.
+// a NULL handle (e.g. of a failed compilation) rejects every packet
+#[no_mangle]
+pub unsafe extern "C" fn cbpf_jit_run(
+    handle: *const cbpf_jit,
+    data: *const u8,
+    len: usize,
+) -> u32 {
+    if handle.is_null() {
+        return 0;
+    }
+    let data: &[u8] = if data.is_null() {
+        &[]
+    } else {
+        // the generated code takes the length as u32
+        slice::from_raw_parts(data, ::std::cmp::min(len, u32::max_value() as usize))
+    };
+    (*handle).filter.run(data)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn cbpf_jit_free(handle: *mut cbpf_jit) {
+    if !handle.is_null() {
+        drop(Box::from_raw(handle));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn cbpf_jit_free_error(err: *mut ::libc::c_char) {
+    if !err.is_null() {
+        drop(CString::from_raw(err));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cbpf::opcode::*;
+
+    #[test]
+    fn c_api() {
+        // ldh [12]; jeq #0x800, L1, L2; L1: ret #0xffff; L2: ret #0
+        let insns = [
+            sock_filter {
+                code: BPF_LD_H_ABS,
+                jt: 0,
+                jf: 0,
+                k: 12,
+            },
+            sock_filter {
+                code: BPF_JEQ_K,
+                jt: 0,
+                jf: 1,
+                k: 0x800,
+            },
+            sock_filter {
+                code: BPF_RET_K,
+                jt: 0,
+                jf: 0,
+                k: 0xffff,
+            },
+            sock_filter {
+                code: BPF_RET_K,
+                jt: 0,
+                jf: 0,
+                k: 0,
+            },
+        ];
+        let mut data = [0u8; 14];
+        data[12] = 0x08;
+        unsafe {
+            let mut err = ptr::null_mut();
+            let handle = cbpf_jit_compile(insns.as_ptr(), insns.len(), ptr::null(), &mut err);
+            assert!(!handle.is_null() && err.is_null());
+            assert_eq!(cbpf_jit_run(handle, data.as_ptr(), data.len()), 0xffff);
+            assert_eq!(cbpf_jit_run(handle, data.as_ptr(), 13), 0);
+            assert_eq!(cbpf_jit_run(handle, ptr::null(), 0), 0);
+            cbpf_jit_free(handle);
+            assert_eq!(cbpf_jit_run(ptr::null(), data.as_ptr(), data.len()), 0);
+
+            // no return
+            let handle = cbpf_jit_compile(insns.as_ptr(), 2, ptr::null(), &mut err);
+            assert!(handle.is_null() && !err.is_null());
+            cbpf_jit_free_error(err);
+
+            // ld #1; jmp|0x50|k; ret #0 with the cBPF optimizer
+            let invalid = [
+                sock_filter {
+                    code: BPF_LD_IMM,
+                    jt: 0,
+                    jf: 0,
+                    k: 1,
+                },
+                sock_filter {
+                    code: BPF_JMP | 0x50 | BPF_K,
+                    jt: 0,
+                    jf: 0,
+                    k: 1,
+                },
+                sock_filter {
+                    code: BPF_RET_K,
+                    jt: 0,
+                    jf: 0,
+                    k: 0,
+                },
+            ];
+            let options = cbpf_jit_options {
+                optimize: true,
+                cbpf_optimize: true,
+                name: ptr::null(),
+                perf_map: false,
+                gdb_jit: false,
+                cache_dir: ptr::null(),
+            };
+            let mut err = ptr::null_mut();
+            let handle = cbpf_jit_compile(invalid.as_ptr(), invalid.len(), &options, &mut err);
+            assert!(handle.is_null() && !err.is_null());
+            cbpf_jit_free_error(err);
+        }
+    }
+}
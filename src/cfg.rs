@@ -0,0 +1,43 @@
+// control flow of cBPF programs
+
+use cbpf::opcode::*;
+
+// indices of the instructions which may be executed right after insns[idx]
+pub fn successors(insns: &[BpfInsn], idx: usize) -> Vec<usize> {
+    let insn = insns[idx];
+    match bpf_class(insn.code) {
+        BPF_RET => vec![],
+        BPF_JMP => if bpf_op(insn.code) == BPF_JA {
+            vec![idx + 1 + insn.k as usize]
+        } else {
+            vec![idx + 1 + insn.jt as usize, idx + 1 + insn.jf as usize]
+        },
+        _ => vec![idx + 1],
+    }
+}
+
+// the first instruction of each basic block, that is the entry, jump targets and
+// instructions following a jump or a return
+pub fn leaders(insns: &[BpfInsn]) -> Vec<bool> {
+    let mut leaders = vec![false; insns.len()];
+    if insns.is_empty() {
+        return leaders;
+    }
+
+    leaders[0] = true;
+    for i in 0..insns.len() {
+        let class = bpf_class(insns[i].code);
+        if class != BPF_JMP && class != BPF_RET {
+            continue;
+        }
+        if i + 1 < insns.len() {
+            leaders[i + 1] = true;
+        }
+        for succ in successors(insns, i) {
+            if succ < insns.len() {
+                leaders[succ] = true;
+            }
+        }
+    }
+    leaders
+}
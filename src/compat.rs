@@ -0,0 +1,138 @@
+// differences between the supported LLVM versions
+//
+// LLVM 5 has typed pointers, so a load, a GEP or a call takes the type from the pointer.
+// Since LLVM 15 pointers are opaque (`ptr`) and the builders without explicit types are
+// removed, so IR is built through these functions, which take the types explicitly and
+// ignore them on LLVM 5.
+
+use llvm::prelude::*;
+
+// moved from Core.h to Target.h
+#[cfg(feature = "llvm-5")]
+pub use llvm::core::LLVMSetModuleDataLayout;
+#[cfg(not(feature = "llvm-5"))]
+pub use llvm::target::LLVMSetModuleDataLayout;
+
+// pointer syntax of textual IR (util.ll and the skeleton of debuginfo.rs)
+#[cfg(feature = "llvm-5")]
+pub static PTR: &'static str = "i8*";
+#[cfg(not(feature = "llvm-5"))]
+pub static PTR: &'static str = "ptr";
+
+// pointer to `ty` (`ptr` on LLVM 15+)
+#[cfg(feature = "llvm-5")]
+pub unsafe fn pointer_type(_context: LLVMContextRef, ty: LLVMTypeRef) -> LLVMTypeRef {
+    ::llvm::core::LLVMPointerType(ty, 0)
+}
+
+#[cfg(not(feature = "llvm-5"))]
+pub unsafe fn pointer_type(context: LLVMContextRef, _ty: LLVMTypeRef) -> LLVMTypeRef {
+    ::llvm::core::LLVMPointerTypeInContext(context, 0)
+}
+
+// type of the function `f`, which is needed to call it
+#[cfg(feature = "llvm-5")]
+pub unsafe fn function_type(f: LLVMValueRef) -> LLVMTypeRef {
+    ::llvm::core::LLVMGetElementType(::llvm::core::LLVMTypeOf(f))
+}
+
+#[cfg(not(feature = "llvm-5"))]
+pub unsafe fn function_type(f: LLVMValueRef) -> LLVMTypeRef {
+    ::llvm::core::LLVMGlobalGetValueType(f)
+}
+
+// load `ty` from p
+#[cfg(feature = "llvm-5")]
+pub unsafe fn build_load(
+    builder: LLVMBuilderRef,
+    _ty: LLVMTypeRef,
+    p: LLVMValueRef,
+    name: *const ::libc::c_char,
+) -> LLVMValueRef {
+    ::llvm::core::LLVMBuildLoad(builder, p, name)
+}
+
+#[cfg(not(feature = "llvm-5"))]
+pub unsafe fn build_load(
+    builder: LLVMBuilderRef,
+    ty: LLVMTypeRef,
+    p: LLVMValueRef,
+    name: *const ::libc::c_char,
+) -> LLVMValueRef {
+    ::llvm::core::LLVMBuildLoad2(builder, ty, p, name)
+}
+
+// getelementptr inbounds ty, p, indices...
+#[cfg(feature = "llvm-5")]
+pub unsafe fn build_gep(
+    builder: LLVMBuilderRef,
+    _ty: LLVMTypeRef,
+    p: LLVMValueRef,
+    indices: &[LLVMValueRef],
+    name: *const ::libc::c_char,
+) -> LLVMValueRef {
+    ::llvm::core::LLVMBuildInBoundsGEP(
+        builder,
+        p,
+        indices.as_ptr() as *mut _,
+        indices.len() as _,
+        name,
+    )
+}
+
+#[cfg(not(feature = "llvm-5"))]
+pub unsafe fn build_gep(
+    builder: LLVMBuilderRef,
+    ty: LLVMTypeRef,
+    p: LLVMValueRef,
+    indices: &[LLVMValueRef],
+    name: *const ::libc::c_char,
+) -> LLVMValueRef {
+    ::llvm::core::LLVMBuildInBoundsGEP2(
+        builder,
+        ty,
+        p,
+        indices.as_ptr() as *mut _,
+        indices.len() as _,
+        name,
+    )
+}
+
+// call f, whose type is `ty`
+#[cfg(feature = "llvm-5")]
+pub unsafe fn build_call(
+    builder: LLVMBuilderRef,
+    _ty: LLVMTypeRef,
+    f: LLVMValueRef,
+    args: &[LLVMValueRef],
+    name: *const ::libc::c_char,
+) -> LLVMValueRef {
+    ::llvm::core::LLVMBuildCall(builder, f, args.as_ptr() as *mut _, args.len() as _, name)
+}
+
+#[cfg(not(feature = "llvm-5"))]
+pub unsafe fn build_call(
+    builder: LLVMBuilderRef,
+    ty: LLVMTypeRef,
+    f: LLVMValueRef,
+    args: &[LLVMValueRef],
+    name: *const ::libc::c_char,
+) -> LLVMValueRef {
+    ::llvm::core::LLVMBuildCall2(
+        builder,
+        ty,
+        f,
+        args.as_ptr() as *mut _,
+        args.len() as _,
+        name,
+    )
+}
+
+// message of the error, which is consumed
+#[cfg(not(feature = "llvm-5"))]
+pub unsafe fn error_message(err: ::llvm::error::LLVMErrorRef) -> String {
+    let msg = ::llvm::error::LLVMGetErrorMessage(err);
+    let s = ::std::ffi::CStr::from_ptr(msg).to_string_lossy().into_owned();
+    ::llvm::error::LLVMDisposeErrorMessage(msg);
+    s
+}
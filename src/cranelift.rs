@@ -0,0 +1,340 @@
+// Cranelift backend
+//
+// It JIT-compiles the instructions of frontend.rs into the same function as the LLVM
+// backend, `i32 main(i8* data, i32 len)`. Cranelift compiles much faster than LLVM,
+// which suits short-lived filters, while the code is less optimized.
+// Each leader of the program is a block, and A, X and M[] are variables, which
+// cranelift-frontend turns into SSA values. Out of bounds loads and division by zero
+// jump to the block which returns 0.
+
+use cbpf::opcode::{BpfInsn, BPF_MEMWORDS};
+use cfg;
+use cranelift_codegen::entity::EntityRef;
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{types, AbiParam, Block, InstBuilder, MemFlags, Type, Value};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Linkage, Module};
+use frontend::{self, AluOp, Cond, Insn, Reg, Src};
+use Func;
+
+const A: usize = 0;
+const X: usize = 1;
+const M: usize = 2;
+
+pub struct CraneliftFilter {
+    // owns the code of `func`
+    module: Option<JITModule>,
+    func: Func,
+}
+
+impl CraneliftFilter {
+    pub fn run(&self, data: &[u8]) -> i32 {
+        (self.func)(data.as_ptr() as *mut u8, data.len() as u32)
+    }
+}
+
+impl Drop for CraneliftFilter {
+    fn drop(&mut self) {
+        if let Some(module) = self.module.take() {
+            unsafe { module.free_memory() };
+        }
+    }
+}
+
+fn var(reg: Reg) -> Variable {
+    match reg {
+        Reg::A => Variable::new(A),
+        Reg::X => Variable::new(X),
+    }
+}
+
+struct Translator<'a> {
+    builder: FunctionBuilder<'a>,
+    ptr_type: Type,
+    data: Value,
+    len: Value,
+    // block of each leader
+    blocks: Vec<Option<Block>>,
+    ret0: Block,
+}
+
+impl<'a> Translator<'a> {
+    fn src(&mut self, src: Src) -> Value {
+        match src {
+            Src::X => self.builder.use_var(Variable::new(X)),
+            Src::K(k) => self.builder.ins().iconst(types::I32, k as i64),
+        }
+    }
+
+    // jump to ret0 if cond is true
+    fn ret0_if(&mut self, cond: Value) {
+        let next = self.builder.create_block();
+        self.builder.ins().brif(cond, self.ret0, &[], next, &[]);
+        self.builder.switch_to_block(next);
+    }
+
+    // return 0 unless offset + size <= len (offset is i64)
+    fn bounds_check(&mut self, offset: Value, size: u64) {
+        let end = self.builder.ins().iadd_imm(offset, size as i64);
+        let len = self.builder.ins().uextend(types::I64, self.len);
+        let cond = self.builder.ins().icmp(IntCC::UnsignedGreaterThan, end, len);
+        self.ret0_if(cond);
+    }
+
+    // big endian value of `size` bytes at data + offset (offset is i64)
+    fn load(&mut self, offset: Value, size: u64) -> Value {
+        let offset = if self.ptr_type == types::I64 {
+            offset
+        } else {
+            self.builder.ins().ireduce(self.ptr_type, offset)
+        };
+        let addr = self.builder.ins().iadd(self.data, offset);
+        let flags = MemFlags::new();
+        let ty = match size {
+            1 => return self.builder.ins().uload8(types::I32, flags, addr, 0),
+            2 => types::I16,
+            _ => types::I32,
+        };
+        let mut v = self.builder.ins().load(ty, flags, addr, 0);
+        if cfg!(target_endian = "little") {
+            v = self.builder.ins().bswap(v);
+        }
+        if ty != types::I32 {
+            v = self.builder.ins().uextend(types::I32, v);
+        }
+        v
+    }
+
+    fn alu(&mut self, op: AluOp, src: Src) {
+        let a = self.builder.use_var(Variable::new(A));
+        let v = self.src(src);
+        let ins = self.builder.ins();
+        let a = match op {
+            AluOp::Add => ins.iadd(a, v),
+            AluOp::Sub => ins.isub(a, v),
+            AluOp::Mul => ins.imul(a, v),
+            AluOp::And => ins.band(a, v),
+            AluOp::Or => ins.bor(a, v),
+            AluOp::Xor => ins.bxor(a, v),
+            AluOp::Div | AluOp::Mod => {
+                // udiv traps on zero
+                if src != Src::K(0) {
+                    let cond = self.builder.ins().icmp_imm(IntCC::Equal, v, 0);
+                    self.ret0_if(cond);
+                } else {
+                    self.builder.ins().jump(self.ret0, &[]);
+                    let next = self.builder.create_block();
+                    self.builder.switch_to_block(next);
+                }
+                if op == AluOp::Div {
+                    self.builder.ins().udiv(a, v)
+                } else {
+                    self.builder.ins().urem(a, v)
+                }
+            }
+            // Cranelift takes the shift count modulo 32
+            AluOp::Lsh | AluOp::Rsh => {
+                let shifted = if op == AluOp::Lsh {
+                    ins.ishl(a, v)
+                } else {
+                    ins.ushr(a, v)
+                };
+                let zero = self.builder.ins().iconst(types::I32, 0);
+                let cond = self.builder.ins().icmp_imm(IntCC::UnsignedLessThan, v, 32);
+                self.builder.ins().select(cond, shifted, zero)
+            }
+        };
+        self.builder.def_var(Variable::new(A), a);
+    }
+
+    fn translate(&mut self, idx: usize, insn: Insn) {
+        match insn {
+            Insn::RetA => {
+                let a = self.builder.use_var(Variable::new(A));
+                self.builder.ins().return_(&[a]);
+            }
+            Insn::RetK(k) => {
+                let k = self.builder.ins().iconst(types::I32, k as i64);
+                self.builder.ins().return_(&[k]);
+            }
+            Insn::LdAbs { size, k } => {
+                let offset = self.builder.ins().iconst(types::I64, k as i64);
+                self.bounds_check(offset, size);
+                let v = self.load(offset, size);
+                self.builder.def_var(Variable::new(A), v);
+            }
+            Insn::LdInd { size, k } => {
+                // X + k does not wrap around
+                let x = self.builder.use_var(Variable::new(X));
+                let x = self.builder.ins().uextend(types::I64, x);
+                let offset = self.builder.ins().iadd_imm(x, k as i64);
+                self.bounds_check(offset, size);
+                let v = self.load(offset, size);
+                self.builder.def_var(Variable::new(A), v);
+            }
+            Insn::LdxMsh(k) => {
+                let offset = self.builder.ins().iconst(types::I64, k as i64);
+                self.bounds_check(offset, 1);
+                let v = self.load(offset, 1);
+                let v = self.builder.ins().band_imm(v, 0xf);
+                let v = self.builder.ins().ishl_imm(v, 2);
+                self.builder.def_var(Variable::new(X), v);
+            }
+            Insn::Imm(reg, k) => {
+                let k = self.builder.ins().iconst(types::I32, k as i64);
+                self.builder.def_var(var(reg), k);
+            }
+            Insn::Len(reg) => {
+                let len = self.len;
+                self.builder.def_var(var(reg), len);
+            }
+            Insn::LdMem(reg, i) => {
+                let v = self.builder.use_var(Variable::new(M + i));
+                self.builder.def_var(var(reg), v);
+            }
+            Insn::St(reg, i) => {
+                let v = self.builder.use_var(var(reg));
+                self.builder.def_var(Variable::new(M + i), v);
+            }
+            Insn::Alu(op, src) => self.alu(op, src),
+            Insn::Neg => {
+                let a = self.builder.use_var(Variable::new(A));
+                let a = self.builder.ins().ineg(a);
+                self.builder.def_var(Variable::new(A), a);
+            }
+            Insn::Ja(t) => {
+                let target = self.blocks[t].unwrap();
+                self.builder.ins().jump(target, &[]);
+            }
+            Insn::Jmp { cond, src, jt, jf } => {
+                let a = self.builder.use_var(Variable::new(A));
+                let v = self.src(src);
+                let ins = self.builder.ins();
+                let c = match cond {
+                    Cond::Eq => ins.icmp(IntCC::Equal, a, v),
+                    Cond::Gt => ins.icmp(IntCC::UnsignedGreaterThan, a, v),
+                    Cond::Ge => ins.icmp(IntCC::UnsignedGreaterThanOrEqual, a, v),
+                    Cond::Set => {
+                        let v = ins.band(a, v);
+                        self.builder.ins().icmp_imm(IntCC::NotEqual, v, 0)
+                    }
+                };
+                let (jt, jf) = (self.blocks[jt].unwrap(), self.blocks[jf].unwrap());
+                self.builder.ins().brif(c, jt, &[], jf, &[]);
+            }
+            Insn::Tax => {
+                let a = self.builder.use_var(Variable::new(A));
+                self.builder.def_var(Variable::new(X), a);
+            }
+            Insn::Txa => {
+                let x = self.builder.use_var(Variable::new(X));
+                self.builder.def_var(Variable::new(A), x);
+            }
+        }
+
+        if let Some(Some(next)) = self.blocks.get(idx + 1).cloned() {
+            match insn {
+                Insn::RetA | Insn::RetK(_) | Insn::Ja(_) | Insn::Jmp { .. } => {}
+                // fall through to the next leader
+                _ => {
+                    self.builder.ins().jump(next, &[]);
+                }
+            }
+            self.builder.switch_to_block(next);
+        }
+    }
+}
+
+pub fn compile(insns: &[BpfInsn], optimization: bool) -> Result<CraneliftFilter, String> {
+    let decoded = frontend::decode(insns)?;
+
+    let mut flags = settings::builder();
+    let opt_level = if optimization { "speed" } else { "none" };
+    flags.set("opt_level", opt_level).map_err(|e| e.to_string())?;
+    let isa = cranelift_native::builder()?
+        .finish(settings::Flags::new(flags))
+        .map_err(|e| e.to_string())?;
+    let mut module = JITModule::new(JITBuilder::with_isa(isa, default_libcall_names()));
+
+    let ptr_type = module.target_config().pointer_type();
+    let mut ctx = module.make_context();
+    ctx.func.signature.params.push(AbiParam::new(ptr_type));
+    ctx.func.signature.params.push(AbiParam::new(types::I32));
+    ctx.func.signature.returns.push(AbiParam::new(types::I32));
+    let id = module
+        .declare_function("main", Linkage::Local, &ctx.func.signature)
+        .map_err(|e| e.to_string())?;
+
+    let mut builder_ctx = FunctionBuilderContext::new();
+    {
+        let mut builder = FunctionBuilder::new(&mut ctx.func, &mut builder_ctx);
+        let entry = builder.create_block();
+        builder.append_block_params_for_function_params(entry);
+        builder.switch_to_block(entry);
+        let (data, len) = {
+            let params = builder.block_params(entry);
+            (params[0], params[1])
+        };
+        for i in 0..M + BPF_MEMWORDS as usize {
+            builder.declare_var(Variable::new(i), types::I32);
+            let zero = builder.ins().iconst(types::I32, 0);
+            builder.def_var(Variable::new(i), zero);
+        }
+
+        let blocks: Vec<Option<Block>> = cfg::leaders(insns)
+            .into_iter()
+            .map(|leader| if leader { Some(builder.create_block()) } else { None })
+            .collect();
+        let ret0 = builder.create_block();
+        builder.ins().jump(blocks[0].unwrap(), &[]);
+        builder.switch_to_block(blocks[0].unwrap());
+
+        let mut translator = Translator {
+            builder,
+            ptr_type,
+            data,
+            len,
+            blocks,
+            ret0,
+        };
+        for (idx, insn) in decoded.into_iter().enumerate() {
+            translator.translate(idx, insn);
+        }
+
+        let mut builder = translator.builder;
+        builder.switch_to_block(ret0);
+        let zero = builder.ins().iconst(types::I32, 0);
+        builder.ins().return_(&[zero]);
+        builder.seal_all_blocks();
+        builder.finalize();
+    }
+
+    module
+        .define_function(id, &mut ctx)
+        .map_err(|e| format!("{:?}", e))?;
+    module.clear_context(&mut ctx);
+    module.finalize_definitions().map_err(|e| e.to_string())?;
+    let code = module.get_finalized_function(id);
+    Ok(CraneliftFilter {
+        module: Some(module),
+        func: unsafe { ::std::mem::transmute::<*const u8, Func>(code) },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cbpf::opcode::*;
+
+    // the compiled programs are run by frontend.rs
+    #[test]
+    fn invalid() {
+        let insns = [
+            BpfInsn::new(BPF_LD_MEM, 0, 0, 16),
+            BpfInsn::new(BPF_RET_A, 0, 0, 0),
+        ];
+        assert!(compile(&insns, true).is_err());
+    }
+}
@@ -0,0 +1,175 @@
+// C source backend
+//
+// The program is translated to a self-contained C function
+// `uint32_t <name>(const uint8_t *pkt, uint32_t len)` with the same semantics as the
+// generated LLVM IR (see frontend.rs): A, X and M[] are uint32_t, out of bounds loads and
+// division by zero return 0, and X + k of indirect loads does not wrap around.
+// Each instruction is preceded by its disassembly and jump targets have labels.
+
+use cbpf::opcode::*;
+use cfg;
+use disasm::disasm;
+use frontend::{self, AluOp, Cond, Insn, Reg, Src};
+
+static HELPERS: &'static str = "\
+#ifndef CBPF_HELPERS
+#define CBPF_HELPERS
+static inline uint32_t cbpf_ld_w(const uint8_t *p)
+{
+    return (uint32_t)p[0] << 24 | (uint32_t)p[1] << 16 | (uint32_t)p[2] << 8 | (uint32_t)p[3];
+}
+
+static inline uint32_t cbpf_ld_h(const uint8_t *p)
+{
+    return (uint32_t)p[0] << 8 | (uint32_t)p[1];
+}
+
+static inline uint32_t cbpf_ld_b(const uint8_t *p)
+{
+    return (uint32_t)p[0];
+}
+#endif
+";
+
+fn helper(size: u64) -> &'static str {
+    match size {
+        4 => "cbpf_ld_w",
+        2 => "cbpf_ld_h",
+        _ => "cbpf_ld_b",
+    }
+}
+
+fn reg(reg: Reg) -> &'static str {
+    match reg {
+        Reg::A => "A",
+        Reg::X => "X",
+    }
+}
+
+// the statements of the instruction
+fn statements(insn: Insn) -> String {
+    let src = |src: Src| match src {
+        Src::X => "X".to_owned(),
+        Src::K(k) => format!("{:#x}u", k),
+    };
+    match insn {
+        Insn::RetA => "return A;".to_owned(),
+        Insn::RetK(k) => format!("return {:#x}u;", k),
+        Insn::LdAbs { size, k } => format!(
+            "if ({}u > len) return 0;\n    A = {}(pkt + {:#x}u);",
+            k as u64 + size,
+            helper(size),
+            k
+        ),
+        Insn::LdInd { size, k } => format!(
+            "off = (uint64_t)X + {:#x}u;\n    \
+             if (off + {} > len) return 0;\n    \
+             A = {}(pkt + off);",
+            k,
+            size,
+            helper(size)
+        ),
+        Insn::LdxMsh(k) => format!(
+            "if ({}u > len) return 0;\n    X = (pkt[{:#x}u] & 0xf) << 2;",
+            k as u64 + 1,
+            k
+        ),
+        Insn::Imm(r, k) => format!("{} = {:#x}u;", reg(r), k),
+        Insn::Len(r) => format!("{} = len;", reg(r)),
+        Insn::LdMem(r, i) => format!("{} = M[{}];", reg(r), i),
+        Insn::St(r, i) => format!("M[{}] = {};", i, reg(r)),
+        Insn::Alu(op, s) => {
+            let s = src(s);
+            match op {
+                AluOp::Add => format!("A += {};", s),
+                AluOp::Sub => format!("A -= {};", s),
+                AluOp::Mul => format!("A *= {};", s),
+                AluOp::Div => format!("if ({} == 0) return 0;\n    A /= {};", s, s),
+                AluOp::Mod => format!("if ({} == 0) return 0;\n    A %= {};", s, s),
+                AluOp::And => format!("A &= {};", s),
+                AluOp::Or => format!("A |= {};", s),
+                AluOp::Xor => format!("A ^= {};", s),
+                // shifting by 32 or more is undefined in C
+                AluOp::Lsh => format!("A = {} < 32 ? A << {} : 0;", s, s),
+                AluOp::Rsh => format!("A = {} < 32 ? A >> {} : 0;", s, s),
+            }
+        }
+        Insn::Neg => "A = -A;".to_owned(),
+        Insn::Ja(t) => format!("goto insn_{};", t),
+        Insn::Jmp { cond, src: s, jt, jf } => {
+            let s = src(s);
+            let cond = match cond {
+                Cond::Eq => format!("A == {}", s),
+                Cond::Gt => format!("A > {}", s),
+                Cond::Ge => format!("A >= {}", s),
+                Cond::Set => format!("(A & {}) != 0", s),
+            };
+            format!("if ({}) goto insn_{};\n    goto insn_{};", cond, jt, jf)
+        }
+        Insn::Tax => "X = A;".to_owned(),
+        Insn::Txa => "A = X;".to_owned(),
+    }
+}
+
+// instructions which are jumped to. unlike `cfg::leaders()`, instructions which are only
+// reached by falling through are excluded, since their labels would be unused
+fn jump_targets(insns: &[BpfInsn]) -> Vec<bool> {
+    let mut targets = vec![false; insns.len()];
+    for i in 0..insns.len() {
+        if bpf_class(insns[i].code) != BPF_JMP {
+            continue;
+        }
+        for succ in cfg::successors(insns, i) {
+            if succ < insns.len() {
+                targets[succ] = true;
+            }
+        }
+    }
+    targets
+}
+
+// C source of the function `name`. the program must end with a return
+pub fn to_c(insns: &[BpfInsn], name: &str) -> Result<String, String> {
+    let decoded = frontend::decode(insns)?;
+    let targets = jump_targets(insns);
+    let mut body = String::new();
+    for (i, insn) in decoded.into_iter().enumerate() {
+        if targets[i] {
+            body += &format!("insn_{}:\n", i);
+        }
+        body += &format!(
+            "    /* {} */\n    {}\n",
+            disasm(&insns[i]).replace("*/", "* /"),
+            statements(insn)
+        );
+    }
+
+    let mut c = String::new();
+    c += "#include <stdint.h>\n\n";
+    c += HELPERS;
+    c += "\n";
+    c += &format!("uint32_t {}(const uint8_t *pkt, uint32_t len)\n{{\n", name);
+    c += &format!(
+        "    uint32_t A = 0, X = 0;\n    uint32_t M[{}] = {{0}};\n    uint64_t off = 0;\n\n",
+        BPF_MEMWORDS
+    );
+    c += "    (void)pkt; (void)A; (void)X; (void)M; (void)off;\n\n";
+    c += &body;
+    c += "}\n";
+    Ok(c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn c_source() {
+        let insns = [BpfInsn::new(BPF_RET_K, 0, 0, 0)];
+        assert!(to_c(&insns[..0], "f").is_err());
+        assert!(to_c(&[BpfInsn::new(BPF_ST, 0, 0, 16), insns[0]], "f").is_err());
+        let c = to_c(&insns, "f").unwrap();
+        assert!(c.contains("uint32_t f(const uint8_t *pkt, uint32_t len)\n{\n"));
+        assert!(c.contains("    /* ret 0 */\n    return 0x0u;\n"));
+    }
+}
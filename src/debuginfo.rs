@@ -0,0 +1,71 @@
+// DWARF debug information
+//
+// LLVM 5 C API cannot create debug info metadata (DIBuilder is not exposed), so we
+// parse a skeleton module which defines `main` with its DISubprogram and one DILocation
+// per cBPF instruction. The locations are attached to the instructions of the skeleton
+// body, from which the converter takes them before deleting the body.
+// Line N of the synthetic source "filter.bpf" is the (N-1)-th cBPF instruction.
+
+use cbpf::opcode::*;
+#[cfg(feature = "llvm")]
+use compat;
+use disasm::disasm;
+
+#[cfg_attr(not(feature = "llvm"), allow(dead_code))]
+pub static FILENAME: &'static str = "filter.bpf";
+
+// content of "filter.bpf": the disassembly of the program, one instruction per line
+pub fn source(insns: &[BpfInsn]) -> String {
+    insns
+        .iter()
+        .map(|insn| format!("{}\n", disasm(insn)))
+        .collect()
+}
+
+#[cfg(feature = "llvm")]
+pub fn skeleton(n: usize, directory: &str) -> String {
+    let mut ir = String::new();
+    ir += &format!("define i32 @main({}, i32) !dbg !5 {{\n", compat::PTR);
+    for i in 0..n {
+        ir += &format!("  call void @llvm.donothing(), !dbg !{}\n", 7 + i);
+    }
+    ir += "  ret i32 0\n}\n\n";
+    ir += "declare void @llvm.donothing()\n\n";
+    ir += "!llvm.dbg.cu = !{!0}\n";
+    ir += "!llvm.module.flags = !{!3, !4}\n\n";
+    ir += "!0 = distinct !DICompileUnit(language: DW_LANG_C99, file: !1, \
+           producer: \"cbpf-to-llvm-ir\", isOptimized: true, runtimeVersion: 0, \
+           emissionKind: FullDebug, enums: !2)\n";
+    ir += &format!(
+        "!1 = !DIFile(filename: \"{}\", directory: \"{}\")\n",
+        FILENAME,
+        directory.replace('\\', "\\\\").replace('"', "\\22")
+    );
+    ir += "!2 = !{}\n";
+    ir += "!3 = !{i32 2, !\"Dwarf Version\", i32 4}\n";
+    ir += "!4 = !{i32 2, !\"Debug Info Version\", i32 3}\n";
+    ir += "!5 = distinct !DISubprogram(name: \"main\", scope: !1, file: !1, line: 1, \
+           type: !6, isLocal: false, isDefinition: true, scopeLine: 1, isOptimized: true, \
+           unit: !0)\n";
+    ir += "!6 = !DISubroutineType(types: !2)\n";
+    for i in 0..n {
+        ir += &format!("!{} = !DILocation(line: {}, scope: !5)\n", 7 + i, i + 1);
+    }
+    // IRParse requires null terminated strings
+    ir += "\0";
+    ir
+}
+
+#[cfg(all(test, feature = "llvm"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skeleton_locations() {
+        let ir = skeleton(2, "/tmp");
+        assert!(ir.contains("call void @llvm.donothing(), !dbg !8\n  ret i32 0"));
+        assert!(ir.contains("!1 = !DIFile(filename: \"filter.bpf\", directory: \"/tmp\")"));
+        assert!(ir.contains("!8 = !DILocation(line: 2, scope: !5)\n"));
+        assert!(ir.ends_with("\0"));
+    }
+}
@@ -0,0 +1,114 @@
+// disassembler of cBPF instructions
+
+use cbpf::opcode::*;
+
+fn size_suffix(size: u16) -> &'static str {
+    match size {
+        BPF_W => "w",
+        BPF_H => "h",
+        BPF_B => "b",
+        _ => "?",
+    }
+}
+
+fn src_operand(insn: &BpfInsn) -> String {
+    if bpf_src(insn.code) == BPF_X {
+        "X".to_owned()
+    } else {
+        format!("{}", insn.k)
+    }
+}
+
+pub fn disasm(insn: &BpfInsn) -> String {
+    let code = insn.code;
+    let k = insn.k;
+    match bpf_class(code) {
+        BPF_LD => {
+            let op = format!("ld{}", size_suffix(bpf_size(code)));
+            match bpf_mode(code) {
+                BPF_ABS => format!("{} [{}]", op, k),
+                BPF_IND => format!("{} [{}+X]", op, k),
+                BPF_IMM => format!("{} {}", op, k),
+                BPF_MEM => format!("{} MEM[{}]", op, k),
+                BPF_LEN => format!("{} len", op),
+                _ => format!("unknown {:#04x}", code),
+            }
+        }
+        BPF_LDX => {
+            let op = format!("ldx{}", size_suffix(bpf_size(code)));
+            match bpf_mode(code) {
+                BPF_IMM => format!("{} {}", op, k),
+                BPF_MEM => format!("{} MEM[{}]", op, k),
+                BPF_LEN => format!("{} len", op),
+                BPF_MSH => format!("{} ([{}] & 0xf) << 2", op, k),
+                _ => format!("unknown {:#04x}", code),
+            }
+        }
+        BPF_ST => format!("st MEM[{}]", k),
+        BPF_STX => format!("stx MEM[{}]", k),
+        BPF_ALU => {
+            let op = match bpf_op(code) {
+                BPF_ADD => "add",
+                BPF_SUB => "sub",
+                BPF_MUL => "mul",
+                BPF_DIV => "div",
+                BPF_MOD => "mod",
+                BPF_AND => "and",
+                BPF_OR => "or",
+                BPF_XOR => "xor",
+                BPF_LSH => "lsh",
+                BPF_RSH => "rsh",
+                BPF_NEG => return "neg".to_owned(),
+                _ => return format!("unknown {:#04x}", code),
+            };
+            format!("{} {}", op, src_operand(insn))
+        }
+        BPF_JMP => {
+            let op = match bpf_op(code) {
+                BPF_JA => return format!("ja {}", k),
+                BPF_JEQ => "jeq",
+                BPF_JGT => "jgt",
+                BPF_JGE => "jge",
+                BPF_JSET => "jset",
+                _ => return format!("unknown {:#04x}", code),
+            };
+            format!("{} {} {} {}", op, src_operand(insn), insn.jt, insn.jf)
+        }
+        BPF_RET => match bpf_rval(code) {
+            BPF_A => "ret A".to_owned(),
+            _ => format!("ret {}", k),
+        },
+        _ => match bpf_miscop(code) {
+            BPF_TAX => "tax".to_owned(),
+            BPF_TXA => "txa".to_owned(),
+            _ => format!("unknown {:#04x}", code),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disasm_insns() {
+        let cases = [
+            (BpfInsn::new(BPF_LD_H_ABS, 0, 0, 12), "ldh [12]"),
+            (BpfInsn::new(BPF_LD_B_IND, 0, 0, 14), "ldb [14+X]"),
+            (BpfInsn::new(BPF_LD_IMM, 0, 0, 2), "ldw 2"),
+            (BpfInsn::new(BPF_LDX_MEM, 0, 0, 3), "ldxw MEM[3]"),
+            (BpfInsn::new(BPF_LDX_B_MSH, 0, 0, 14), "ldxb ([14] & 0xf) << 2"),
+            (BpfInsn::new(BPF_ST, 0, 0, 1), "st MEM[1]"),
+            (BpfInsn::new(BPF_AND_X, 0, 0, 0), "and X"),
+            (BpfInsn::new(BPF_RSH_K, 0, 0, 2), "rsh 2"),
+            (BpfInsn::new(BPF_JEQ_K, 0, 6, 34525), "jeq 34525 0 6"),
+            (BpfInsn::new(BPF_JMP_JA, 0, 0, 3), "ja 3"),
+            (BpfInsn::new(BPF_MISC_TAX, 0, 0, 0), "tax"),
+            (BpfInsn::new(BPF_RET_K, 0, 0, 65535), "ret 65535"),
+            (BpfInsn::new(BPF_RET_A, 0, 0, 0), "ret A"),
+        ];
+        for &(insn, expected) in cases.iter() {
+            assert_eq!(disasm(&insn), expected);
+        }
+    }
+}
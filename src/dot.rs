@@ -0,0 +1,82 @@
+// Graphviz DOT output of the control flow graph
+
+use cbpf::opcode::*;
+use cfg;
+use disasm::disasm;
+
+// escape a string for a double-quoted DOT label
+pub fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+// render the cBPF control flow graph. Each node is a basic block, which has the same
+// name as the corresponding LLVM basic block ("insn.N").
+pub fn to_dot(insns: &[BpfInsn]) -> String {
+    let n = insns.len();
+    let leaders = cfg::leaders(insns);
+    let mut nodes = String::new();
+    let mut edges = String::new();
+
+    let mut i = 0;
+    while i < n {
+        let start = i;
+        let mut label = format!("insn.{}:\\l", start);
+        loop {
+            label += &format!("{}: {}\\l", i, escape(&disasm(&insns[i])));
+            i += 1;
+            if i >= n || leaders[i] {
+                break;
+            }
+        }
+        nodes += &format!("    \"insn.{}\" [label=\"{}\"];\n", start, label);
+
+        let last = i - 1;
+        let succs = cfg::successors(insns, last);
+        let conditional = bpf_class(insns[last].code) == BPF_JMP && bpf_op(insns[last].code) != BPF_JA;
+        for (j, succ) in succs.into_iter().enumerate() {
+            let target = if succ < n {
+                format!("\"insn.{}\"", succ)
+            } else {
+                // jump out of the program
+                "\"invalid\"".to_owned()
+            };
+            let attr = match (conditional, j) {
+                (true, 0) => " [label=\"true\"]",
+                (true, _) => " [label=\"false\", style=dashed]",
+                _ => "",
+            };
+            edges += &format!("    \"insn.{}\" -> {}{};\n", start, target, attr);
+        }
+    }
+
+    format!(
+        "digraph cbpf {{\n    node [shape=box, fontname=\"monospace\"];\n{}{}}}\n",
+        nodes, edges
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dot() {
+        // ldh [12]; jeq #0x800, L1, L2; L1: ret #0xffff; L2: ret #0
+        let insns = [
+            BpfInsn::new(BPF_LD_H_ABS, 0, 0, 12),
+            BpfInsn::new(BPF_JEQ_K, 0, 1, 0x0800),
+            BpfInsn::new(BPF_RET_K, 0, 0, 0xffff),
+            BpfInsn::new(BPF_RET_K, 0, 0, 0),
+        ];
+        assert_eq!(
+            to_dot(&insns),
+            "digraph cbpf {\n    node [shape=box, fontname=\"monospace\"];\n    \
+             \"insn.0\" [label=\"insn.0:\\l0: ldh [12]\\l1: jeq 2048 0 1\\l\"];\n    \
+             \"insn.2\" [label=\"insn.2:\\l2: ret 65535\\l\"];\n    \
+             \"insn.3\" [label=\"insn.3:\\l3: ret 0\\l\"];\n    \
+             \"insn.0\" -> \"insn.2\" [label=\"true\"];\n    \
+             \"insn.0\" -> \"insn.3\" [label=\"false\", style=dashed];\n\
+             }\n"
+        );
+    }
+}
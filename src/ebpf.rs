@@ -0,0 +1,332 @@
+// eBPF output
+//
+// With `Options::ebpf`, main becomes the entry point of an eBPF program: it takes the
+// context (`struct xdp_md *` or `struct __sk_buff *`), reads the packet through its
+// data/data_end, and returns the action for the verdict (`accept` if the cBPF program
+// returns non-zero, otherwise `reject`). XDP programs can redirect packets to a map
+// with `bpf_redirect_map()`.
+// Every packet access is checked by comparing the pointer with data_end, as the
+// verifier requires, and `Converter::emit_object()` compiles it with the BPF target.
+// Socket filters cannot read data/data_end. They read the packet with LD_ABS/LD_IND
+// (`llvm.bpf.load.*`), which return 0 from the program when the access is out of bounds
+// like cBPF, and return the verdict of the cBPF program as the number of bytes to keep.
+// `sections()` reads the ELF object so that the result can be inspected without a kernel.
+
+use aot;
+use cbpf::opcode::*;
+#[cfg(feature = "llvm")]
+use llvm::target_machine::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProgramType {
+    // XDP (struct xdp_md)
+    Xdp,
+    // tc classifier (struct __sk_buff)
+    Classifier,
+    // socket filter (struct __sk_buff)
+    SocketFilter,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MapType {
+    // BPF_MAP_TYPE_DEVMAP: redirect to another interface
+    Devmap,
+    // BPF_MAP_TYPE_CPUMAP: redirect to another CPU
+    Cpumap,
+    // BPF_MAP_TYPE_XSKMAP: redirect to an AF_XDP socket
+    Xskmap,
+}
+
+impl MapType {
+    pub fn id(&self) -> u32 {
+        match *self {
+            MapType::Devmap => 14,
+            MapType::Cpumap => 16,
+            MapType::Xskmap => 17,
+        }
+    }
+}
+
+// map defined in the legacy "maps" section, which iproute2 creates. libbpf 1.0 and
+// later only load maps defined with BTF in ".maps", so see `EbpfTarget::iproute2_maps`
+#[derive(Debug, Clone, PartialEq)]
+pub struct RedirectMap {
+    pub name: String,
+    pub map_type: MapType,
+    pub max_entries: u32,
+    // the packet is redirected to the entry of this key
+    pub key: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Pass,
+    Drop,
+    // XDP only
+    Redirect(RedirectMap),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EbpfTarget {
+    pub program_type: ProgramType,
+    // bpfeb instead of bpfel
+    pub big_endian: bool,
+    // action for packets which the filter accepts. socket filters ignore the actions
+    pub accept: Action,
+    // action for the other packets (including out of bounds accesses)
+    pub reject: Action,
+    // the filter sees the packet from this offset (see `linktype_offset()`)
+    pub offset: u32,
+    // the object is loaded by iproute2 (ip link set ... xdp obj), which is required to
+    // redirect packets
+    pub iproute2_maps: bool,
+}
+
+// XDP_DROP, XDP_PASS
+const XDP_ACTIONS: (u32, u32) = (1, 2);
+// TC_ACT_SHOT, TC_ACT_OK
+const TC_ACTIONS: (u32, u32) = (2, 0);
+
+#[cfg(feature = "llvm")]
+pub const BPF_FUNC_REDIRECT_MAP: u64 = 51;
+
+// number of bytes before the start of the packet which the filter expects.
+// programs see Ethernet frames, so raw IP filters skip the Ethernet header
+pub fn linktype_offset(linktype: i32) -> Result<u32, String> {
+    match linktype {
+        // EN10MB
+        1 => Ok(0),
+        // RAW (12 on Linux, 14 on OpenBSD, 101 in pcap files), IPV4, IPV6
+        12 | 14 | 101 | 228 | 229 => Ok(14),
+        _ => Err(format!("linktype {} is not supported for eBPF output", linktype)),
+    }
+}
+
+impl EbpfTarget {
+    pub fn new(program_type: ProgramType) -> Self {
+        EbpfTarget {
+            program_type,
+            big_endian: false,
+            accept: Action::Pass,
+            reject: Action::Drop,
+            offset: 0,
+            iproute2_maps: false,
+        }
+    }
+
+    pub fn triple(&self) -> &'static str {
+        if self.big_endian {
+            "bpfeb"
+        } else {
+            "bpfel"
+        }
+    }
+
+    // the section name which loaders (ip, tc and libbpf) look for
+    pub fn section(&self) -> &'static str {
+        match self.program_type {
+            ProgramType::Xdp => "xdp",
+            ProgramType::Classifier => "classifier",
+            ProgramType::SocketFilter => "socket",
+        }
+    }
+
+    // offsets of data and data_end in the context, which socket filters cannot read
+    pub fn data_offsets(&self) -> Option<(u64, u64)> {
+        match self.program_type {
+            ProgramType::Xdp => Some((0, 4)),
+            ProgramType::Classifier => Some((76, 80)),
+            ProgramType::SocketFilter => None,
+        }
+    }
+
+    // offset of the packet length in the context. xdp_md does not have it, and the
+    // verifier does not allow data_end - data
+    pub fn len_offset(&self) -> Option<u64> {
+        match self.program_type {
+            ProgramType::Xdp => None,
+            ProgramType::Classifier | ProgramType::SocketFilter => Some(0),
+        }
+    }
+
+    // return value of pass and drop. socket filters return the verdict instead
+    pub fn action_code(&self, action: &Action) -> Option<u32> {
+        let (drop, pass) = match self.program_type {
+            ProgramType::Xdp => XDP_ACTIONS,
+            ProgramType::Classifier => TC_ACTIONS,
+            ProgramType::SocketFilter => return None,
+        };
+        match *action {
+            Action::Pass => Some(pass),
+            Action::Drop => Some(drop),
+            Action::Redirect(_) => None,
+        }
+    }
+
+    #[cfg(feature = "llvm")]
+    pub fn target_machine(&self) -> Result<LLVMTargetMachineRef, String> {
+        unsafe {
+            ::llvm::target::LLVMInitializeBPFTargetInfo();
+            ::llvm::target::LLVMInitializeBPFTarget();
+            ::llvm::target::LLVMInitializeBPFTargetMC();
+            ::llvm::target::LLVMInitializeBPFAsmPrinter();
+        }
+        aot::target_machine_for(
+            self.triple(),
+            LLVMRelocMode::LLVMRelocDefault,
+            LLVMCodeModel::LLVMCodeModelDefault,
+        )
+    }
+
+    pub fn check(&self, insns: &[BpfInsn]) -> Result<(), String> {
+        let mut maps: Vec<&RedirectMap> = vec![];
+        for action in &[&self.accept, &self.reject] {
+            if let Action::Redirect(ref map) = **action {
+                if self.program_type != ProgramType::Xdp {
+                    return Err("only XDP programs can redirect packets".to_owned());
+                }
+                if !self.iproute2_maps {
+                    return Err(
+                        "redirecting packets needs a map which only iproute2 loads".to_owned(),
+                    );
+                }
+                if !aot::is_identifier(&map.name) {
+                    return Err(format!("invalid map name: {}", map.name));
+                }
+                if map.key >= map.max_entries {
+                    return Err(format!("key {} is out of the map {}", map.key, map.name));
+                }
+                if maps.iter().any(|m| {
+                    m.name == map.name
+                        && (m.map_type != map.map_type || m.max_entries != map.max_entries)
+                }) {
+                    return Err(format!("map {} is defined twice", map.name));
+                }
+                maps.push(map);
+            }
+        }
+        if self.len_offset().is_none() {
+            for (i, insn) in insns.iter().enumerate() {
+                let class = bpf_class(insn.code);
+                if (class == BPF_LD || class == BPF_LDX) && bpf_mode(insn.code) == BPF_LEN {
+                    return Err(format!(
+                        "{}: the packet length is not available in {} programs",
+                        i,
+                        self.section()
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct Section {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+// sections of an ELF64 object
+pub fn sections(elf: &[u8]) -> Result<Vec<Section>, String> {
+    if elf.len() < 64 || &elf[..4] != b"\x7fELF" || elf[4] != 2 {
+        return Err("not an ELF64 object".to_owned());
+    }
+    let big_endian = elf[5] == 2;
+    let read = |off: usize, n: usize| -> Result<u64, String> {
+        let bytes = match elf.get(off..off + n) {
+            Some(bytes) => bytes,
+            None => return Err("truncated ELF object".to_owned()),
+        };
+        Ok(if big_endian {
+            bytes.iter().fold(0, |v, &b| (v << 8) | b as u64)
+        } else {
+            bytes.iter().rev().fold(0, |v, &b| (v << 8) | b as u64)
+        })
+    };
+
+    let shoff = read(0x28, 8)? as usize;
+    let shentsize = read(0x3a, 2)? as usize;
+    let shnum = read(0x3c, 2)? as usize;
+    let shstrndx = read(0x3e, 2)? as usize;
+    let header = |i: usize| -> Result<(u64, u32, usize, usize), String> {
+        let h = shoff + i * shentsize;
+        // name, type, offset, size
+        Ok((
+            read(h, 4)?,
+            read(h + 4, 4)? as u32,
+            read(h + 0x18, 8)? as usize,
+            read(h + 0x20, 8)? as usize,
+        ))
+    };
+    let slice = |off: usize, size: usize| match elf.get(off..off + size) {
+        Some(s) => Ok(s),
+        None => Err("truncated ELF object".to_owned()),
+    };
+
+    let (_, _, stroff, strsize) = header(shstrndx)?;
+    let strtab = slice(stroff, strsize)?;
+    let mut result = vec![];
+    for i in 0..shnum {
+        let (name, ty, off, size) = header(i)?;
+        let name = strtab.get(name as usize..).unwrap_or(&[]);
+        let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+        // SHT_NOBITS has no data in the file
+        let data = if ty == 8 { vec![] } else { slice(off, size)?.to_vec() };
+        result.push(Section {
+            name: String::from_utf8_lossy(name).into_owned(),
+            data,
+        });
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn len() {
+        let insns = [
+            BpfInsn::new(BPF_LD | BPF_W | BPF_LEN, 0, 0, 0),
+            BpfInsn::new(BPF_RET_A, 0, 0, 0),
+        ];
+        assert!(EbpfTarget::new(ProgramType::Xdp).check(&insns).is_err());
+        assert!(EbpfTarget::new(ProgramType::Classifier).check(&insns).is_ok());
+        assert!(EbpfTarget::new(ProgramType::SocketFilter).check(&insns).is_ok());
+    }
+
+    #[test]
+    fn redirect() {
+        let insns = [BpfInsn::new(BPF_RET_K, 0, 0, 1)];
+        let map = RedirectMap {
+            name: "tx_port".to_owned(),
+            map_type: MapType::Devmap,
+            max_entries: 4,
+            key: 1,
+        };
+        let mut target = EbpfTarget::new(ProgramType::Xdp);
+        target.accept = Action::Redirect(map.clone());
+        // libbpf does not load the map
+        assert!(target.check(&insns).is_err());
+        target.iproute2_maps = true;
+        assert!(target.check(&insns).is_ok());
+        assert_eq!(target.action_code(&target.accept), None);
+        assert_eq!(target.action_code(&target.reject), Some(1));
+
+        target.reject = Action::Redirect(RedirectMap {
+            map_type: MapType::Xskmap,
+            ..map.clone()
+        });
+        assert!(target.check(&insns).is_err());
+        target.reject = Action::Redirect(RedirectMap { key: 4, ..map.clone() });
+        assert!(target.check(&insns).is_err());
+
+        target.program_type = ProgramType::Classifier;
+        target.reject = Action::Drop;
+        assert!(target.check(&insns).is_err());
+
+        assert_eq!(linktype_offset(1), Ok(0));
+        assert_eq!(linktype_offset(101), Ok(14));
+        assert!(linktype_offset(127).is_err());
+    }
+}
@@ -0,0 +1,346 @@
+// eBPF interpreter for tests
+//
+// It runs the generated eBPF programs without a kernel.
+// Memory consists of regions at fixed addresses below 4GB, so that the context can hold
+// data/data_end as u32 fields like the kernel's. Accessing outside of the regions panics,
+// which means that a bounds check is missing.
+// The packet loads (ld_abs, ld_ind) and byte swaps are cross-checked with rbpf.
+
+pub const CTX: u64 = 0x1000;
+pub const PACKET: u64 = 0x10_0000;
+const STACK: u64 = 0x20_0000;
+const STACK_SIZE: usize = 512;
+const MAX_STEPS: usize = 1_000_000;
+
+struct Insn {
+    op: u8,
+    dst: usize,
+    src: usize,
+    off: i16,
+    imm: i32,
+}
+
+pub struct Vm {
+    insns: Vec<Insn>,
+    big_endian: bool,
+}
+
+struct Memory {
+    regions: Vec<(u64, Vec<u8>)>,
+    big_endian: bool,
+}
+
+impl Memory {
+    fn region(&mut self, addr: u64, size: usize) -> &mut [u8] {
+        for &mut (base, ref mut bytes) in &mut self.regions {
+            if base <= addr && addr + size as u64 <= base + bytes.len() as u64 {
+                let start = (addr - base) as usize;
+                return &mut bytes[start..start + size];
+            }
+        }
+        panic!("out of bounds access: {:#x} ({} bytes)", addr, size);
+    }
+
+    fn load(&mut self, addr: u64, size: usize) -> u64 {
+        let big_endian = self.big_endian;
+        let bytes = self.region(addr, size);
+        if big_endian {
+            bytes.iter().fold(0, |v, &b| (v << 8) | b as u64)
+        } else {
+            bytes.iter().rev().fold(0, |v, &b| (v << 8) | b as u64)
+        }
+    }
+
+    fn store(&mut self, addr: u64, size: usize, v: u64) {
+        let big_endian = self.big_endian;
+        let bytes = self.region(addr, size);
+        for (i, b) in bytes.iter_mut().enumerate() {
+            let shift = if big_endian { (size - 1 - i) * 8 } else { i * 8 };
+            *b = (v >> shift) as u8;
+        }
+    }
+}
+
+fn swap(v: u64, bits: i32) -> u64 {
+    match bits {
+        16 => (v as u16).swap_bytes() as u64,
+        32 => (v as u32).swap_bytes() as u64,
+        _ => v.swap_bytes(),
+    }
+}
+
+fn truncate(v: u64, bits: i32) -> u64 {
+    match bits {
+        16 => v & 0xffff,
+        32 => v & 0xffff_ffff,
+        _ => v,
+    }
+}
+
+impl Vm {
+    pub fn new(code: &[u8], big_endian: bool) -> Self {
+        let insns = code.chunks(8)
+            .map(|b| {
+                let (dst, src, off, imm) = if big_endian {
+                    (
+                        b[1] >> 4,
+                        b[1] & 0xf,
+                        (b[2] as u16) << 8 | b[3] as u16,
+                        (b[4] as u32) << 24 | (b[5] as u32) << 16 | (b[6] as u32) << 8 | b[7] as u32,
+                    )
+                } else {
+                    (
+                        b[1] & 0xf,
+                        b[1] >> 4,
+                        (b[3] as u16) << 8 | b[2] as u16,
+                        (b[7] as u32) << 24 | (b[6] as u32) << 16 | (b[5] as u32) << 8 | b[4] as u32,
+                    )
+                };
+                Insn {
+                    op: b[0],
+                    dst: dst as usize,
+                    src: src as usize,
+                    off: off as i16,
+                    imm: imm as i32,
+                }
+            })
+            .collect();
+        Vm { insns, big_endian }
+    }
+
+    // run the program with r1 = ctx. `helper` is called with the helper ID and r1-r5
+    pub fn run<F>(&self, ctx: &[u8], packet: &[u8], mut helper: F) -> u64
+    where
+        F: FnMut(i32, [u64; 5]) -> u64,
+    {
+        let mut mem = Memory {
+            regions: vec![
+                (CTX, ctx.to_vec()),
+                (PACKET, packet.to_vec()),
+                (STACK, vec![0; STACK_SIZE]),
+            ],
+            big_endian: self.big_endian,
+        };
+        let mut reg = [0u64; 11];
+        reg[1] = CTX;
+        reg[10] = STACK + STACK_SIZE as u64;
+
+        let mut pc = 0;
+        for _ in 0..MAX_STEPS {
+            let insn = &self.insns[pc];
+            pc += 1;
+            let size = match insn.op & 0x18 {
+                0x00 => 4,
+                0x08 => 2,
+                0x10 => 1,
+                _ => 8,
+            };
+            let addr = |base: u64| base.wrapping_add(insn.off as i64 as u64);
+            match insn.op & 7 {
+                // lddw
+                0 if insn.op == 0x18 => {
+                    let hi = self.insns[pc].imm as u32 as u64;
+                    pc += 1;
+                    reg[insn.dst] = insn.imm as u32 as u64 | hi << 32;
+                }
+                // ld_abs, ld_ind: r0 = packet[(src +) imm] in network byte order, and
+                // the program returns 0 if it is out of bounds
+                0 => {
+                    let off = match insn.op & 0xe0 {
+                        0x20 => insn.imm as u32,
+                        0x40 => (reg[insn.src] as u32).wrapping_add(insn.imm as u32),
+                        _ => panic!("unknown instruction {:#x}", insn.op),
+                    } as usize;
+                    match packet.get(off..off + size) {
+                        Some(bytes) => reg[0] = bytes.iter().fold(0, |v, &b| (v << 8) | b as u64),
+                        None => return 0,
+                    }
+                }
+                // ldx
+                1 => reg[insn.dst] = mem.load(addr(reg[insn.src]), size),
+                // st
+                2 => mem.store(addr(reg[insn.dst]), size, insn.imm as i64 as u64),
+                // stx
+                3 => mem.store(addr(reg[insn.dst]), size, reg[insn.src]),
+                // alu, alu64
+                class @ 4 | class @ 7 => {
+                    let alu32 = class == 4;
+                    let src = if insn.op & 8 != 0 {
+                        reg[insn.src]
+                    } else {
+                        insn.imm as i64 as u64
+                    };
+                    let (dst, src) = if alu32 {
+                        (reg[insn.dst] & 0xffff_ffff, src & 0xffff_ffff)
+                    } else {
+                        (reg[insn.dst], src)
+                    };
+                    let bits = if alu32 { 32 } else { 64 };
+                    let v = match insn.op & 0xf0 {
+                        0x00 => dst.wrapping_add(src),
+                        0x10 => dst.wrapping_sub(src),
+                        0x20 => dst.wrapping_mul(src),
+                        0x30 => if src == 0 { 0 } else { dst / src },
+                        0x40 => dst | src,
+                        0x50 => dst & src,
+                        0x60 => dst << (src & (bits - 1)),
+                        0x70 => dst >> (src & (bits - 1)),
+                        0x80 => dst.wrapping_neg(),
+                        0x90 => if src == 0 { dst } else { dst % src },
+                        0xa0 => dst ^ src,
+                        0xb0 => src,
+                        0xc0 => if alu32 {
+                            ((dst as i32) >> (src & 31)) as u32 as u64
+                        } else {
+                            ((dst as i64) >> (src & 63)) as u64
+                        },
+                        // to be (or le) of the low imm bits
+                        0xd0 => {
+                            let to_big_endian = insn.op & 8 != 0;
+                            if to_big_endian != self.big_endian {
+                                swap(reg[insn.dst], insn.imm)
+                            } else {
+                                truncate(reg[insn.dst], insn.imm)
+                            }
+                        }
+                        op => panic!("unknown alu operation {:#x}", op),
+                    };
+                    reg[insn.dst] = if alu32 && insn.op & 0xf0 != 0xd0 {
+                        v & 0xffff_ffff
+                    } else {
+                        v
+                    };
+                }
+                // jmp, jmp32
+                class @ 5 | class @ 6 => {
+                    let src = if insn.op & 8 != 0 {
+                        reg[insn.src]
+                    } else {
+                        insn.imm as i64 as u64
+                    };
+                    let (dst, src) = if class == 6 {
+                        (
+                            reg[insn.dst] as u32 as i32 as i64 as u64,
+                            src as u32 as i32 as i64 as u64,
+                        )
+                    } else {
+                        (reg[insn.dst], src)
+                    };
+                    let (udst, usrc) = if class == 6 {
+                        (dst & 0xffff_ffff, src & 0xffff_ffff)
+                    } else {
+                        (dst, src)
+                    };
+                    let taken = match insn.op & 0xf0 {
+                        0x00 => true,
+                        0x10 => udst == usrc,
+                        0x20 => udst > usrc,
+                        0x30 => udst >= usrc,
+                        0x40 => udst & usrc != 0,
+                        0x50 => udst != usrc,
+                        0x60 => (dst as i64) > (src as i64),
+                        0x70 => (dst as i64) >= (src as i64),
+                        0x80 => {
+                            reg[0] = helper(insn.imm, [reg[1], reg[2], reg[3], reg[4], reg[5]]);
+                            false
+                        }
+                        0x90 => return reg[0],
+                        0xa0 => udst < usrc,
+                        0xb0 => udst <= usrc,
+                        0xc0 => (dst as i64) < (src as i64),
+                        0xd0 => (dst as i64) <= (src as i64),
+                        op => panic!("unknown jump operation {:#x}", op),
+                    };
+                    if taken {
+                        pc = (pc as i64 + insn.off as i64) as usize;
+                    }
+                }
+                _ => unreachable!(),
+            }
+        }
+        panic!("the program does not terminate");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rbpf::EbpfVmRaw;
+
+    // little endian encoding
+    fn insn(op: u8, dst: u8, src: u8, imm: i32) -> Vec<u8> {
+        let mut b = vec![op, src << 4 | dst, 0, 0];
+        b.extend_from_slice(&[imm as u8, (imm >> 8) as u8, (imm >> 16) as u8, (imm >> 24) as u8]);
+        b
+    }
+
+    fn run_both(code: &[u8], packet: &[u8]) -> (u64, u64) {
+        let v = Vm::new(code, false).run(&[], packet, |_, _| panic!());
+        let mut mem = packet.to_vec();
+        let r = EbpfVmRaw::new(Some(code))
+            .unwrap()
+            .execute_program(&mut mem)
+            .unwrap();
+        (v, r)
+    }
+
+    #[test]
+    fn vm() {
+        // r0 = *(u16 *)(r1 + 0); r0 = be16 r0; r0 += 1; exit
+        let code = [
+            0x69, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0xdc, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00,
+            0x07, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+            0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        let vm = Vm::new(&code, false);
+        assert_eq!(vm.run(&[0x12, 0x34], &[], |_, _| 0), 0x1235);
+    }
+
+    #[test]
+    fn rbpf() {
+        let packet: Vec<u8> = (0..32u8).map(|i| 0x81u8.wrapping_mul(i + 1)).collect();
+        let exit = insn(0x95, 0, 0, 0);
+
+        // rbpf reads ld_abs and ld_ind in host byte order, while the kernel (and Vm) reads
+        // them in network byte order. rbpf also checks 8 bytes for every size
+        let network_order = |r: u64, size| match size {
+            1 => r,
+            2 => u16::from_be(r as u16) as u64,
+            _ => u32::from_be(r as u32) as u64,
+        };
+        for &(op, size) in &[(0x30u8, 1), (0x28, 2), (0x20, 4)] {
+            for k in 0..25 {
+                let code = [insn(op, 0, 0, k), exit.clone()].concat();
+                let (v, r) = run_both(&code, &packet);
+                assert_eq!(v, network_order(r, size), "ld_abs {:#x} {}", op, k);
+
+                // r2 = 3; ld_ind [r2 + k]
+                if k > 21 {
+                    continue;
+                }
+                let code = [insn(0xb7, 2, 0, 3), insn(op + 0x20, 0, 2, k), exit.clone()].concat();
+                let (v, r) = run_both(&code, &packet);
+                assert_eq!(v, network_order(r, size), "ld_ind {:#x} {}", op, k);
+            }
+        }
+
+        // r0 = 0x12345678; r0 = be16/be32/le16/le32 r0
+        for &(op, bits) in &[(0xdcu8, 16), (0xdc, 32), (0xd4, 16), (0xd4, 32)] {
+            let code = [insn(0xb7, 0, 0, 0x1234_5678), insn(op, 0, 0, bits), exit.clone()].concat();
+            let (v, r) = run_both(&code, &packet);
+            assert_eq!(v, r, "{:#x} {}", op, bits);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn out_of_bounds() {
+        // r0 = *(u32 *)(r1 + 0); exit
+        let code = [
+            0x61, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        Vm::new(&code, false).run(&[0; 2], &[], |_, _| 0);
+    }
+}
@@ -0,0 +1,487 @@
+// JIT-compiled filter (or an interpreted one)
+
+use cbpf::opcode::BpfInsn;
+#[cfg(feature = "cranelift")]
+use cranelift::{self, CraneliftFilter};
+use disasm::disasm;
+use interp::Interpreter;
+#[cfg(feature = "llvm")]
+use orc::{self, OrcFilter};
+use pgo::BranchProfile;
+use std::str::FromStr;
+use trace::{self, TraceStep};
+use Options;
+#[cfg(feature = "llvm")]
+use Converter;
+
+// code generator of `CompiledFilter`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    // requires the "llvm" feature (enabled by default)
+    Llvm,
+    // compiles faster than LLVM but optimizes less (requires the "cranelift" feature).
+    // debug info, profiling and tracing are not available
+    Cranelift,
+    // runs the program without generating code (see interp.rs).
+    // debug info and profiling are not available
+    Interpreter,
+}
+
+// LLVM if it is available
+impl Default for Backend {
+    #[cfg(feature = "llvm")]
+    fn default() -> Self {
+        Backend::Llvm
+    }
+
+    #[cfg(not(feature = "llvm"))]
+    fn default() -> Self {
+        Backend::Interpreter
+    }
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "llvm" => Ok(Backend::Llvm),
+            "cranelift" => Ok(Backend::Cranelift),
+            "interp" => Ok(Backend::Interpreter),
+            _ => Err(format!("unknown backend: {}", s)),
+        }
+    }
+}
+
+enum Engine {
+    // ORC JIT (see `compile_llvm()`)
+    #[cfg(feature = "llvm")]
+    Llvm(OrcFilter),
+    #[cfg(feature = "llvm")]
+    Mcjit(Converter),
+    #[cfg(feature = "cranelift")]
+    Cranelift(CraneliftFilter),
+    Interpreter(Interpreter),
+}
+
+pub struct CompiledFilter {
+    engine: Engine,
+    insns: Vec<BpfInsn>,
+    // built with `Options::trace`
+    traced: bool,
+}
+
+// the filter can be shared between threads: the compiled code does not modify anything but the
+// counters, the ORC stack of `orc::shared_session()` is used only with its lock (LLJIT of
+// LLVM 15+ is thread safe), and the converter of `OrcFilter` is behind a mutex. the MCJIT and
+// Cranelift engines are only read after the compilation
+unsafe impl Send for CompiledFilter {}
+unsafe impl Sync for CompiledFilter {}
+
+// whether the options need LLVM: debug info, profiling, the cache, eBPF and pass pipelines
+fn llvm_only(options: &Options) -> bool {
+    options.debug_info || options.perf_map || options.gdb_jit || options.profile
+        || options.branch_profile.is_some() || options.cache_dir.is_some()
+        || options.ebpf.is_some() || options.passes.is_some()
+}
+
+// filters are compiled by the ORC JIT session shared by the process. MCJIT is still used for
+// perf_map, gdb_jit and cache_dir, which rely on its memory manager, JIT event listener and
+// object cache
+#[cfg(feature = "llvm")]
+fn compile_llvm(insns: &[BpfInsn], options: Options, optimization: bool) -> Result<Engine, String> {
+    if options.perf_map || options.gdb_jit || options.cache_dir.is_some() {
+        let mut converter = Converter::with_options(options);
+        converter.build(insns, optimization)?;
+        converter.jit_compile()?;
+        return Ok(Engine::Mcjit(converter));
+    }
+    let session = orc::shared_session()?;
+    Ok(Engine::Llvm(session.compile(insns, options, optimization, false)?))
+}
+
+#[cfg(not(feature = "llvm"))]
+fn compile_llvm(_: &[BpfInsn], _: Options, _: bool) -> Result<Engine, String> {
+    Err("the LLVM backend requires the \"llvm\" feature".to_owned())
+}
+
+#[cfg(feature = "cranelift")]
+fn compile_cranelift(
+    insns: &[BpfInsn],
+    options: &Options,
+    optimization: bool,
+) -> Result<Engine, String> {
+    if llvm_only(options) || options.trace {
+        return Err("the Cranelift backend only supports the name option".to_owned());
+    }
+    Ok(Engine::Cranelift(cranelift::compile(insns, optimization)?))
+}
+
+#[cfg(not(feature = "cranelift"))]
+fn compile_cranelift(_: &[BpfInsn], _: &Options, _: bool) -> Result<Engine, String> {
+    Err("the Cranelift backend requires the \"cranelift\" feature".to_owned())
+}
+
+impl CompiledFilter {
+    // `optimization` is ignored by the interpreter
+    pub fn new(insns: &[BpfInsn], options: Options, optimization: bool) -> Result<Self, String> {
+        let traced = options.trace;
+        let engine = match options.backend {
+            Backend::Llvm => compile_llvm(insns, options, optimization)?,
+            Backend::Cranelift => compile_cranelift(insns, &options, optimization)?,
+            Backend::Interpreter => {
+                if llvm_only(&options) {
+                    return Err("the interpreter only supports the name and trace options"
+                        .to_owned());
+                }
+                Engine::Interpreter(Interpreter::new(insns, options.trace)?)
+            }
+        };
+        Ok(CompiledFilter {
+            engine,
+            insns: insns.to_vec(),
+            traced,
+        })
+    }
+
+    pub fn backend(&self) -> Backend {
+        match self.engine {
+            #[cfg(feature = "llvm")]
+            Engine::Llvm(_) | Engine::Mcjit(_) => Backend::Llvm,
+            #[cfg(feature = "cranelift")]
+            Engine::Cranelift(_) => Backend::Cranelift,
+            Engine::Interpreter(_) => Backend::Interpreter,
+        }
+    }
+
+    pub fn run(&self, data: &[u8]) -> u32 {
+        match self.engine {
+            // the code is generated in `new()`, so it does not fail
+            #[cfg(feature = "llvm")]
+            Engine::Llvm(ref filter) => filter.run(data).unwrap_or(0),
+            #[cfg(feature = "llvm")]
+            Engine::Mcjit(ref converter) => unsafe { converter.run_jit_func(data) as u32 },
+            #[cfg(feature = "cranelift")]
+            Engine::Cranelift(ref filter) => filter.run(data) as u32,
+            Engine::Interpreter(ref interpreter) => interpreter.run(data),
+        }
+    }
+
+    // steps to the verdict, or None without `Options::trace`
+    pub fn run_traced(&self, data: &[u8]) -> Option<Vec<TraceStep>> {
+        if !self.traced {
+            return None;
+        }
+        trace::start();
+        self.run(data);
+        Some(trace::finish())
+    }
+
+    pub fn insns(&self) -> &[BpfInsn] {
+        &self.insns
+    }
+
+    // execution count of each instruction (only with `Options::profile`)
+    pub fn counters(&self) -> Option<Vec<u64>> {
+        match self.engine {
+            #[cfg(feature = "llvm")]
+            Engine::Llvm(ref filter) => filter.counters(),
+            #[cfg(feature = "llvm")]
+            Engine::Mcjit(ref converter) => converter.counters(),
+            _ => None,
+        }
+    }
+
+    // how many times each conditional jump is taken (only with `Options::profile`)
+    pub fn branch_profile(&self) -> Option<BranchProfile> {
+        let counters = match self.engine {
+            #[cfg(feature = "llvm")]
+            Engine::Llvm(ref filter) => (filter.counters(), filter.taken_counters()),
+            #[cfg(feature = "llvm")]
+            Engine::Mcjit(ref converter) => (converter.counters(), converter.taken_counters()),
+            _ => (None, None),
+        };
+        match counters {
+            (Some(executed), Some(taken)) => Some(BranchProfile::from_counters(
+                &self.insns,
+                &executed,
+                &taken,
+            )),
+            _ => None,
+        }
+    }
+
+    pub fn reset_counters(&self) {
+        match self.engine {
+            #[cfg(feature = "llvm")]
+            Engine::Llvm(ref filter) => filter.reset_counters(),
+            #[cfg(feature = "llvm")]
+            Engine::Mcjit(ref converter) => converter.reset_counters(),
+            _ => {}
+        }
+    }
+
+    // disassembly annotated with the execution counts
+    pub fn annotate(&self) -> String {
+        let counters = self.counters();
+        self.insns
+            .iter()
+            .enumerate()
+            .map(|(i, insn)| {
+                let count = match counters {
+                    Some(ref c) => c[i].to_string(),
+                    None => "-".to_owned(),
+                };
+                format!("{:>12}  {:4}: {}\n", count, i, disasm(insn))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cbpf::opcode::*;
+
+    #[test]
+    #[cfg(feature = "llvm")]
+    fn profile() {
+        // ldb [0]; jeq #1, L1, L2; L1: ret #1; L2: ret #0
+        let insns = [
+            BpfInsn::new(BPF_LD_B_ABS, 0, 0, 0),
+            BpfInsn::new(BPF_JEQ_K, 0, 1, 1),
+            BpfInsn::new(BPF_RET_K, 0, 0, 1),
+            BpfInsn::new(BPF_RET_K, 0, 0, 0),
+        ];
+        let options = Options {
+            profile: true,
+            ..Default::default()
+        };
+
+        for &optimization in &[false, true] {
+            let filter = CompiledFilter::new(&insns, options.clone(), optimization).unwrap();
+            for &b in &[1u8, 1, 2] {
+                filter.run(&[b]);
+            }
+            filter.run(&[]);
+            assert_eq!(filter.counters(), Some(vec![4, 3, 2, 1]));
+            assert_eq!(
+                filter.branch_profile().unwrap().counts,
+                vec![(0, 0), (2, 1), (0, 0), (0, 0)]
+            );
+            assert_eq!(
+                filter.annotate().lines().nth(2).unwrap(),
+                "           2     2: ret 1"
+            );
+
+            filter.reset_counters();
+            assert_eq!(filter.counters(), Some(vec![0, 0, 0, 0]));
+        }
+    }
+
+    // M[2] is written only on one path, and the JIT reads 0 on the other like the interpreter
+    #[test]
+    #[cfg(feature = "llvm")]
+    fn unwritten_mem() {
+        // ldb [0]; jeq #1, L1, L2; L1: st M[2]; L2: ld M[2]; ret a
+        let insns = [
+            BpfInsn::new(BPF_LD_B_ABS, 0, 0, 0),
+            BpfInsn::new(BPF_JEQ_K, 0, 1, 1),
+            BpfInsn::new(BPF_ST, 0, 0, 2),
+            BpfInsn::new(BPF_LD_MEM, 0, 0, 2),
+            BpfInsn::new(BPF_RET_A, 0, 0, 0),
+        ];
+        let interpreter = Options {
+            backend: Backend::Interpreter,
+            ..Default::default()
+        };
+
+        for &optimization in &[false, true] {
+            let jit = CompiledFilter::new(&insns, Options::default(), optimization).unwrap();
+            let interp = CompiledFilter::new(&insns, interpreter.clone(), optimization).unwrap();
+            for &(data, expected) in &[(&[1u8][..], 1), (&[2u8][..], 0), (&[][..], 0)] {
+                assert_eq!(interp.run(data), expected);
+                assert_eq!(jit.run(data), expected);
+            }
+        }
+    }
+
+    // the loads of a short packet fail at the load itself, after the jump is counted
+    #[test]
+    #[cfg(feature = "llvm")]
+    fn profile_short_packet() {
+        // ldb [0]; jeq #1, L1, L2; L1: ldb [5]; ret a; L2: ldb [6]; ret a
+        let insns = [
+            BpfInsn::new(BPF_LD_B_ABS, 0, 0, 0),
+            BpfInsn::new(BPF_JEQ_K, 0, 2, 1),
+            BpfInsn::new(BPF_LD_B_ABS, 0, 0, 5),
+            BpfInsn::new(BPF_RET_A, 0, 0, 0),
+            BpfInsn::new(BPF_LD_B_ABS, 0, 0, 6),
+            BpfInsn::new(BPF_RET_A, 0, 0, 0),
+        ];
+        let options = Options {
+            profile: true,
+            ..Default::default()
+        };
+
+        for &optimization in &[false, true] {
+            let filter = CompiledFilter::new(&insns, options.clone(), optimization).unwrap();
+            assert_eq!(filter.run(&[1, 0]), 0);
+            assert_eq!(filter.run(&[2, 0, 0, 0, 0, 0]), 0);
+            assert_eq!(filter.counters(), Some(vec![2, 2, 1, 0, 1, 0]));
+            assert_eq!(filter.branch_profile().unwrap().counts[1], (1, 1));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "llvm")]
+    fn profile_jeq_chain() {
+        // ldb [0]; jeq #1, L1; jeq #2, L1; jeq #3, L1; ret #0; L1: ret #1
+        let insns = [
+            BpfInsn::new(BPF_LD_B_ABS, 0, 0, 0),
+            BpfInsn::new(BPF_JEQ_K, 3, 0, 1),
+            BpfInsn::new(BPF_JEQ_K, 2, 0, 2),
+            BpfInsn::new(BPF_JEQ_K, 1, 0, 3),
+            BpfInsn::new(BPF_RET_K, 0, 0, 0),
+            BpfInsn::new(BPF_RET_K, 0, 0, 1),
+        ];
+        let options = Options {
+            profile: true,
+            ..Default::default()
+        };
+
+        // every member of the chain is counted
+        for &optimization in &[false, true] {
+            let filter = CompiledFilter::new(&insns, options.clone(), optimization).unwrap();
+            for &b in &[1u8, 2, 3, 4] {
+                filter.run(&[b]);
+            }
+            assert_eq!(filter.counters(), Some(vec![4, 4, 3, 2, 1, 3]));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "llvm")]
+    fn trace() {
+        // ldh [12]; jeq #0x800, L1, L2; L1: ldx msh [14]; ldb [x + 23]; ret a; L2: ret #0
+        let insns = [
+            BpfInsn::new(BPF_LD_H_ABS, 0, 0, 12),
+            BpfInsn::new(BPF_JEQ_K, 0, 3, 0x0800),
+            BpfInsn::new(BPF_LDX_B_MSH, 0, 0, 14),
+            BpfInsn::new(BPF_LD_B_IND, 0, 0, 3),
+            BpfInsn::new(BPF_RET_A, 0, 0, 0),
+            BpfInsn::new(BPF_RET_K, 0, 0, 0),
+        ];
+        let options = Options {
+            trace: true,
+            ..Default::default()
+        };
+        let mut data = vec![0u8; 40];
+        data[12] = 0x08;
+        data[14] = 0x45;
+        data[23] = 6;
+
+        for &optimization in &[false, true] {
+            let filter = CompiledFilter::new(&insns, options.clone(), optimization).unwrap();
+            for len in &[40, 30, 14, 10] {
+                let data = &data[..*len];
+                let (steps, result) = trace::interpret(&insns, data);
+                assert_eq!(filter.run_traced(data), Some(steps));
+                // nothing is recorded outside of run_traced()
+                filter.run(data);
+                trace::start();
+                assert_eq!(trace::finish(), vec![]);
+                assert_eq!(Some(filter.run(data)), result);
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "llvm")]
+    fn trace_jeq_chain() {
+        // ldb [0]; jeq #1, L1; jeq #2, L1; jeq #3, L1; ret #0; L1: ret #1
+        let insns = [
+            BpfInsn::new(BPF_LD_B_ABS, 0, 0, 0),
+            BpfInsn::new(BPF_JEQ_K, 3, 0, 1),
+            BpfInsn::new(BPF_JEQ_K, 2, 0, 2),
+            BpfInsn::new(BPF_JEQ_K, 1, 0, 3),
+            BpfInsn::new(BPF_RET_K, 0, 0, 0),
+            BpfInsn::new(BPF_RET_K, 0, 0, 1),
+        ];
+        let options = Options {
+            trace: true,
+            ..Default::default()
+        };
+
+        // every member of the chain is recorded
+        for &optimization in &[false, true] {
+            let filter = CompiledFilter::new(&insns, options.clone(), optimization).unwrap();
+            for &b in &[1u8, 2, 3, 4] {
+                assert_eq!(filter.run_traced(&[b]), Some(trace::interpret(&insns, &[b]).0));
+            }
+        }
+    }
+
+    #[test]
+    fn backends() {
+        // ldh [12]; jeq #0x800, L1, L2; L1: ldx msh [14]; ldb [x + 23]; ret a; L2: ret #0
+        let insns = [
+            BpfInsn::new(BPF_LD_H_ABS, 0, 0, 12),
+            BpfInsn::new(BPF_JEQ_K, 0, 3, 0x0800),
+            BpfInsn::new(BPF_LDX_B_MSH, 0, 0, 14),
+            BpfInsn::new(BPF_LD_B_IND, 0, 0, 23),
+            BpfInsn::new(BPF_RET_A, 0, 0, 0),
+            BpfInsn::new(BPF_RET_K, 0, 0, 0),
+        ];
+        let mut data = vec![0u8; 40];
+        data[12] = 0x08;
+        data[14] = 0x45;
+        data[23] = 6;
+
+        let backends = [
+            (Backend::Llvm, cfg!(feature = "llvm")),
+            (Backend::Cranelift, cfg!(feature = "cranelift")),
+            (Backend::Interpreter, true),
+        ];
+        for &(backend, available) in &backends {
+            let options = Options {
+                backend,
+                ..Default::default()
+            };
+            let filter = CompiledFilter::new(&insns, options.clone(), true);
+            if !available {
+                assert!(filter.is_err());
+                continue;
+            }
+
+            let filter = filter.unwrap();
+            assert_eq!(filter.backend(), backend);
+            for len in &[40, 30, 14, 10] {
+                let data = &data[..*len];
+                assert_eq!(Some(filter.run(data)), trace::interpret(&insns, data).1);
+            }
+            if backend != Backend::Llvm {
+                assert_eq!(filter.counters(), None);
+                let profile = Options {
+                    profile: true,
+                    ..options
+                };
+                assert!(CompiledFilter::new(&insns, profile, true).is_err());
+            }
+        }
+
+        // the interpreter supports tracing
+        let options = Options {
+            backend: Backend::Interpreter,
+            trace: true,
+            ..Default::default()
+        };
+        let filter = CompiledFilter::new(&insns, options, true).unwrap();
+        assert_eq!(filter.run_traced(&data), Some(trace::interpret(&insns, &data).0));
+        let filter = CompiledFilter::new(&insns, Default::default(), true).unwrap();
+        assert_eq!(filter.run_traced(&data), None);
+
+        assert_eq!("interp".parse(), Ok(Backend::Interpreter));
+        assert!("gcc".parse::<Backend>().is_err());
+    }
+}
@@ -0,0 +1,335 @@
+// in-process cache of compiled filters
+//
+// Identical programs are compiled only once and the compiled filter is shared.
+// Programs are compared after `normalize()`, so that the fields an instruction does not
+// use do not make a difference. When the number of filters exceeds the limit, the least
+// recently used one is removed from the cache (it is freed when the last user drops it).
+// Programs are compiled without the lock of the cache, and the threads which request a
+// program being compiled wait for the result.
+
+use cbpf::opcode::*;
+use filter::CompiledFilter;
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use Options;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    // total time spent in compilation
+    pub compile_time: Duration,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Key {
+    insns: Vec<(u16, u8, u8, u32)>,
+    options: String,
+    optimization: bool,
+}
+
+// filled by the thread which compiles the filter. it holds the lock until then
+type Slot = Arc<Mutex<Option<Result<Arc<CompiledFilter>, String>>>>;
+
+struct Entry {
+    slot: Slot,
+    last_used: u64,
+}
+
+struct Inner {
+    entries: HashMap<Key, Entry>,
+    tick: u64,
+    stats: CacheStats,
+}
+
+pub struct FilterCache {
+    limit: usize,
+    inner: Mutex<Inner>,
+}
+
+// clear fields which are not used by the instruction
+pub fn normalize(insns: &[BpfInsn]) -> Vec<BpfInsn> {
+    insns
+        .iter()
+        .map(|insn| {
+            let code = insn.code;
+            let uses_k = match bpf_class(code) {
+                BPF_LD | BPF_LDX => bpf_mode(code) != BPF_LEN,
+                BPF_ST | BPF_STX => true,
+                BPF_ALU => bpf_op(code) != BPF_NEG && bpf_src(code) == BPF_K,
+                BPF_JMP => bpf_op(code) == BPF_JA || bpf_src(code) == BPF_K,
+                BPF_RET => bpf_rval(code) == BPF_K,
+                _ => false,
+            };
+            let uses_jt_jf = bpf_class(code) == BPF_JMP && bpf_op(code) != BPF_JA;
+            BpfInsn::new(
+                code,
+                if uses_jt_jf { insn.jt } else { 0 },
+                if uses_jt_jf { insn.jf } else { 0 },
+                if uses_k { insn.k } else { 0 },
+            )
+        })
+        .collect()
+}
+
+// the options which change the compiled code. the name is only used for perf and GDB,
+// and the cache directory only changes where the object is stored
+fn options_key(options: &Options) -> String {
+    let name = if options.perf_map || options.gdb_jit {
+        options.name.clone()
+    } else {
+        String::new()
+    };
+    // `Options` may have a branch profile, which cannot be hashed as is
+    format!(
+        "{:?}",
+        Options {
+            name,
+            cache_dir: None,
+            ..options.clone()
+        }
+    )
+}
+
+impl FilterCache {
+    // keep at most `limit` filters
+    pub fn new(limit: usize) -> Self {
+        FilterCache {
+            limit,
+            inner: Mutex::new(Inner {
+                entries: HashMap::new(),
+                tick: 0,
+                stats: CacheStats::default(),
+            }),
+        }
+    }
+
+    // the compiled filter of the program, which is compiled if it is not in the cache.
+    // concurrent requests of the same program wait for one compilation
+    pub fn get(
+        &self,
+        insns: &[BpfInsn],
+        options: &Options,
+        optimization: bool,
+    ) -> Result<Arc<CompiledFilter>, String> {
+        let normalized = normalize(insns);
+        let key = Key {
+            insns: normalized
+                .iter()
+                .map(|i| (i.code, i.jt, i.jf, i.k))
+                .collect(),
+            options: options_key(options),
+            optimization,
+        };
+
+        let slot: Slot = Arc::new(Mutex::new(None));
+        let mut result = slot.lock().unwrap();
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        if let Some(entry) = inner.entries.get_mut(&key) {
+            entry.last_used = tick;
+            let shared = entry.slot.clone();
+            inner.stats.hits += 1;
+            drop(inner);
+            // the slot is filled before it is unlocked, unless the compiling thread died
+            let filter = shared.lock().unwrap_or_else(|e| e.into_inner()).clone();
+            return match filter {
+                Some(filter) => filter,
+                None => {
+                    // compiled again when it is requested next time
+                    let mut inner = self.inner.lock().unwrap();
+                    let aborted = match inner.entries.get(&key) {
+                        Some(entry) => Arc::ptr_eq(&entry.slot, &shared),
+                        None => false,
+                    };
+                    if aborted {
+                        inner.entries.remove(&key);
+                    }
+                    Err("the compilation of the program was aborted".to_owned())
+                }
+            };
+        }
+        inner.stats.misses += 1;
+        if self.limit > 0 {
+            while inner.entries.len() >= self.limit {
+                inner.evict();
+            }
+            inner.entries.insert(
+                key.clone(),
+                Entry {
+                    slot: slot.clone(),
+                    last_used: tick,
+                },
+            );
+        }
+        drop(inner);
+
+        let start = Instant::now();
+        // a panic must not leave the slot empty, or the waiting threads would never get
+        // the result
+        let filter = match panic::catch_unwind(AssertUnwindSafe(|| {
+            CompiledFilter::new(&normalized, options.clone(), optimization)
+        })) {
+            Ok(filter) => filter.map(Arc::new),
+            Err(_) => Err("the compilation of the program panicked".to_owned()),
+        };
+        let elapsed = start.elapsed();
+        *result = Some(filter.clone());
+        drop(result);
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.stats.compile_time += elapsed;
+        // the program is compiled again when it is requested next time
+        if filter.is_err() {
+            let failed = match inner.entries.get(&key) {
+                Some(entry) => Arc::ptr_eq(&entry.slot, &slot),
+                None => false,
+            };
+            if failed {
+                inner.entries.remove(&key);
+            }
+        }
+        filter
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        self.inner.lock().unwrap().entries.clear();
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.inner.lock().unwrap().stats.clone()
+    }
+}
+
+impl Inner {
+    fn evict(&mut self) {
+        let lru = self.entries
+            .iter()
+            .min_by_key(|&(_, e)| e.last_used)
+            .map(|(k, _)| k.clone());
+        if let Some(key) = lru {
+            self.entries.remove(&key);
+            self.stats.evictions += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalized() {
+        let insns = [
+            BpfInsn::new(BPF_LD | BPF_W | BPF_LEN, 1, 2, 3),
+            BpfInsn::new(BPF_JEQ_K, 0, 1, 4),
+            BpfInsn::new(BPF_RET_A, 0, 0, 5),
+            BpfInsn::new(BPF_RET_K, 0, 0, 0),
+        ];
+        let fields: Vec<_> = normalize(&insns)
+            .iter()
+            .map(|i| (i.code, i.jt, i.jf, i.k))
+            .collect();
+        assert_eq!(
+            fields,
+            vec![
+                (BPF_LD | BPF_W | BPF_LEN, 0, 0, 0),
+                (BPF_JEQ_K, 0, 1, 4),
+                (BPF_RET_A, 0, 0, 0),
+                (BPF_RET_K, 0, 0, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn lru() {
+        let ret = |k| [BpfInsn::new(BPF_RET_K, 0, 0, k)];
+        let options = Options::default();
+        let cache = FilterCache::new(2);
+
+        let f1 = cache.get(&ret(1), &options, true).unwrap();
+        // RET_K ignores jt
+        let f1_ = cache
+            .get(&[BpfInsn::new(BPF_RET_K, 1, 0, 1)], &options, true)
+            .unwrap();
+        assert!(Arc::ptr_eq(&f1, &f1_));
+        assert_eq!(f1.run(&[]), 1);
+
+        let f2 = cache.get(&ret(2), &options, true).unwrap();
+        assert!(!Arc::ptr_eq(&f1, &f2));
+        // use 1 so that 2 is evicted
+        cache.get(&ret(1), &options, true).unwrap();
+        cache.get(&ret(3), &options, true).unwrap();
+        assert_eq!(cache.len(), 2);
+        let f1_ = cache.get(&ret(1), &options, true).unwrap();
+        assert!(Arc::ptr_eq(&f1, &f1_));
+        let f2_ = cache.get(&ret(2), &options, true).unwrap();
+        assert!(!Arc::ptr_eq(&f2, &f2_));
+        assert_eq!(f2.run(&[]), 2);
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (3, 4, 2));
+    }
+
+    #[test]
+    fn concurrent() {
+        let cache = Arc::new(FilterCache::new(2));
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let cache = cache.clone();
+                std::thread::spawn(move || {
+                    // the name does not change the compiled code
+                    let options = Options {
+                        name: format!("filter{}", i),
+                        ..Default::default()
+                    };
+                    cache
+                        .get(&[BpfInsn::new(BPF_RET_K, 0, 0, 1)], &options, true)
+                        .unwrap()
+                })
+            })
+            .collect();
+        let filters: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        assert!(filters.iter().all(|f| Arc::ptr_eq(f, &filters[0])));
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (3, 1));
+
+        // failures are not cached
+        let invalid = [BpfInsn::new(BPF_LD_W_ABS, 0, 0, 0)];
+        assert!(cache.get(&invalid, &Options::default(), true).is_err());
+        assert_eq!(cache.len(), 1);
+    }
+    #[test]
+    fn aborted_compilation() {
+        let insns = [BpfInsn::new(BPF_RET_K, 0, 0, 1)];
+        let options = Options::default();
+        let cache = FilterCache::new(2);
+        cache.get(&insns, &options, true).unwrap();
+
+        // a slot whose compiling thread panicked while holding the lock
+        let slot: Slot = Arc::new(Mutex::new(None));
+        let poisoned = slot.clone();
+        let _ = std::thread::spawn(move || {
+            let _lock = poisoned.lock().unwrap();
+            panic!("compilation panics");
+        }).join();
+        for entry in cache.inner.lock().unwrap().entries.values_mut() {
+            entry.slot = slot.clone();
+        }
+        assert!(cache.get(&insns, &options, true).is_err());
+        assert!(cache.is_empty());
+        assert_eq!(cache.get(&insns, &options, true).unwrap().run(&[]), 1);
+    }
+}
@@ -0,0 +1,429 @@
+// backend-independent front end
+//
+// `decode()` validates a cBPF program and translates it into `Insn`s, which the backends
+// other than LLVM (C source, WebAssembly and Cranelift) compile and the interpreter runs.
+// All the backends, including the LLVM IR generated by `Converter`, follow the same semantics:
+// - A, X and M[] are u32 and start at 0
+// - loads read big endian values, and a load out of the packet makes the filter return 0
+//   (X + k of indirect loads does not wrap around)
+// - division and modulo by zero return 0
+// - shifting by 32 or more gives 0
+
+use cbpf::opcode::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reg {
+    A,
+    X,
+}
+
+// second operand of ALU operations and conditional jumps
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Src {
+    X,
+    K(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AluOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    And,
+    Or,
+    Xor,
+    Lsh,
+    Rsh,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cond {
+    Eq,
+    Gt,
+    Ge,
+    // A & src != 0
+    Set,
+}
+
+// jump targets are the indices of the instructions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Insn {
+    RetA,
+    RetK(u32),
+    // A = P[k:size]
+    LdAbs { size: u64, k: u32 },
+    // A = P[X+k:size]
+    LdInd { size: u64, k: u32 },
+    // X = 4 * (P[k] & 0xf)
+    LdxMsh(u32),
+    Imm(Reg, u32),
+    Len(Reg),
+    LdMem(Reg, usize),
+    St(Reg, usize),
+    Alu(AluOp, Src),
+    Neg,
+    Ja(usize),
+    Jmp {
+        cond: Cond,
+        src: Src,
+        jt: usize,
+        jf: usize,
+    },
+    Tax,
+    Txa,
+}
+
+fn load_size(idx: usize, code: u16) -> Result<u64, String> {
+    match bpf_size(code) {
+        BPF_W => Ok(4),
+        BPF_H => Ok(2),
+        BPF_B => Ok(1),
+        _ => Err(format!("{}: invalid load size", idx)),
+    }
+}
+
+fn mem(idx: usize, k: u32) -> Result<usize, String> {
+    if k >= BPF_MEMWORDS as u32 {
+        return Err(format!("{}: invalid scratch memory M[{}]", idx, k));
+    }
+    Ok(k as usize)
+}
+
+fn decode_insn(insns: &[BpfInsn], idx: usize) -> Result<Insn, String> {
+    let insn = insns[idx];
+    let code = insn.code;
+    let k = insn.k;
+    let src = if bpf_src(code) == BPF_X {
+        Src::X
+    } else {
+        Src::K(k)
+    };
+    let target = |offset: u32| {
+        let t = idx + 1 + offset as usize;
+        if t >= insns.len() {
+            return Err(format!("{}: jump out of the program", idx));
+        }
+        Ok(t)
+    };
+
+    let insn = match bpf_class(code) {
+        BPF_RET => match bpf_rval(code) {
+            BPF_A => Insn::RetA,
+            BPF_K => Insn::RetK(k),
+            _ => return Err(format!("{}: invalid return value", idx)),
+        },
+        BPF_LD | BPF_LDX => {
+            let reg = if bpf_class(code) == BPF_LD {
+                Reg::A
+            } else {
+                Reg::X
+            };
+            match bpf_mode(code) {
+                BPF_ABS if reg == Reg::A => Insn::LdAbs {
+                    size: load_size(idx, code)?,
+                    k,
+                },
+                BPF_IND if reg == Reg::A => Insn::LdInd {
+                    size: load_size(idx, code)?,
+                    k,
+                },
+                BPF_MSH if reg == Reg::X => Insn::LdxMsh(k),
+                BPF_IMM => Insn::Imm(reg, k),
+                BPF_LEN => Insn::Len(reg),
+                BPF_MEM => Insn::LdMem(reg, mem(idx, k)?),
+                _ => return Err(format!("{}: invalid load mode", idx)),
+            }
+        }
+        BPF_ST => Insn::St(Reg::A, mem(idx, k)?),
+        BPF_STX => Insn::St(Reg::X, mem(idx, k)?),
+        BPF_ALU => {
+            let op = match bpf_op(code) {
+                BPF_NEG => return Ok(Insn::Neg),
+                BPF_ADD => AluOp::Add,
+                BPF_SUB => AluOp::Sub,
+                BPF_MUL => AluOp::Mul,
+                BPF_DIV => AluOp::Div,
+                BPF_MOD => AluOp::Mod,
+                BPF_AND => AluOp::And,
+                BPF_OR => AluOp::Or,
+                BPF_XOR => AluOp::Xor,
+                BPF_LSH => AluOp::Lsh,
+                BPF_RSH => AluOp::Rsh,
+                _ => return Err(format!("{}: invalid ALU operation", idx)),
+            };
+            Insn::Alu(op, src)
+        }
+        BPF_JMP => {
+            let cond = match bpf_op(code) {
+                BPF_JA => return Ok(Insn::Ja(target(k)?)),
+                BPF_JEQ => Cond::Eq,
+                BPF_JGT => Cond::Gt,
+                BPF_JGE => Cond::Ge,
+                BPF_JSET => Cond::Set,
+                _ => return Err(format!("{}: invalid jump", idx)),
+            };
+            Insn::Jmp {
+                cond,
+                src,
+                jt: target(insn.jt as u32)?,
+                jf: target(insn.jf as u32)?,
+            }
+        }
+        BPF_MISC => match bpf_miscop(code) {
+            BPF_TAX => Insn::Tax,
+            BPF_TXA => Insn::Txa,
+            _ => return Err(format!("{}: invalid misc operation", idx)),
+        },
+        _ => return Err(format!("{}: invalid instruction", idx)),
+    };
+    Ok(insn)
+}
+
+// instructions of the program. the program must end with a return, so that
+// every path returns
+pub fn decode(insns: &[BpfInsn]) -> Result<Vec<Insn>, String> {
+    match insns.last() {
+        Some(insn) if bpf_class(insn.code) == BPF_RET => {}
+        _ => return Err("the program does not end with a return".to_owned()),
+    }
+    (0..insns.len()).map(|i| decode_insn(insns, i)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_program() {
+        // ldh [12]; jeq #0x800, L1, L2; L1: ldx msh [14]; ldb [x + 23]; st M[3]; ret a;
+        // L2: ret #0
+        let insns = [
+            BpfInsn::new(BPF_LD_H_ABS, 0, 0, 12),
+            BpfInsn::new(BPF_JEQ_K, 0, 4, 0x0800),
+            BpfInsn::new(BPF_LDX_B_MSH, 0, 0, 14),
+            BpfInsn::new(BPF_LD_B_IND, 0, 0, 23),
+            BpfInsn::new(BPF_ST, 0, 0, 3),
+            BpfInsn::new(BPF_RET_A, 0, 0, 0),
+            BpfInsn::new(BPF_RET_K, 0, 0, 0),
+        ];
+        assert_eq!(
+            decode(&insns),
+            Ok(vec![
+                Insn::LdAbs { size: 2, k: 12 },
+                Insn::Jmp {
+                    cond: Cond::Eq,
+                    src: Src::K(0x0800),
+                    jt: 2,
+                    jf: 6,
+                },
+                Insn::LdxMsh(14),
+                Insn::LdInd { size: 1, k: 23 },
+                Insn::St(Reg::A, 3),
+                Insn::RetA,
+                Insn::RetK(0),
+            ])
+        );
+
+        let invalid: Vec<Vec<BpfInsn>> = vec![
+            vec![],
+            vec![BpfInsn::new(BPF_LD_W_ABS, 0, 0, 0)],
+            vec![
+                BpfInsn::new(BPF_JMP_JA, 0, 0, 1),
+                BpfInsn::new(BPF_RET_K, 0, 0, 0),
+            ],
+            vec![
+                BpfInsn::new(BPF_LD_MEM, 0, 0, 16),
+                BpfInsn::new(BPF_RET_A, 0, 0, 0),
+            ],
+            vec![
+                BpfInsn::new(BPF_LDX | BPF_W | BPF_ABS, 0, 0, 0),
+                BpfInsn::new(BPF_RET_A, 0, 0, 0),
+            ],
+        ];
+        for insns in &invalid {
+            assert!(decode(insns).is_err());
+        }
+    }
+
+    // every backend against `Simple`
+    #[test]
+    fn backends() {
+        use cbpf::interpreter::{Interpreter, Simple};
+        use filter::{Backend, CompiledFilter};
+        use wasm::{run_wasmi, to_wasm};
+        use Options;
+
+        let packets = fixture::packets();
+        for insns in &fixture::programs() {
+            let mut filters = vec![];
+            for &backend in &[Backend::Llvm, Backend::Cranelift, Backend::Interpreter] {
+                for &optimization in &[false, true] {
+                    let options = Options {
+                        backend,
+                        ..Default::default()
+                    };
+                    // the backends which are not enabled fail
+                    if let Ok(filter) = CompiledFilter::new(insns, options, optimization) {
+                        filters.push(filter);
+                    }
+                }
+            }
+            if cfg!(feature = "llvm") {
+                assert_eq!(filters[0].backend(), Backend::Llvm);
+            }
+            let wasm = to_wasm(insns).unwrap();
+            for packet in &packets {
+                // out of bounds loads return 0
+                let expected = Simple::run(insns, packet).unwrap_or(0);
+                for filter in &filters {
+                    assert_eq!(filter.run(packet), expected, "{:?}", filter.backend());
+                }
+                assert_eq!(run_wasmi(&wasm, packet), expected);
+            }
+        }
+    }
+}
+
+// programs and packets which the backends are tested with
+#[cfg(test)]
+pub mod fixture {
+    use cbpf::opcode::*;
+
+    pub fn programs() -> Vec<Vec<BpfInsn>> {
+        vec![
+            // ip and tcp port 80
+            vec![
+                BpfInsn::new(BPF_LD_H_ABS, 0, 0, 12),
+                BpfInsn::new(BPF_JEQ_K, 0, 8, 0x800),
+                BpfInsn::new(BPF_LD | BPF_B | BPF_ABS, 0, 0, 23),
+                BpfInsn::new(BPF_JEQ_K, 0, 6, 6),
+                BpfInsn::new(BPF_LD_H_ABS, 0, 0, 20),
+                BpfInsn::new(BPF_JMP | BPF_JSET | BPF_K, 4, 0, 0x1fff),
+                BpfInsn::new(BPF_LDX | BPF_B | BPF_MSH, 0, 0, 14),
+                BpfInsn::new(BPF_LD | BPF_H | BPF_IND, 0, 0, 16),
+                BpfInsn::new(BPF_JEQ_K, 0, 1, 80),
+                BpfInsn::new(BPF_RET_K, 0, 0, 0x40000),
+                BpfInsn::new(BPF_RET_K, 0, 0, 0),
+            ],
+            // arithmetic, scratch memory and registers. X is the 5th byte of the packet
+            vec![
+                BpfInsn::new(BPF_LD | BPF_W | BPF_ABS, 0, 0, 0),
+                BpfInsn::new(BPF_ST, 0, 0, 3),
+                BpfInsn::new(BPF_LD | BPF_B | BPF_ABS, 0, 0, 4),
+                BpfInsn::new(BPF_MISC | BPF_TAX, 0, 0, 0),
+                BpfInsn::new(BPF_LD_MEM, 0, 0, 3),
+                BpfInsn::new(BPF_ALU | BPF_DIV | BPF_X, 0, 0, 0),
+                BpfInsn::new(BPF_ALU | BPF_LSH | BPF_X, 0, 0, 0),
+                BpfInsn::new(BPF_LDX | BPF_W | BPF_MEM, 0, 0, 3),
+                BpfInsn::new(BPF_ALU | BPF_ADD | BPF_X, 0, 0, 0),
+                BpfInsn::new(BPF_ALU | BPF_NEG, 0, 0, 0),
+                BpfInsn::new(BPF_ALU | BPF_MOD | BPF_K, 0, 0, 7),
+                BpfInsn::new(BPF_MISC | BPF_TAX, 0, 0, 0),
+                BpfInsn::new(BPF_MISC | BPF_TXA, 0, 0, 0),
+                BpfInsn::new(BPF_JMP | BPF_JGT | BPF_X, 0, 1, 0),
+                BpfInsn::new(BPF_JMP | BPF_JA, 0, 0, 0),
+                BpfInsn::new(BPF_LD | BPF_W | BPF_LEN, 0, 0, 0),
+                BpfInsn::new(BPF_RET_A, 0, 0, 0),
+            ],
+            // the same with constants: ld [0]; st M[3]; ldx #5; div x; lsh #3; ldx #2; rsh x;
+            // ldx M[3]; add x; neg; mod #7; tax; txa; jgt x, L1, L2; L1: ja L2; L2: ld len; ret a
+            vec![
+                BpfInsn::new(BPF_LD | BPF_W | BPF_ABS, 0, 0, 0),
+                BpfInsn::new(BPF_ST, 0, 0, 3),
+                BpfInsn::new(BPF_LDX | BPF_W | BPF_IMM, 0, 0, 5),
+                BpfInsn::new(BPF_ALU | BPF_DIV | BPF_X, 0, 0, 0),
+                BpfInsn::new(BPF_ALU | BPF_LSH | BPF_K, 0, 0, 3),
+                BpfInsn::new(BPF_LDX | BPF_W | BPF_IMM, 0, 0, 2),
+                BpfInsn::new(BPF_ALU | BPF_RSH | BPF_X, 0, 0, 0),
+                BpfInsn::new(BPF_LDX | BPF_W | BPF_MEM, 0, 0, 3),
+                BpfInsn::new(BPF_ALU | BPF_ADD | BPF_X, 0, 0, 0),
+                BpfInsn::new(BPF_ALU | BPF_NEG, 0, 0, 0),
+                BpfInsn::new(BPF_ALU | BPF_MOD | BPF_K, 0, 0, 7),
+                BpfInsn::new(BPF_MISC | BPF_TAX, 0, 0, 0),
+                BpfInsn::new(BPF_MISC | BPF_TXA, 0, 0, 0),
+                BpfInsn::new(BPF_JMP | BPF_JGT | BPF_X, 0, 1, 0),
+                BpfInsn::new(BPF_JMP | BPF_JA, 0, 0, 0),
+                BpfInsn::new(BPF_LD | BPF_W | BPF_LEN, 0, 0, 0),
+                BpfInsn::new(BPF_RET_A, 0, 0, 0),
+            ],
+            // 32-bit constants
+            vec![
+                BpfInsn::new(BPF_LD_IMM, 0, 0, 0xffff_fffe),
+                BpfInsn::new(BPF_ALU | BPF_ADD | BPF_K, 0, 0, 1),
+                BpfInsn::new(BPF_JEQ_K, 0, 1, 0xffff_ffff),
+                BpfInsn::new(BPF_RET_A, 0, 0, 0),
+                BpfInsn::new(BPF_RET_K, 0, 0, 0x8000_0000),
+            ],
+            // division and modulo by X:
+            // ldb [4]; tax; ld [0]; div x; st M[0]; ld [0]; mod x; ldx M[0]; add x; ret a
+            vec![
+                BpfInsn::new(BPF_LD | BPF_B | BPF_ABS, 0, 0, 4),
+                BpfInsn::new(BPF_MISC | BPF_TAX, 0, 0, 0),
+                BpfInsn::new(BPF_LD_W_ABS, 0, 0, 0),
+                BpfInsn::new(BPF_ALU | BPF_DIV | BPF_X, 0, 0, 0),
+                BpfInsn::new(BPF_ST, 0, 0, 0),
+                BpfInsn::new(BPF_LD_W_ABS, 0, 0, 0),
+                BpfInsn::new(BPF_ALU | BPF_MOD | BPF_X, 0, 0, 0),
+                BpfInsn::new(BPF_LDX | BPF_W | BPF_MEM, 0, 0, 0),
+                BpfInsn::new(BPF_ALU | BPF_ADD | BPF_X, 0, 0, 0),
+                BpfInsn::new(BPF_RET_A, 0, 0, 0),
+            ],
+            // shifts by X:
+            // ldb [4]; tax; ld [0]; lsh x; st M[0]; ld [0]; rsh x; ldx M[0]; or x; ret a
+            vec![
+                BpfInsn::new(BPF_LD | BPF_B | BPF_ABS, 0, 0, 4),
+                BpfInsn::new(BPF_MISC | BPF_TAX, 0, 0, 0),
+                BpfInsn::new(BPF_LD_W_ABS, 0, 0, 0),
+                BpfInsn::new(BPF_ALU | BPF_LSH | BPF_X, 0, 0, 0),
+                BpfInsn::new(BPF_ST, 0, 0, 0),
+                BpfInsn::new(BPF_LD_W_ABS, 0, 0, 0),
+                BpfInsn::new(BPF_ALU | BPF_RSH | BPF_X, 0, 0, 0),
+                BpfInsn::new(BPF_LDX | BPF_W | BPF_MEM, 0, 0, 0),
+                BpfInsn::new(BPF_ALU | BPF_OR | BPF_X, 0, 0, 0),
+                BpfInsn::new(BPF_RET_A, 0, 0, 0),
+            ],
+            // M[] starts at 0: ld M[5]; ret a
+            vec![
+                BpfInsn::new(BPF_LD_MEM, 0, 0, 5),
+                BpfInsn::new(BPF_RET_A, 0, 0, 0),
+            ],
+        ]
+    }
+
+    // Ethernet, IPv4 and TCP headers of a packet to port 80
+    pub fn tcp80() -> Vec<u8> {
+        let mut tcp80 = vec![0u8; 54];
+        tcp80[12] = 0x08;
+        tcp80[14] = 0x45;
+        tcp80[23] = 6;
+        tcp80[37] = 80;
+        tcp80
+    }
+
+    pub fn packets() -> Vec<Vec<u8>> {
+        let tcp80 = tcp80();
+        let mut ssh = tcp80.clone();
+        ssh[37] = 22;
+        // IP options make the port out of bounds
+        let mut options = tcp80.clone();
+        options[14] = 0x4f;
+        let mut word = vec![0xde, 0xad, 0xbe, 0xef];
+        word.extend_from_slice(&tcp80);
+        vec![
+            tcp80.clone(),
+            tcp80[..36].to_vec(),
+            ssh,
+            options,
+            word,
+            // X is 3, 0 and 40 in the programs which read it from the 5th byte
+            vec![0x12, 0x34, 0x56, 0x78, 3],
+            vec![0x12, 0x34, 0x56, 0x78, 0],
+            vec![0xff, 0xff, 0xff, 0xff, 40],
+            vec![0x12, 0x34, 0x56, 0x78, 40],
+            vec![0xff; 4],
+            vec![],
+        ]
+    }
+}
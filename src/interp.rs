@@ -0,0 +1,144 @@
+// interpreter backend
+//
+// It runs the instructions of frontend.rs without generating any code, so that
+// `CompiledFilter` is available without LLVM and behaves the same as the JIT-compiled
+// code. The program is decoded and validated once in `new()`, so `run()` does not look at
+// opcodes and jumps directly to the resolved targets.
+
+use cbpf::opcode::{BpfInsn, BPF_MEMWORDS};
+use frontend::{self, AluOp, Cond, Insn, Reg, Src};
+use trace;
+
+pub struct Interpreter {
+    insns: Vec<Insn>,
+    // call the trace hook before each instruction (see `Options::trace`)
+    trace: bool,
+}
+
+// big endian value of `size` bytes at offset, or None if it is out of bounds
+fn load(data: &[u8], offset: u64, size: u64) -> Option<u32> {
+    if offset + size > data.len() as u64 {
+        return None;
+    }
+    let offset = offset as usize;
+    Some(
+        data[offset..offset + size as usize]
+            .iter()
+            .fold(0, |v, &b| (v << 8) | b as u32),
+    )
+}
+
+fn operand(src: Src, x: u32) -> u32 {
+    match src {
+        Src::X => x,
+        Src::K(k) => k,
+    }
+}
+
+impl Interpreter {
+    pub fn new(insns: &[BpfInsn], trace: bool) -> Result<Self, String> {
+        Ok(Interpreter {
+            insns: frontend::decode(insns)?,
+            trace,
+        })
+    }
+
+    pub fn run(&self, data: &[u8]) -> u32 {
+        let (mut a, mut x) = (0u32, 0u32);
+        let mut mem = [0u32; BPF_MEMWORDS as usize];
+        let mut pc = 0;
+        loop {
+            if self.trace {
+                trace::hook(pc as u32, a, x);
+            }
+            match self.insns[pc] {
+                Insn::RetA => return a,
+                Insn::RetK(k) => return k,
+                Insn::LdAbs { size, k } => match load(data, k as u64, size) {
+                    Some(v) => a = v,
+                    None => return 0,
+                },
+                // X + k does not wrap around
+                Insn::LdInd { size, k } => match load(data, x as u64 + k as u64, size) {
+                    Some(v) => a = v,
+                    None => return 0,
+                },
+                Insn::LdxMsh(k) => match load(data, k as u64, 1) {
+                    Some(v) => x = (v & 0xf) << 2,
+                    None => return 0,
+                },
+                Insn::Imm(Reg::A, k) => a = k,
+                Insn::Imm(Reg::X, k) => x = k,
+                Insn::Len(Reg::A) => a = data.len() as u32,
+                Insn::Len(Reg::X) => x = data.len() as u32,
+                Insn::LdMem(Reg::A, i) => a = mem[i],
+                Insn::LdMem(Reg::X, i) => x = mem[i],
+                Insn::St(Reg::A, i) => mem[i] = a,
+                Insn::St(Reg::X, i) => mem[i] = x,
+                Insn::Alu(op, src) => {
+                    let v = operand(src, x);
+                    a = match op {
+                        AluOp::Add => a.wrapping_add(v),
+                        AluOp::Sub => a.wrapping_sub(v),
+                        AluOp::Mul => a.wrapping_mul(v),
+                        AluOp::Div | AluOp::Mod if v == 0 => return 0,
+                        AluOp::Div => a / v,
+                        AluOp::Mod => a % v,
+                        AluOp::And => a & v,
+                        AluOp::Or => a | v,
+                        AluOp::Xor => a ^ v,
+                        AluOp::Lsh => a.checked_shl(v).unwrap_or(0),
+                        AluOp::Rsh => a.checked_shr(v).unwrap_or(0),
+                    }
+                }
+                Insn::Neg => a = a.wrapping_neg(),
+                Insn::Ja(target) => {
+                    pc = target;
+                    continue;
+                }
+                Insn::Jmp { cond, src, jt, jf } => {
+                    let v = operand(src, x);
+                    let taken = match cond {
+                        Cond::Eq => a == v,
+                        Cond::Gt => a > v,
+                        Cond::Ge => a >= v,
+                        Cond::Set => a & v != 0,
+                    };
+                    pc = if taken { jt } else { jf };
+                    continue;
+                }
+                Insn::Tax => x = a,
+                Insn::Txa => a = x,
+            }
+            pc += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cbpf::opcode::*;
+    use frontend::fixture;
+
+    // tracing does not change the result. frontend.rs compares the backends with `Simple`,
+    // and filter.rs compares the traces with those of the JIT-compiled code
+    #[test]
+    fn interpret() {
+        let packets = fixture::packets();
+        for insns in &fixture::programs() {
+            let interpreter = Interpreter::new(insns, false).unwrap();
+            let traced = Interpreter::new(insns, true).unwrap();
+            for packet in &packets {
+                trace::start();
+                let result = traced.run(packet);
+                let steps = trace::finish();
+                assert_eq!(result, interpreter.run(packet));
+                assert_eq!(steps[0], trace::TraceStep { idx: 0, a: 0, x: 0 });
+            }
+        }
+
+        let invalid = [BpfInsn::new(BPF_LD_W_ABS, 0, 0, 0)];
+        assert!(Interpreter::new(&invalid, false).is_err());
+    }
+}
@@ -0,0 +1,213 @@
+// registration of JIT-compiled filters to profilers and debuggers
+//
+// perf reads symbols of JIT-compiled code from /tmp/perf-<pid>.map, which has a line
+// "START SIZE symbol" for each function. MCJIT does not tell us where the code is, so
+// we use our own memory manager which remembers the code sections it allocates.
+
+use cbpf::opcode::BpfInsn;
+#[cfg(feature = "llvm")]
+use llvm::execution_engine::{LLVMExecutionEngineRef, LLVMMCJITMemoryManagerRef};
+#[cfg(feature = "llvm")]
+use llvm::prelude::LLVMBool;
+
+#[cfg(feature = "llvm")]
+use std::fs::OpenOptions;
+#[cfg(feature = "llvm")]
+use std::io::{self, Write};
+
+#[cfg(feature = "llvm")]
+extern "C" {
+    // src/shim/gdb.cpp
+    pub fn cbpf_register_gdb_listener(engine: LLVMExecutionEngineRef);
+}
+
+// FNV-1a
+pub fn fnv1a(basis: u64, data: &[u8]) -> u64 {
+    let mut h = basis;
+    for &b in data {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x0100_0000_01b3);
+    }
+    h
+}
+
+pub fn hash(insns: &[BpfInsn]) -> u64 {
+    let mut bytes = Vec::with_capacity(insns.len() * 8);
+    for insn in insns {
+        bytes.extend_from_slice(&[
+            insn.code as u8,
+            (insn.code >> 8) as u8,
+            insn.jt,
+            insn.jf,
+            insn.k as u8,
+            (insn.k >> 8) as u8,
+            (insn.k >> 16) as u8,
+            (insn.k >> 24) as u8,
+        ]);
+    }
+    fnv1a(0xcbf2_9ce4_8422_2325, &bytes)
+}
+
+// cbpf_filter_<name>_<hash>
+#[cfg(feature = "llvm")]
+pub fn symbol_name(name: &str, insns: &[BpfInsn]) -> String {
+    let name: String = if name.is_empty() { "anon" } else { name }
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("cbpf_filter_{}_{:016x}", name, hash(insns))
+}
+
+#[cfg(feature = "llvm")]
+struct Section {
+    addr: *mut u8,
+    size: usize,
+    // size of the mapping (rounded up to pages)
+    len: usize,
+    code: bool,
+    read_only: bool,
+}
+
+#[cfg(feature = "llvm")]
+pub struct CodeSections {
+    sections: Vec<Section>,
+}
+
+#[cfg(feature = "llvm")]
+impl CodeSections {
+    // (address, size) of the code sections
+    pub fn code(&self) -> Vec<(usize, usize)> {
+        self.sections
+            .iter()
+            .filter(|s| s.code)
+            .map(|s| (s.addr as usize, s.size))
+            .collect()
+    }
+
+    fn allocate(&mut self, size: usize, code: bool, read_only: bool) -> *mut u8 {
+        let page = unsafe { ::libc::sysconf(::libc::_SC_PAGESIZE) as usize };
+        let len = std::cmp::max((size + page - 1) / page * page, page);
+        let addr = unsafe {
+            ::libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                ::libc::PROT_READ | ::libc::PROT_WRITE,
+                ::libc::MAP_PRIVATE | ::libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if addr == ::libc::MAP_FAILED {
+            return std::ptr::null_mut();
+        }
+        self.sections.push(Section {
+            addr: addr as *mut u8,
+            size,
+            len,
+            code,
+            read_only,
+        });
+        addr as *mut u8
+    }
+}
+
+#[cfg(feature = "llvm")]
+extern "C" fn allocate_code_section(
+    opaque: *mut ::libc::c_void,
+    size: ::libc::uintptr_t,
+    _alignment: ::libc::c_uint,
+    _section_id: ::libc::c_uint,
+    _section_name: *const ::libc::c_char,
+) -> *mut u8 {
+    // mmap returns page aligned memory, which satisfies any alignment of sections
+    let sections = unsafe { &mut *(opaque as *mut CodeSections) };
+    sections.allocate(size, true, true)
+}
+
+#[cfg(feature = "llvm")]
+extern "C" fn allocate_data_section(
+    opaque: *mut ::libc::c_void,
+    size: ::libc::uintptr_t,
+    _alignment: ::libc::c_uint,
+    _section_id: ::libc::c_uint,
+    _section_name: *const ::libc::c_char,
+    read_only: LLVMBool,
+) -> *mut u8 {
+    let sections = unsafe { &mut *(opaque as *mut CodeSections) };
+    sections.allocate(size, false, read_only != 0)
+}
+
+#[cfg(feature = "llvm")]
+extern "C" fn finalize_memory(
+    opaque: *mut ::libc::c_void,
+    _err_msg: *mut *mut ::libc::c_char,
+) -> LLVMBool {
+    let sections = unsafe { &*(opaque as *const CodeSections) };
+    for s in &sections.sections {
+        let prot = match (s.code, s.read_only) {
+            (true, _) => ::libc::PROT_READ | ::libc::PROT_EXEC,
+            (false, true) => ::libc::PROT_READ,
+            (false, false) => continue,
+        };
+        if unsafe { ::libc::mprotect(s.addr as *mut _, s.len, prot) } != 0 {
+            return 1;
+        }
+    }
+    0
+}
+
+#[cfg(feature = "llvm")]
+extern "C" fn destroy(opaque: *mut ::libc::c_void) {
+    let sections = unsafe { Box::from_raw(opaque as *mut CodeSections) };
+    for s in &sections.sections {
+        unsafe {
+            ::libc::munmap(s.addr as *mut _, s.len);
+        }
+    }
+}
+
+// the memory manager is owned by the execution engine, and the returned CodeSections is
+// valid until the engine is disposed
+#[cfg(feature = "llvm")]
+pub fn create_memory_manager() -> (LLVMMCJITMemoryManagerRef, *const CodeSections) {
+    let sections = Box::into_raw(Box::new(CodeSections { sections: vec![] }));
+    // the callback is optional in the bindings of LLVM 15+
+    #[cfg(not(feature = "llvm-5"))]
+    let destroy: ::llvm::execution_engine::LLVMMemoryManagerDestroyCallback = Some(destroy);
+    let mm = unsafe {
+        ::llvm::execution_engine::LLVMCreateSimpleMCJITMemoryManager(
+            sections as *mut _,
+            allocate_code_section,
+            allocate_data_section,
+            finalize_memory,
+            destroy,
+        )
+    };
+    (mm, sections)
+}
+
+#[cfg(feature = "llvm")]
+pub fn write_perf_map(sections: &CodeSections, symbol: &str) -> io::Result<()> {
+    let path = format!("/tmp/perf-{}.map", unsafe { ::libc::getpid() });
+    let mut f = OpenOptions::new().create(true).append(true).open(path)?;
+    for (addr, size) in sections.code() {
+        writeln!(f, "{:x} {:x} {}", addr, size, symbol)?;
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "llvm"))]
+mod tests {
+    use super::*;
+    use cbpf::opcode::*;
+
+    #[test]
+    fn symbol() {
+        let insns = [BpfInsn::new(BPF_RET_K, 0, 0, 0)];
+        let name = symbol_name("tcp port 80", &insns);
+        assert!(name.starts_with("cbpf_filter_tcp_port_80_"));
+        assert_eq!(name.len(), "cbpf_filter_tcp_port_80_".len() + 16);
+        assert_eq!(name, symbol_name("tcp port 80", &insns));
+        assert!(name != symbol_name("tcp port 80", &[BpfInsn::new(BPF_RET_K, 0, 0, 1)]));
+    }
+}
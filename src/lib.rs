@@ -1,76 +1,285 @@
 extern crate cbpf;
 extern crate libc;
+// the version of LLVM is selected by one of the llvm-* features
+#[cfg(feature = "llvm-5")]
 extern crate llvm_sys as llvm;
-
+#[cfg(feature = "llvm-15")]
+extern crate llvm_sys_150 as llvm;
+#[cfg(feature = "llvm-16")]
+extern crate llvm_sys_160 as llvm;
+#[cfg(feature = "llvm-17")]
+extern crate llvm_sys_170 as llvm;
+#[cfg(feature = "llvm-18")]
+extern crate llvm_sys_181 as llvm;
+#[cfg(test)]
+extern crate rbpf;
+#[cfg(test)]
+extern crate wasmi;
+#[cfg(feature = "cranelift")]
+extern crate cranelift_codegen;
+#[cfg(feature = "cranelift")]
+extern crate cranelift_frontend;
+#[cfg(feature = "cranelift")]
+extern crate cranelift_jit;
+#[cfg(feature = "cranelift")]
+extern crate cranelift_module;
+#[cfg(feature = "cranelift")]
+extern crate cranelift_native;
+
+#[cfg(feature = "llvm")]
 use cbpf::opcode::*;
+#[cfg(feature = "llvm")]
 use llvm::prelude::*;
+#[cfg(feature = "llvm")]
+use llvm::LLVMOpcode;
+#[cfg(feature = "llvm")]
 use llvm::analysis::{LLVMVerifierFailureAction, LLVMVerifyModule};
+#[cfg(feature = "llvm")]
 use llvm::execution_engine::{LLVMExecutionEngineRef, LLVMMCJITCompilerOptions};
 
+#[cfg(feature = "llvm")]
 use std::ptr;
+#[cfg(feature = "llvm")]
 use std::mem;
+#[cfg(feature = "llvm")]
 use std::collections::HashMap;
-
+#[cfg(feature = "llvm")]
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "llvm")]
+use bounds::{load_size, BoundsPlan};
+
+#[cfg(all(
+    feature = "llvm",
+    not(any(
+        feature = "llvm-5",
+        feature = "llvm-15",
+        feature = "llvm-16",
+        feature = "llvm-17",
+        feature = "llvm-18"
+    ))
+))]
+compile_error!("select the version of LLVM with one of the llvm-5, llvm-15, llvm-16, llvm-17 and llvm-18 features");
+
+// llvm-5 is a default feature, so the other versions need --no-default-features
+#[cfg(any(
+    all(
+        feature = "llvm-5",
+        any(
+            feature = "llvm-15",
+            feature = "llvm-16",
+            feature = "llvm-17",
+            feature = "llvm-18"
+        )
+    ),
+    all(
+        feature = "llvm-15",
+        any(feature = "llvm-16", feature = "llvm-17", feature = "llvm-18")
+    ),
+    all(feature = "llvm-16", any(feature = "llvm-17", feature = "llvm-18")),
+    all(feature = "llvm-17", feature = "llvm-18")
+))]
+compile_error!("only one of the llvm-* features can be enabled; build with --no-default-features to replace the default llvm-5 (e.g. --no-default-features --features llvm-18)");
+
+// defined before the modules so that they can use it
+#[cfg(feature = "llvm")]
 macro_rules! cstr {
-    ($x: expr) => (concat!($x, "\0").as_ptr() as *const libc::c_char);
-    () => (b"\0".as_ptr() as *const libc::c_char);
+    ($x: expr) => (concat!($x, "\0").as_ptr() as *const ::libc::c_char);
+    () => (b"\0".as_ptr() as *const ::libc::c_char);
 }
 
-type Func = extern "C" fn(*mut u8) -> i32;
+mod analysis;
+mod aot;
+mod bounds;
+pub mod capi;
+mod cfg;
+#[cfg(feature = "llvm")]
+mod compat;
+#[cfg(feature = "cranelift")]
+mod cranelift;
+mod csource;
+mod debuginfo;
+mod disasm;
+mod dot;
+mod ebpf;
+#[cfg(test)]
+mod ebpfvm;
+mod filter;
+mod filtercache;
+mod frontend;
+mod interp;
+mod jit;
+#[cfg(feature = "llvm")]
+mod objcache;
+mod optimize;
+#[cfg(feature = "llvm")]
+mod orc;
+mod pgo;
+mod slot;
+mod trace;
+mod wasm;
+
+pub use analysis::{analyze, FilterReport};
+pub use csource::to_c;
+pub use aot::{archive as write_archive, header as c_header, is_identifier};
+pub use debuginfo::source as debug_source;
+pub use disasm::disasm;
+pub use dot::to_dot;
+pub use ebpf::{linktype_offset, sections as elf_sections, Action, EbpfTarget, MapType, ProgramType,
+               RedirectMap, Section};
+pub use filter::{Backend, CompiledFilter};
+pub use filtercache::{CacheStats, FilterCache};
+pub use optimize::optimize;
+#[cfg(feature = "llvm")]
+pub use orc::{JitSession, OrcFilter};
+pub use pgo::BranchProfile;
+pub use slot::FilterSlot;
+pub use trace::{interpret as interpret_traced, format as format_trace, TraceStep};
+pub use wasm::to_wasm;
+
+#[cfg(any(feature = "llvm", feature = "cranelift"))]
+type Func = extern "C" fn(*mut u8, u32) -> i32;
+
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    // attach DWARF locations; the line number is the cBPF instruction index + 1
+    // of the disassembly "filter.bpf" in the current directory (see `debug_source()`)
+    pub debug_info: bool,
+    // name of the filter. JIT-compiled code is named `cbpf_filter_<name>_<hash>`
+    // when it is registered to perf or GDB
+    pub name: String,
+    // append the JIT-compiled code to /tmp/perf-<pid>.map
+    pub perf_map: bool,
+    // register the JIT-compiled code through the GDB JIT interface
+    pub gdb_jit: bool,
+    // count executions of each cBPF instruction (and how many times each conditional
+    // jump is taken) in the global array "cbpf.counters"
+    pub profile: bool,
+    // attach branch weights to conditional jumps
+    pub branch_profile: Option<BranchProfile>,
+    // call `void cbpf_trace_hook(i32 idx, i32 A, i32 X)` before each instruction.
+    // bounds checks are not hoisted so that the trace stops at the failing load
+    pub trace: bool,
+    // keep JIT-compiled objects in this directory and reuse them across processes
+    pub cache_dir: Option<String>,
+    // generate an eBPF program for the BPF target instead (see ebpf.rs),
+    // which can only be emitted with `emit_object()`
+    pub ebpf: Option<EbpfTarget>,
+    // code generator of `CompiledFilter`. `Converter` always uses LLVM
+    pub backend: Backend,
+    // pipeline of the new pass manager (the syntax of `opt -passes`) which is run instead of
+    // `default<O2>` and `lto<O2>`, e.g. "default<O3>,lto<O3>". requires LLVM 15+
+    pub passes: Option<String>,
+}
 
+#[cfg(feature = "llvm")]
 pub struct Converter {
     context: LLVMContextRef,
+    owns_context: bool,
     module: LLVMModuleRef,
     builder: LLVMBuilderRef,
     functions: HashMap<String, LLVMValueRef>,
     values: HashMap<String, LLVMValueRef>,
+    blocks: HashMap<String, LLVMBasicBlockRef>,
     engine: Option<LLVMExecutionEngineRef>,
     jit_func: Option<Func>,
+    options: Options,
+    debug_locations: Vec<LLVMValueRef>,
+    symbol: String,
+    num_insns: usize,
+    counters: Option<*mut u64>,
+    cache_path: Option<String>,
+    // stored with the object and compared on load (see objcache.rs)
+    cache_description: String,
+    object_cache: Option<*mut libc::c_void>,
+    pass_timings: Vec<(String, Duration)>,
+    // the optimization is deferred on a cache hit (see `build()`)
+    pending_optimization: bool,
 }
 
 // it seems IRParse requires null terminated strings
+#[cfg(feature = "llvm-5")]
 static UTIL_CODE: &'static str = concat!(include_str!("./ll/util.ll"), "\0");
+// the same code with opaque pointers
+#[cfg(all(feature = "llvm", not(feature = "llvm-5")))]
+static UTIL_CODE: &'static str = concat!(include_str!("./ll/util-opaque.ll"), "\0");
 
 // TODO: error handling, currently just panic!() if something go wrong
+#[cfg(feature = "llvm")]
 impl Converter {
     pub fn new() -> Self {
-        unsafe {
-            llvm::target::LLVM_InitializeNativeTarget();
-            llvm::target::LLVM_InitializeNativeAsmPrinter();
-            llvm::target::LLVM_InitializeNativeAsmParser();
+        Converter::with_options(Options::default())
+    }
 
+    pub fn with_options(options: Options) -> Self {
+        unsafe {
             let context = llvm::core::LLVMContextCreate();
             if context.is_null() {
                 panic!("context is null");
             }
+            Converter::with_context(options, context, true)
+        }
+    }
 
-            let module = llvm::core::LLVMModuleCreateWithNameInContext(cstr!("cbpf_ir"), context);
-            if module.is_null() {
+    // the context is disposed with the converter if `owns_context`. otherwise it is owned
+    // by the caller (ORC JIT of LLVM 15+, which compiles modules in its own contexts)
+    unsafe fn with_context(options: Options, context: LLVMContextRef, owns_context: bool) -> Self {
+        llvm::target::LLVM_InitializeNativeTarget();
+        llvm::target::LLVM_InitializeNativeAsmPrinter();
+        llvm::target::LLVM_InitializeNativeAsmParser();
+
+        let module = llvm::core::LLVMModuleCreateWithNameInContext(cstr!("cbpf_ir"), context);
+        if module.is_null() {
+            if owns_context {
                 llvm::core::LLVMContextDispose(context);
-                panic!("module is null");
             }
+            panic!("module is null");
+        }
 
-            let builder = llvm::core::LLVMCreateBuilderInContext(context);
-            if builder.is_null() {
+        let builder = llvm::core::LLVMCreateBuilderInContext(context);
+        if builder.is_null() {
+            llvm::core::LLVMDisposeModule(module);
+            if owns_context {
                 llvm::core::LLVMContextDispose(context);
-                llvm::core::LLVMDisposeModule(module);
-                panic!("builder is null");
             }
+            panic!("builder is null");
+        }
 
-            let values = HashMap::new();
-            let functions = HashMap::new();
-            let engine = None;
-            let jit_func = None;
-
-            Converter {
-                context,
-                module,
-                builder,
-                functions,
-                values,
-                engine,
-                jit_func,
-            }
+        let values = HashMap::new();
+        let blocks = HashMap::new();
+        let functions = HashMap::new();
+        let engine = None;
+        let jit_func = None;
+        let debug_locations = vec![];
+        let symbol = "main".to_owned();
+        let num_insns = 0;
+        let counters = None;
+        let cache_path = None;
+        let cache_description = String::new();
+        let object_cache = None;
+        let pass_timings = vec![];
+        let pending_optimization = false;
+
+        Converter {
+            context,
+            owns_context,
+            module,
+            builder,
+            functions,
+            values,
+            blocks,
+            engine,
+            jit_func,
+            options,
+            debug_locations,
+            symbol,
+            num_insns,
+            counters,
+            cache_path,
+            cache_description,
+            object_cache,
+            pass_timings,
+            pending_optimization,
         }
     }
 
@@ -114,27 +323,135 @@ impl Converter {
             );
             self.link_module_from_buf(buf);
 
-            self.load_function("ldw");
-            self.load_function("ldh");
             self.load_function("ldb");
             self.load_function("msh");
         }
     }
 
+    fn declare_intrinsics(&mut self) {
+        unsafe {
+            for &bits in &[16, 32] {
+                let ty = llvm::core::LLVMIntTypeInContext(self.context, bits);
+                let params = [ty];
+                let ty_function = llvm::core::LLVMFunctionType(ty, params.as_ptr() as *mut _, 1, 0);
+                let name = format!("llvm.bswap.i{}", bits);
+                let function = llvm::core::LLVMAddFunction(
+                    self.module,
+                    format!("{}\0", name).as_ptr() as *const _,
+                    ty_function,
+                );
+                self.functions.insert(name, function);
+            }
+            if self.skb_loads() {
+                // i64 llvm.bpf.load.*(i8* skb, i64 off), which returns the value in host order
+                let ty_i64 = llvm::core::LLVMInt64TypeInContext(self.context);
+                let ty_i8 = llvm::core::LLVMInt8TypeInContext(self.context);
+                let params = [compat::pointer_type(self.context, ty_i8), ty_i64];
+                let ty_function =
+                    llvm::core::LLVMFunctionType(ty_i64, params.as_ptr() as *mut _, 2, 0);
+                for name in &["llvm.bpf.load.byte", "llvm.bpf.load.half", "llvm.bpf.load.word"] {
+                    let function = llvm::core::LLVMAddFunction(
+                        self.module,
+                        format!("{}\0", name).as_ptr() as *const _,
+                        ty_function,
+                    );
+                    self.functions.insert(name.to_string(), function);
+                }
+            }
+        }
+    }
+
+    // whether the packet is read with LD_ABS/LD_IND instead of data/data_end (socket filters)
+    fn skb_loads(&self) -> bool {
+        match self.options.ebpf {
+            Some(ref target) => target.data_offsets().is_none(),
+            None => false,
+        }
+    }
+
     fn create_main(&mut self) {
         unsafe {
             let ty_i32 = llvm::core::LLVMInt32TypeInContext(self.context);
             let ty_i8 = llvm::core::LLVMInt8TypeInContext(self.context);
-            let params = [llvm::core::LLVMPointerType(ty_i8, 0)];
-            let ty_function = llvm::core::LLVMFunctionType(ty_i32, params.as_ptr() as *mut _, 1, 0);
+            if let Some(ref target) = self.options.ebpf {
+                // i32 main(i8* ctx) in the section of the program type
+                let params = [compat::pointer_type(self.context, ty_i8)];
+                let ty_function =
+                    llvm::core::LLVMFunctionType(ty_i32, params.as_ptr() as *mut _, 1, 0);
+                let function = llvm::core::LLVMAddFunction(self.module, cstr!("main"), ty_function);
+                let section = std::ffi::CString::new(target.section()).unwrap();
+                llvm::core::LLVMSetSection(function, section.as_ptr());
+                // otherwise helper calls make LLVM emit .eh_frame
+                let kind = llvm::core::LLVMGetEnumAttributeKindForName(cstr!("nounwind"), 8);
+                let nounwind = llvm::core::LLVMCreateEnumAttribute(self.context, kind, 0);
+                llvm::core::LLVMAddAttributeAtIndex(
+                    function,
+                    llvm::LLVMAttributeFunctionIndex,
+                    nounwind,
+                );
+                self.functions.insert("main".to_owned(), function);
+
+                // the kernel refuses programs without a license
+                let license = "Dual MIT/GPL";
+                let init = llvm::core::LLVMConstStringInContext(
+                    self.context,
+                    license.as_ptr() as *const _,
+                    license.len() as _,
+                    0,
+                );
+                let global = llvm::core::LLVMAddGlobal(
+                    self.module,
+                    llvm::core::LLVMTypeOf(init),
+                    cstr!("_license"),
+                );
+                llvm::core::LLVMSetInitializer(global, init);
+                llvm::core::LLVMSetSection(global, cstr!("license"));
+                return;
+            }
+            // i32 main(i8* data, i32 len)
+            let params = [compat::pointer_type(self.context, ty_i8), ty_i32];
+            let ty_function = llvm::core::LLVMFunctionType(ty_i32, params.as_ptr() as *mut _, 2, 0);
             let function = llvm::core::LLVMAddFunction(self.module, cstr!("main"), ty_function);
             self.functions.insert("main".to_owned(), function);
         }
     }
 
+    // create main from the skeleton which has debug info and take the locations of
+    // each instruction from it (see debuginfo.rs)
+    fn create_main_with_debug_info(&mut self, insns: &[BpfInsn]) {
+        let directory = std::env::current_dir()
+            .map(|d| d.to_string_lossy().into_owned())
+            .unwrap_or_else(|_| ".".to_owned());
+        let ir = debuginfo::skeleton(insns.len(), &directory);
+        unsafe {
+            let buf = llvm::core::LLVMCreateMemoryBufferWithMemoryRange(
+                ir.as_ptr() as *const _,
+                (ir.len() - 1) as _, // exclude null terminator
+                cstr!("skeleton"),
+                1,
+            );
+            self.link_module_from_buf(buf);
+            self.load_function("main");
+
+            let kind = llvm::core::LLVMGetMDKindIDInContext(self.context, cstr!("dbg"), 3);
+            let bb = llvm::core::LLVMGetFirstBasicBlock(self.get_function("main"));
+            let mut inst = llvm::core::LLVMGetFirstInstruction(bb);
+            while !inst.is_null() {
+                if !llvm::core::LLVMIsACallInst(inst).is_null() {
+                    let loc = llvm::core::LLVMGetMetadata(inst, kind);
+                    self.debug_locations.push(loc);
+                }
+                inst = llvm::core::LLVMGetNextInstruction(inst);
+            }
+            llvm::core::LLVMDeleteBasicBlock(bb);
+            let donothing = llvm::core::LLVMGetNamedFunction(self.module, cstr!("llvm.donothing"));
+            llvm::core::LLVMDeleteFunction(donothing);
+        }
+    }
+
     fn emit_prolog(&mut self) {
         unsafe {
-            // init A, X, MEM[BPF_INSN]
+            // init A, X, MEM[BPF_MEMWORDS]
             let ty_i32 = llvm::core::LLVMInt32TypeInContext(self.context);
             let bb = llvm::core::LLVMAppendBasicBlockInContext(
                 self.context,
@@ -149,9 +466,328 @@ impl Converter {
             let v = llvm::core::LLVMConstInt(ty_i32, 0, 1);
             llvm::core::LLVMBuildStore(self.builder, v, a);
             llvm::core::LLVMBuildStore(self.builder, v, x);
+            // M[] starts at 0 like the other backends. stores which are overwritten before
+            // any load are removed by the optimizer
+            for i in 0..cbpf::opcode::BPF_MEMWORDS {
+                let idx = llvm::core::LLVMConstInt(ty_i32, i as _, 0);
+                let p = compat::build_gep(self.builder, ty_i32, mem, &[idx], cstr!());
+                llvm::core::LLVMBuildStore(self.builder, v, p);
+            }
             self.values.insert("A".to_owned(), a);
             self.values.insert("X".to_owned(), x);
             self.values.insert("MEM".to_owned(), mem);
+
+            let main = self.get_function("main");
+            match self.options.ebpf.clone() {
+                None => {
+                    self.values
+                        .insert("data".to_owned(), llvm::core::LLVMGetParam(main, 0));
+                    self.values
+                        .insert("len".to_owned(), llvm::core::LLVMGetParam(main, 1));
+                }
+                Some(target) => {
+                    let ctx = llvm::core::LLVMGetParam(main, 0);
+                    let ty_i8 = llvm::core::LLVMInt8TypeInContext(self.context);
+                    let ty_ptr = compat::pointer_type(self.context, ty_i8);
+                    let ty_i64 = llvm::core::LLVMInt64TypeInContext(self.context);
+                    // data and data_end are u32 fields of the context, which
+                    // the verifier rewrites into pointers. LD_ABS/LD_IND take the context
+                    let fields = match target.data_offsets() {
+                        Some((data, data_end)) => vec![("data", data), ("data_end", data_end)],
+                        None => {
+                            self.values.insert("data".to_owned(), ctx);
+                            vec![]
+                        }
+                    };
+                    for &(name, offset) in &fields {
+                        let v = self.build_ctx_load(ctx, offset);
+                        let v = llvm::core::LLVMBuildZExt(self.builder, v, ty_i64, cstr!());
+                        let p = llvm::core::LLVMBuildIntToPtr(
+                            self.builder,
+                            v,
+                            ty_ptr,
+                            format!("{}\0", name).as_ptr() as *const _,
+                        );
+                        self.values.insert(name.to_owned(), p);
+                    }
+                    let ty_i32 = llvm::core::LLVMInt32TypeInContext(self.context);
+                    let offset = llvm::core::LLVMConstInt(ty_i32, target.offset as _, 0);
+                    if target.offset > 0 && !fields.is_empty() {
+                        // skip the link layer header
+                        let data = compat::build_gep(
+                            self.builder,
+                            ty_i8,
+                            self.get_value("data"),
+                            &[offset],
+                            cstr!("data"),
+                        );
+                        self.values.insert("data".to_owned(), data);
+                    }
+                    if let Some(len_offset) = target.len_offset() {
+                        let mut len = self.build_ctx_load(ctx, len_offset);
+                        if target.offset > 0 {
+                            // len > offset ? len - offset : 0
+                            let cond = llvm::core::LLVMBuildICmp(
+                                self.builder,
+                                llvm::LLVMIntPredicate::LLVMIntUGT,
+                                len,
+                                offset,
+                                cstr!(),
+                            );
+                            let sub = llvm::core::LLVMBuildSub(self.builder, len, offset, cstr!());
+                            let zero = llvm::core::LLVMConstInt(ty_i32, 0, 0);
+                            len = llvm::core::LLVMBuildSelect(self.builder, cond, sub, zero, cstr!());
+                        }
+                        self.values.insert("len".to_owned(), len);
+                    }
+                }
+            }
+        }
+    }
+
+    // load the u32 field of the eBPF context at `offset`
+    fn build_ctx_load(&self, ctx: LLVMValueRef, offset: u64) -> LLVMValueRef {
+        unsafe {
+            let ty_i8 = llvm::core::LLVMInt8TypeInContext(self.context);
+            let ty_i32 = llvm::core::LLVMInt32TypeInContext(self.context);
+            let ty_i64 = llvm::core::LLVMInt64TypeInContext(self.context);
+            let offset = llvm::core::LLVMConstInt(ty_i64, offset, 0);
+            let p = compat::build_gep(self.builder, ty_i8, ctx, &[offset], cstr!());
+            let p = llvm::core::LLVMBuildBitCast(
+                self.builder,
+                p,
+                compat::pointer_type(self.context, ty_i32),
+                cstr!(),
+            );
+            let v = compat::build_load(self.builder, ty_i32, p, cstr!());
+            llvm::core::LLVMSetAlignment(v, 4);
+            v
+        }
+    }
+
+    // return the verdict. eBPF programs return the action instead:
+    // `accept` if the verdict is non-zero, otherwise `reject`
+    fn build_ret(&mut self, v: LLVMValueRef) {
+        unsafe {
+            if self.options.ebpf.is_none() || self.skb_loads() {
+                llvm::core::LLVMBuildRet(self.builder, v);
+                return;
+            }
+            let accept = self.get_action_block(true);
+            let reject = self.get_action_block(false);
+            let ty_i32 = llvm::core::LLVMInt32TypeInContext(self.context);
+            let zero = llvm::core::LLVMConstInt(ty_i32, 0, 0);
+            let cond = llvm::core::LLVMBuildICmp(
+                self.builder,
+                llvm::LLVMIntPredicate::LLVMIntNE,
+                v,
+                zero,
+                cstr!(),
+            );
+            llvm::core::LLVMBuildCondBr(self.builder, cond, accept, reject);
+        }
+    }
+
+    // basic block which returns the action for accepted (or rejected) packets
+    fn get_action_block(&mut self, accept: bool) -> LLVMBasicBlockRef {
+        let name = if accept { "accept" } else { "reject" };
+        if let Some(&bb) = self.blocks.get(name) {
+            return bb;
+        }
+        let target = self.options.ebpf.clone().unwrap();
+        let action = if accept { &target.accept } else { &target.reject };
+        unsafe {
+            let current = llvm::core::LLVMGetInsertBlock(self.builder);
+            let bb = llvm::core::LLVMAppendBasicBlockInContext(
+                self.context,
+                self.get_function("main"),
+                format!("{}\0", name).as_ptr() as *const _,
+            );
+            llvm::core::LLVMPositionBuilderAtEnd(self.builder, bb);
+            let v = match *action {
+                Action::Redirect(ref map) => self.build_redirect(map),
+                _ => {
+                    let ty_i32 = llvm::core::LLVMInt32TypeInContext(self.context);
+                    let code = target.action_code(action).unwrap();
+                    llvm::core::LLVMConstInt(ty_i32, code as _, 0)
+                }
+            };
+            llvm::core::LLVMBuildRet(self.builder, v);
+            llvm::core::LLVMPositionBuilderAtEnd(self.builder, current);
+            self.blocks.insert(name.to_owned(), bb);
+            bb
+        }
+    }
+
+    // bpf_redirect_map(&map, key, 0), which returns XDP_REDIRECT
+    // (or XDP_ABORTED if the map has no entry for the key)
+    fn build_redirect(&mut self, map: &RedirectMap) -> LLVMValueRef {
+        let global = self.get_map(map);
+        unsafe {
+            let ty_i32 = llvm::core::LLVMInt32TypeInContext(self.context);
+            let ty_i64 = llvm::core::LLVMInt64TypeInContext(self.context);
+            let ty_i8 = llvm::core::LLVMInt8TypeInContext(self.context);
+            let ty_ptr = compat::pointer_type(self.context, ty_i8);
+            // helpers are called through their IDs as function pointers
+            let params = [ty_ptr, ty_i32, ty_i64];
+            let ty_helper = llvm::core::LLVMFunctionType(ty_i64, params.as_ptr() as *mut _, 3, 0);
+            let helper = llvm::core::LLVMConstIntToPtr(
+                llvm::core::LLVMConstInt(ty_i64, ebpf::BPF_FUNC_REDIRECT_MAP, 0),
+                compat::pointer_type(self.context, ty_helper),
+            );
+            let args = [
+                llvm::core::LLVMConstBitCast(global, ty_ptr),
+                llvm::core::LLVMConstInt(ty_i32, map.key as _, 0),
+                llvm::core::LLVMConstInt(ty_i64, 0, 0),
+            ];
+            let v = compat::build_call(self.builder, ty_helper, helper, &args, cstr!());
+            llvm::core::LLVMBuildTrunc(self.builder, v, ty_i32, cstr!())
+        }
+    }
+
+    // the map definition in the legacy "maps" section. its layout is struct bpf_elf_map
+    // of iproute2. libbpf 1.0 and later reject the section, so this is only used with
+    // `EbpfTarget::iproute2_maps`
+    fn get_map(&mut self, map: &RedirectMap) -> LLVMValueRef {
+        let key = format!("map.{}", map.name);
+        if let Some(&v) = self.values.get(&key) {
+            return v;
+        }
+        unsafe {
+            let ty_i32 = llvm::core::LLVMInt32TypeInContext(self.context);
+            // type, key size, value size, max entries, flags, id, pinning, inner id, inner idx
+            let mut fields: Vec<_> = [map.map_type.id(), 4, 4, map.max_entries, 0, 0, 0, 0, 0]
+                .iter()
+                .map(|&v| llvm::core::LLVMConstInt(ty_i32, v as _, 0))
+                .collect();
+            let init = llvm::core::LLVMConstArray(ty_i32, fields.as_mut_ptr(), fields.len() as _);
+            let name = std::ffi::CString::new(map.name.as_str()).unwrap();
+            let global =
+                llvm::core::LLVMAddGlobal(self.module, llvm::core::LLVMTypeOf(init), name.as_ptr());
+            llvm::core::LLVMSetInitializer(global, init);
+            llvm::core::LLVMSetSection(global, cstr!("maps"));
+            llvm::core::LLVMSetAlignment(global, 4);
+            self.values.insert(key, global);
+            global
+        }
+    }
+
+    // i64 cbpf.counters[2 * n], which is kept external through llvm.used so that it can be
+    // read after JIT compilation. counters[i] is the execution count of the i-th
+    // instruction and counters[n + i] is the number of times the i-th jump is taken
+    fn create_counters(&mut self, n: usize) {
+        unsafe {
+            let ty_i64 = llvm::core::LLVMInt64TypeInContext(self.context);
+            let ty = llvm::core::LLVMArrayType(ty_i64, 2 * n as u32);
+            let counters = llvm::core::LLVMAddGlobal(self.module, ty, cstr!("cbpf.counters"));
+            llvm::core::LLVMSetInitializer(counters, llvm::core::LLVMConstNull(ty));
+
+            let ty_i8_ptr = compat::pointer_type(
+                self.context,
+                llvm::core::LLVMInt8TypeInContext(self.context),
+            );
+            let mut used = [llvm::core::LLVMConstBitCast(counters, ty_i8_ptr)];
+            let ty_used = llvm::core::LLVMArrayType(ty_i8_ptr, 1);
+            let llvm_used = llvm::core::LLVMAddGlobal(self.module, ty_used, cstr!("llvm.used"));
+            llvm::core::LLVMSetInitializer(
+                llvm_used,
+                llvm::core::LLVMConstArray(ty_i8_ptr, used.as_mut_ptr(), 1),
+            );
+            llvm::core::LLVMSetLinkage(llvm_used, llvm::LLVMLinkage::LLVMAppendingLinkage);
+            llvm::core::LLVMSetSection(llvm_used, cstr!("llvm.metadata"));
+
+            self.values.insert("counters".to_owned(), counters);
+        }
+    }
+
+    // counters[idx] += v
+    fn build_count(&self, idx: usize, v: LLVMValueRef) {
+        unsafe {
+            let ty_i32 = llvm::core::LLVMInt32TypeInContext(self.context);
+            let ty_i64 = llvm::core::LLVMInt64TypeInContext(self.context);
+            let ty = llvm::core::LLVMArrayType(ty_i64, 2 * self.num_insns as u32);
+            let indices = [
+                llvm::core::LLVMConstInt(ty_i32, 0, 0),
+                llvm::core::LLVMConstInt(ty_i32, idx as _, 0),
+            ];
+            let counter = compat::build_gep(
+                self.builder,
+                ty,
+                self.get_value("counters"),
+                &indices,
+                cstr!("counter"),
+            );
+            let v = llvm::core::LLVMBuildZExt(self.builder, v, ty_i64, cstr!());
+            llvm::core::LLVMBuildAtomicRMW(
+                self.builder,
+                llvm::LLVMAtomicRMWBinOp::LLVMAtomicRMWBinOpAdd,
+                counter,
+                v,
+                llvm::LLVMAtomicOrdering::LLVMAtomicOrderingMonotonic,
+                0,
+            );
+        }
+    }
+
+    fn read_counters(&self, offset: usize) -> Option<Vec<u64>> {
+        self.counters.map(|p| {
+            (offset..offset + self.num_insns)
+                .map(|i| unsafe { ptr::read_volatile(p.offset(i as isize)) })
+                .collect()
+        })
+    }
+
+    // execution count of each instruction if the program is JIT-compiled with `profile`
+    pub fn counters(&self) -> Option<Vec<u64>> {
+        self.read_counters(0)
+    }
+
+    // number of times each conditional jump is taken
+    pub fn taken_counters(&self) -> Option<Vec<u64>> {
+        self.read_counters(self.num_insns)
+    }
+
+    pub fn reset_counters(&self) {
+        if let Some(p) = self.counters {
+            for i in 0..2 * self.num_insns {
+                unsafe {
+                    ptr::write_volatile(p.offset(i as isize), 0);
+                }
+            }
+        }
+    }
+
+    fn declare_trace_hook(&mut self) {
+        unsafe {
+            let ty_i32 = llvm::core::LLVMInt32TypeInContext(self.context);
+            let ty_void = llvm::core::LLVMVoidTypeInContext(self.context);
+            let params = [ty_i32, ty_i32, ty_i32];
+            let ty_function =
+                llvm::core::LLVMFunctionType(ty_void, params.as_ptr() as *mut _, 3, 0);
+            let name = std::ffi::CString::new(trace::HOOK_NAME).unwrap();
+            let hook = llvm::core::LLVMAddFunction(self.module, name.as_ptr(), ty_function);
+            // not in self.functions, which are made private by optimize_lto()
+            self.values.insert("trace_hook".to_owned(), hook);
+        }
+    }
+
+    // !prof !{!"branch_weights", i32 w0, i32 w1, ...}
+    fn set_branch_weights(&self, inst: LLVMValueRef, weights: &[u64]) {
+        // weights are 32-bit, so scale them down if needed
+        let max = weights.iter().cloned().max().unwrap_or(0);
+        let scale = max / u32::max_value() as u64 + 1;
+        unsafe {
+            let ty_i32 = llvm::core::LLVMInt32TypeInContext(self.context);
+            let mut ops = vec![
+                llvm::core::LLVMMDStringInContext(self.context, cstr!("branch_weights"), 14),
+            ];
+            for &w in weights {
+                ops.push(llvm::core::LLVMConstInt(ty_i32, w / scale, 0));
+            }
+            let node =
+                llvm::core::LLVMMDNodeInContext(self.context, ops.as_mut_ptr(), ops.len() as _);
+            let kind = llvm::core::LLVMGetMDKindIDInContext(self.context, cstr!("prof"), 4);
+            llvm::core::LLVMSetMetadata(inst, kind, node);
         }
     }
 
@@ -178,14 +814,113 @@ impl Converter {
         self.verify_module(self.module)
     }
 
+    // make the functions but main invisible from other modules so that
+    // multiple filters can be linked together
+    fn internalize_helpers(&self) {
+        // walk the module since the optimizer may have deleted some of self.functions
+        unsafe {
+            let main = self.get_function("main");
+            let mut f = llvm::core::LLVMGetFirstFunction(self.module);
+            while !f.is_null() {
+                if f != main && llvm::core::LLVMIsDeclaration(f) == 0 {
+                    llvm::core::LLVMSetLinkage(f, llvm::LLVMLinkage::LLVMInternalLinkage);
+                }
+                f = llvm::core::LLVMGetNextFunction(f);
+            }
+        }
+    }
+
+    // write an object file for the host, which defines the converted program as
+    // `uint32_t <symbol>(const uint8_t *pkt, uint32_t len)`,
+    // or an eBPF object whose program is <symbol> if `Options::ebpf` is set
+    pub fn emit_object(&mut self, symbol: &str, path: &str) -> Result<(), String> {
+        use llvm::target_machine::*;
+
+        let tm = match self.options.ebpf {
+            Some(ref target) => target.target_machine()?,
+            None => aot::target_machine(
+                LLVMRelocMode::LLVMRelocPIC,
+                LLVMCodeModel::LLVMCodeModelDefault,
+            )?,
+        };
+        self.finish_optimization()?;
+        self.write_object(tm, symbol, path)
+    }
+
+    // write a relocatable WebAssembly object (wasm32-unknown-unknown) which exports the
+    // converted program as `<symbol>(ptr, len) -> i32`. the object imports its memory
+    // (env.__linear_memory) until it is linked by wasm-ld
+    pub fn emit_wasm_object(&mut self, symbol: &str, path: &str) -> Result<(), String> {
+        if self.options.ebpf.is_some() {
+            return Err("the program is converted for eBPF".to_owned());
+        }
+        self.finish_optimization()?;
+        let tm = aot::wasm_target_machine()?;
+        let name = std::ffi::CString::new(symbol).unwrap();
+        unsafe {
+            llvm::core::LLVMAddTargetDependentFunctionAttr(
+                self.get_function("main"),
+                cstr!("wasm-export-name"),
+                name.as_ptr(),
+            );
+        }
+        self.write_object(tm, symbol, path)
+    }
+
+    fn write_object(
+        &mut self,
+        tm: llvm::target_machine::LLVMTargetMachineRef,
+        symbol: &str,
+        path: &str,
+    ) -> Result<(), String> {
+        use llvm::target_machine::*;
+
+        unsafe {
+            self.internalize_helpers();
+            let name = std::ffi::CString::new(symbol).unwrap();
+            llvm::core::LLVMSetValueName(self.get_function("main"), name.as_ptr());
+            self.symbol = symbol.to_owned();
+
+            let triple = LLVMGetTargetMachineTriple(tm);
+            llvm::core::LLVMSetTarget(self.module, triple);
+            llvm::core::LLVMDisposeMessage(triple);
+            let layout = LLVMCreateTargetDataLayout(tm);
+            compat::LLVMSetModuleDataLayout(self.module, layout);
+            llvm::target::LLVMDisposeTargetData(layout);
+
+            let path = std::ffi::CString::new(path).unwrap();
+            let mut err_msg = ptr::null_mut();
+            let result = LLVMTargetMachineEmitToFile(
+                tm,
+                self.module,
+                path.as_ptr() as *mut _,
+                LLVMCodeGenFileType::LLVMObjectFile,
+                &mut err_msg,
+            );
+            LLVMDisposeTargetMachine(tm);
+            if result != 0 {
+                let err = std::ffi::CStr::from_ptr(err_msg)
+                    .to_string_lossy()
+                    .into_owned();
+                llvm::core::LLVMDisposeMessage(err_msg);
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
     // for debug
-    pub fn dump_module(&self) {
+    pub fn dump_module(&mut self) {
+        self.finish_optimization()
+            .expect("the optimization of a cached program failed");
         unsafe {
             llvm::core::LLVMDumpModule(self.module);
         }
     }
 
-    pub fn get_ir(&self) -> String {
+    pub fn get_ir(&mut self) -> String {
+        self.finish_optimization()
+            .expect("the optimization of a cached program failed");
         unsafe {
             std::ffi::CStr::from_ptr(llvm::core::LLVMPrintModuleToString(self.module))
                 .to_string_lossy()
@@ -193,107 +928,534 @@ impl Converter {
         }
     }
 
-    // create basic block in advance
-    fn create_basic_blocks(&mut self, num: usize) -> Vec<LLVMBasicBlockRef> {
+    // render the control flow graph of the (optimized) main function in DOT
+    pub fn to_dot(&mut self) -> String {
+        self.finish_optimization()
+            .expect("the optimization of a cached program failed");
+        let mut nodes = String::new();
+        let mut edges = String::new();
+        unsafe {
+            let main = self.get_function("main");
+            let mut ids = HashMap::new();
+            let mut bb = llvm::core::LLVMGetFirstBasicBlock(main);
+            while !bb.is_null() {
+                let id = ids.len();
+                ids.insert(bb, id);
+                bb = llvm::core::LLVMGetNextBasicBlock(bb);
+            }
+
+            let mut bb = llvm::core::LLVMGetFirstBasicBlock(main);
+            while !bb.is_null() {
+                let id = ids[&bb];
+                let name = std::ffi::CStr::from_ptr(llvm::core::LLVMGetBasicBlockName(bb))
+                    .to_string_lossy()
+                    .into_owned();
+                let mut label = format!("{}:\\l", dot::escape(&name));
+                let mut inst = llvm::core::LLVMGetFirstInstruction(bb);
+                while !inst.is_null() {
+                    let s = llvm::core::LLVMPrintValueToString(inst);
+                    let text = std::ffi::CStr::from_ptr(s).to_string_lossy().into_owned();
+                    llvm::core::LLVMDisposeMessage(s);
+                    label += &format!("{}\\l", dot::escape(text.trim()));
+                    inst = llvm::core::LLVMGetNextInstruction(inst);
+                }
+                nodes += &format!("    bb{} [label=\"{}\"];\n", id, label);
+
+                let term = llvm::core::LLVMGetBasicBlockTerminator(bb);
+                if !term.is_null() {
+                    let opcode = llvm::core::LLVMGetInstructionOpcode(term);
+                    for i in 0..llvm::core::LLVMGetNumSuccessors(term) {
+                        let succ = llvm::core::LLVMGetSuccessor(term, i);
+                        let attr = match opcode {
+                            LLVMOpcode::LLVMBr if llvm::core::LLVMIsConditional(term) != 0 => {
+                                if i == 0 {
+                                    " [label=\"true\"]".to_owned()
+                                } else {
+                                    " [label=\"false\", style=dashed]".to_owned()
+                                }
+                            }
+                            // operands of switch: cond, default, (value, dest)...
+                            LLVMOpcode::LLVMSwitch => if i == 0 {
+                                " [label=\"default\"]".to_owned()
+                            } else {
+                                let v = llvm::core::LLVMGetOperand(term, i * 2);
+                                format!(
+                                    " [label=\"{}\"]",
+                                    llvm::core::LLVMConstIntGetZExtValue(v)
+                                )
+                            },
+                            _ => "".to_owned(),
+                        };
+                        edges += &format!("    bb{} -> bb{}{};\n", id, ids[&succ], attr);
+                    }
+                }
+                bb = llvm::core::LLVMGetNextBasicBlock(bb);
+            }
+        }
+
+        format!(
+            "digraph main {{\n    node [shape=box, fontname=\"monospace\"];\n{}{}}}\n",
+            nodes, edges
+        )
+    }
+
+    // create basic block in advance.
+    // bbs[i] is the basic block which the i-th instruction belongs to
+    fn create_basic_blocks(&mut self, insns: &[BpfInsn]) -> Vec<LLVMBasicBlockRef> {
         let mut bbs = vec![];
-        for i in 0..num {
-            let bb = unsafe {
-                llvm::core::LLVMAppendBasicBlockInContext(
-                    self.context,
-                    self.get_function("main"),
-                    format!("insn.{}\0", i).as_ptr() as *const _,
-                )
+        for (i, leader) in cfg::leaders(insns).into_iter().enumerate() {
+            let bb = if leader {
+                unsafe {
+                    llvm::core::LLVMAppendBasicBlockInContext(
+                        self.context,
+                        self.get_function("main"),
+                        format!("insn.{}\0", i).as_ptr() as *const _,
+                    )
+                }
+            } else {
+                bbs[i - 1]
             };
             bbs.push(bb);
         }
         bbs
     }
 
+    // basic block which returns 0 when a packet access is out of bounds or X of div/mod is 0
+    fn get_oob_block(&mut self) -> LLVMBasicBlockRef {
+        if let Some(&bb) = self.blocks.get("oob") {
+            return bb;
+        }
+        unsafe {
+            let current = llvm::core::LLVMGetInsertBlock(self.builder);
+            let bb = llvm::core::LLVMAppendBasicBlockInContext(
+                self.context,
+                self.get_function("main"),
+                cstr!("oob"),
+            );
+            llvm::core::LLVMPositionBuilderAtEnd(self.builder, bb);
+            let ty_i32 = llvm::core::LLVMInt32TypeInContext(self.context);
+            let zero = llvm::core::LLVMConstInt(ty_i32, 0, 0);
+            self.build_ret(zero);
+            llvm::core::LLVMPositionBuilderAtEnd(self.builder, current);
+            self.blocks.insert("oob".to_owned(), bb);
+            bb
+        }
+    }
+
+    // shifts by 32 or more are poison in LLVM, but they make 0 in cBPF
+    fn build_shift_result(&self, shifted: LLVMValueRef, amount: LLVMValueRef) -> LLVMValueRef {
+        unsafe {
+            let ty_i32 = llvm::core::LLVMInt32TypeInContext(self.context);
+            let zero = llvm::core::LLVMConstInt(ty_i32, 0, 0);
+            let cond = llvm::core::LLVMBuildICmp(
+                self.builder,
+                llvm::LLVMIntPredicate::LLVMIntUGE,
+                amount,
+                llvm::core::LLVMConstInt(ty_i32, 32, 0),
+                cstr!(),
+            );
+            llvm::core::LLVMBuildSelect(self.builder, cond, zero, shifted, cstr!())
+        }
+    }
+
+    // return 0 if end (i64) > len
+    fn build_bounds_check(&mut self, end: LLVMValueRef, idx: usize) {
+        let cond = unsafe {
+            if self.options.ebpf.is_some() {
+                // data + end > data_end, which tells the verifier the accessible range
+                let ty_i8 = llvm::core::LLVMInt8TypeInContext(self.context);
+                let p = compat::build_gep(
+                    self.builder,
+                    ty_i8,
+                    self.get_value("data"),
+                    &[end],
+                    cstr!(),
+                );
+                llvm::core::LLVMBuildICmp(
+                    self.builder,
+                    llvm::LLVMIntPredicate::LLVMIntUGT,
+                    p,
+                    self.get_value("data_end"),
+                    cstr!(),
+                )
+            } else {
+                let ty_i64 = llvm::core::LLVMInt64TypeInContext(self.context);
+                let len = self.get_value("len");
+                let len = llvm::core::LLVMBuildZExt(self.builder, len, ty_i64, cstr!());
+                llvm::core::LLVMBuildICmp(
+                    self.builder,
+                    llvm::LLVMIntPredicate::LLVMIntUGT,
+                    end,
+                    len,
+                    cstr!(),
+                )
+            }
+        };
+        self.build_branch_to_oob(cond, idx);
+    }
+
+    fn build_branch_to_oob(&mut self, cond: LLVMValueRef, idx: usize) {
+        let oob = self.get_oob_block();
+        unsafe {
+            let next = llvm::core::LLVMAppendBasicBlockInContext(
+                self.context,
+                self.get_function("main"),
+                format!("insn.{}.inbounds\0", idx).as_ptr() as *const _,
+            );
+            llvm::core::LLVMBuildCondBr(self.builder, cond, oob, next);
+            llvm::core::LLVMPositionBuilderAtEnd(self.builder, next);
+        }
+    }
+
+    // load data[offset] (offset is i32) in network byte order
+    fn build_load(&self, data: LLVMValueRef, offset: LLVMValueRef, size: u16) -> LLVMValueRef {
+        unsafe {
+            // eBPF programs cannot call the helper
+            if size == BPF_B && self.options.ebpf.is_none() {
+                let ldb = self.get_function("ldb");
+                return compat::build_call(
+                    self.builder,
+                    compat::function_type(ldb),
+                    ldb,
+                    &[data, offset],
+                    cstr!(),
+                );
+            }
+
+            let ty_i8 = llvm::core::LLVMInt8TypeInContext(self.context);
+            let ty_i64 = llvm::core::LLVMInt64TypeInContext(self.context);
+            let offset = llvm::core::LLVMBuildZExt(self.builder, offset, ty_i64, cstr!());
+            let p = compat::build_gep(self.builder, ty_i8, data, &[offset], cstr!());
+            self.build_load_ptr(p, size)
+        }
+    }
+
+    // LD_ABS/LD_IND of socket filters: skb[offset + off (i32)] in host order. the program
+    // returns 0 if it is out of bounds
+    fn build_skb_load(&self, skb: LLVMValueRef, off: LLVMValueRef, size: u16) -> LLVMValueRef {
+        unsafe {
+            let ty_i32 = llvm::core::LLVMInt32TypeInContext(self.context);
+            let ty_i64 = llvm::core::LLVMInt64TypeInContext(self.context);
+            let name = match size {
+                BPF_B => "llvm.bpf.load.byte",
+                BPF_H => "llvm.bpf.load.half",
+                _ => "llvm.bpf.load.word",
+            };
+            let offset = self.options.ebpf.as_ref().unwrap().offset;
+            let off = llvm::core::LLVMBuildZExt(self.builder, off, ty_i64, cstr!());
+            let off = llvm::core::LLVMBuildAdd(
+                self.builder,
+                off,
+                llvm::core::LLVMConstInt(ty_i64, offset as _, 0),
+                cstr!(),
+            );
+            let load = self.get_function(name);
+            let v = compat::build_call(
+                self.builder,
+                compat::function_type(load),
+                load,
+                &[skb, off],
+                cstr!(),
+            );
+            llvm::core::LLVMBuildTrunc(self.builder, v, ty_i32, cstr!())
+        }
+    }
+
+    // load *p (i8*) in network byte order
+    fn build_load_ptr(&self, p: LLVMValueRef, size: u16) -> LLVMValueRef {
+        unsafe {
+            let ty_i32 = llvm::core::LLVMInt32TypeInContext(self.context);
+            // single unaligned load + bswap
+            let bits = match size {
+                BPF_B => 8,
+                BPF_H => 16,
+                _ => 32,
+            };
+            let ty = llvm::core::LLVMIntTypeInContext(self.context, bits);
+            let p = llvm::core::LLVMBuildBitCast(
+                self.builder,
+                p,
+                compat::pointer_type(self.context, ty),
+                cstr!(),
+            );
+            let mut v = compat::build_load(self.builder, ty, p, cstr!());
+            llvm::core::LLVMSetAlignment(v, 1);
+            let little_endian = match self.options.ebpf {
+                Some(ref target) => !target.big_endian,
+                None => cfg!(target_endian = "little"),
+            };
+            if bits > 8 && little_endian {
+                let bswap = self.get_function(&format!("llvm.bswap.i{}", bits));
+                v = compat::build_call(
+                    self.builder,
+                    compat::function_type(bswap),
+                    bswap,
+                    &[v],
+                    cstr!(),
+                );
+            }
+            if bits < 32 {
+                v = llvm::core::LLVMBuildZExt(self.builder, v, ty_i32, cstr!());
+            }
+            v
+        }
+    }
+
+    // convert the program and return the (optimized) IR
     pub fn convert(&mut self, insns: &[BpfInsn], optimization: bool) -> Result<String, String> {
+        self.build(insns, optimization)?;
+        Ok(self.get_ir())
+    }
+
+    // convert the program without printing the IR. when the object is in the cache,
+    // the optimization is deferred until the module is needed (`get_ir()`, `to_dot()`,
+    // the emitters, or `jit_compile()` if the entry is gone), since `jit_compile()` loads
+    // the cached object
+    pub fn build(&mut self, insns: &[BpfInsn], optimization: bool) -> Result<(), String> {
+        // the same validation as the other backends
+        frontend::decode(insns)?;
+
+        if let Some(ref profile) = self.options.branch_profile {
+            if profile.counts.len() != insns.len() {
+                return Err("the branch profile is not for this program".to_owned());
+            }
+        }
+
+        if let Some(ref target) = self.options.ebpf {
+            if self.options.debug_info
+                || self.options.perf_map
+                || self.options.gdb_jit
+                || self.options.profile
+                || self.options.trace
+                || self.options.cache_dir.is_some()
+            {
+                return Err("debug info, profiling, tracing and JIT options are not \
+                            available for eBPF output"
+                    .to_owned());
+            }
+            target.check(insns)?;
+        }
+
         // setup
-        self.create_main();
-        self.link_util();
+        if self.options.debug_info {
+            self.create_main_with_debug_info(insns);
+        } else {
+            self.create_main();
+        }
+        if self.options.ebpf.is_none() {
+            self.link_util();
+        }
+        self.declare_intrinsics();
         self.emit_prolog();
-        let bbs = self.create_basic_blocks(insns.len());
+        self.num_insns = insns.len();
+        if self.options.profile {
+            self.create_counters(insns.len());
+        }
+        if self.options.trace {
+            self.declare_trace_hook();
+        }
+        let bbs = self.create_basic_blocks(insns);
+        // the verifier forgets the checked range of a packet pointer once a variable
+        // is added to it, so each load of eBPF programs is checked just before it.
+        // traces and counters also need the instructions before the failing load to run
+        let bounds =
+            if self.options.trace || self.options.profile || self.options.ebpf.is_some() {
+                bounds::unhoisted(insns)
+            } else {
+                bounds::plan(insns)
+            };
+        if self.options.perf_map || self.options.gdb_jit {
+            self.symbol = jit::symbol_name(&self.options.name, insns);
+        }
+
+        if let Some(ref dir) = self.options.cache_dir {
+            objcache::create_dir(dir)?;
+            let description = objcache::describe(insns, &self.options, optimization);
+            self.cache_path = Some(objcache::path(dir, &objcache::key(&description)));
+            self.cache_description = description;
+        }
 
         // convert each instruction
-        for (i, insn) in insns.iter().enumerate() {
-            self.convert_insn(*insn, &bbs, i);
+        let chained = jeq_chain_members(insns);
+        for i in 0..insns.len() {
+            self.convert_insn(insns, &bbs, &bounds, &chained, i);
         }
 
         if optimization {
-            self.optimize();
+            if self.is_cached() {
+                self.pending_optimization = true;
+            } else {
+                self.optimize()?;
+            }
         }
 
         if self.verify_main() {
             return Err("Verify Failed".to_owned());
         }
-        Ok(self.get_ir())
+        Ok(())
+    }
+
+    // run the optimization deferred by `build()`
+    fn finish_optimization(&mut self) -> Result<(), String> {
+        if self.pending_optimization {
+            self.pending_optimization = false;
+            self.optimize()?;
+        }
+        Ok(())
+    }
+
+    // whether the object of the converted program is in the cache
+    pub fn is_cached(&self) -> bool {
+        match self.cache_path {
+            Some(ref path) => objcache::exists(path, &self.cache_description),
+            None => false,
+        }
+    }
+
+    // (pipeline, time) of each pipeline run by the optimization, in order
+    pub fn pass_timings(&self) -> &[(String, Duration)] {
+        &self.pass_timings
+    }
+
+    // jeq chains are not converted to a switch when profiling or tracing so that every jump is
+    // counted and recorded. only the head of a chain is a switch; the other members are
+    // converted as plain jumps, which are used when they are jumped to from elsewhere
+    fn switch_chain(&self, insns: &[BpfInsn], chained: &[bool], idx: usize) -> Option<JeqChain> {
+        if self.options.profile || self.options.trace || chained[idx] {
+            None
+        } else {
+            jeq_chain(insns, idx)
+        }
     }
 
     // should return Result
-    fn convert_insn(&mut self, insn: BpfInsn, bbs: &Vec<LLVMBasicBlockRef>, idx: usize) {
+    fn convert_insn(
+        &mut self,
+        insns: &[BpfInsn],
+        bbs: &Vec<LLVMBasicBlockRef>,
+        bounds: &BoundsPlan,
+        chained: &[bool],
+        idx: usize,
+    ) {
+        let insn = insns[idx];
         // we load A and X regardless of instructions, since they are basicaly used
         let (addr_a, addr_x, addr_mem, a, x, k, data, ty_i32) = unsafe {
-            // create branch from the last bb if the last instruction is not a terminator instruction
-            if idx >= 1 {
-                let inst = llvm::core::LLVMGetLastInstruction(bbs[idx - 1]);
-                if llvm::core::LLVMIsATerminatorInst(inst) as i32 == 0 {
+            if idx == 0 || bbs[idx] != bbs[idx - 1] {
+                // create branch from the last bb (or entry) if the last instruction is not
+                // a terminator instruction
+                let current = llvm::core::LLVMGetInsertBlock(self.builder);
+                let inst = llvm::core::LLVMGetLastInstruction(current);
+                if inst.is_null() || llvm::core::LLVMIsATerminatorInst(inst).is_null() {
                     llvm::core::LLVMBuildBr(self.builder, bbs[idx]);
                 }
-            } else {
-                // br from entry
-                llvm::core::LLVMBuildBr(self.builder, bbs[idx]);
+                llvm::core::LLVMPositionBuilderAtEnd(self.builder, bbs[idx]);
+            }
+            if let Some(&loc) = self.debug_locations.get(idx) {
+                llvm::core::LLVMSetCurrentDebugLocation(self.builder, loc);
+            }
+            if self.options.profile {
+                let ty_i1 = llvm::core::LLVMInt1TypeInContext(self.context);
+                self.build_count(idx, llvm::core::LLVMConstInt(ty_i1, 1, 0));
             }
-            llvm::core::LLVMPositionBuilderAtEnd(self.builder, bbs[idx]);
             let ty_i32 = llvm::core::LLVMInt32TypeInContext(self.context);
             let addr_a = self.get_value("A");
             let addr_x = self.get_value("X");
             let addr_mem = self.get_value("MEM");
-            let a = llvm::core::LLVMBuildLoad(self.builder, addr_a, cstr!("A"));
-            let x = llvm::core::LLVMBuildLoad(self.builder, addr_x, cstr!("X"));
+            let a = compat::build_load(self.builder, ty_i32, addr_a, cstr!("A"));
+            let x = compat::build_load(self.builder, ty_i32, addr_x, cstr!("X"));
             let k = llvm::core::LLVMConstInt(ty_i32, insn.k as _, 1);
-            let data = llvm::core::LLVMGetParam(self.get_function("main"), 0);
+            let data = self.get_value("data");
+            if self.options.trace {
+                let hook = self.get_value("trace_hook");
+                let args = [llvm::core::LLVMConstInt(ty_i32, idx as _, 0), a, x];
+                compat::build_call(
+                    self.builder,
+                    compat::function_type(hook),
+                    hook,
+                    &args,
+                    cstr!(),
+                );
+            }
             (addr_a, addr_x, addr_mem, a, x, k, data, ty_i32)
         };
 
         match bpf_class(insn.code) {
             BPF_RET => match bpf_rval(insn.code) {
-                BPF_A => unsafe {
-                    llvm::core::LLVMBuildRet(self.builder, a);
-                },
-                BPF_K => unsafe {
-                    llvm::core::LLVMBuildRet(self.builder, k);
-                },
+                BPF_A => self.build_ret(a),
+                BPF_K => self.build_ret(k),
                 _ => panic!("InvalidRval"),
             },
 
             BPF_LD => match (bpf_size(insn.code), bpf_mode(insn.code)) {
                 // A = data[k(+x)]
-                (_, n @ BPF_ABS) | (_, n @ BPF_IND) => unsafe {
-                    let v = if n == BPF_IND {
+                (size, n @ BPF_ABS) | (size, n @ BPF_IND) => unsafe {
+                    let ty_i64 = llvm::core::LLVMInt64TypeInContext(self.context);
+                    if self.skb_loads() {
+                        let off = if n == BPF_IND {
+                            llvm::core::LLVMBuildAdd(self.builder, x, k, cstr!())
+                        } else {
+                            k
+                        };
+                        let v = self.build_skb_load(data, off, size);
+                        llvm::core::LLVMBuildStore(self.builder, v, addr_a);
+                        return;
+                    }
+                    if let Some(end) = bounds.guards[idx] {
+                        let end = llvm::core::LLVMConstInt(ty_i64, end, 0);
+                        self.build_bounds_check(end, idx);
+                    }
+                    let v = if n == BPF_IND && self.options.ebpf.is_some() {
+                        // p = data + (x + k); p + size > data_end
+                        // x + k is limited to 16 bits so that the verifier can bound p
+                        let off = llvm::core::LLVMBuildZExt(self.builder, x, ty_i64, cstr!());
+                        let k64 = llvm::core::LLVMConstInt(ty_i64, insn.k as _, 0);
+                        let off = llvm::core::LLVMBuildAdd(self.builder, off, k64, cstr!());
+                        let cond = llvm::core::LLVMBuildICmp(
+                            self.builder,
+                            llvm::LLVMIntPredicate::LLVMIntUGT,
+                            off,
+                            llvm::core::LLVMConstInt(ty_i64, 0xffff, 0),
+                            cstr!(),
+                        );
+                        self.build_branch_to_oob(cond, idx);
+                        let ty_i8 = llvm::core::LLVMInt8TypeInContext(self.context);
+                        let p = compat::build_gep(self.builder, ty_i8, data, &[off], cstr!());
+                        let size64 = llvm::core::LLVMConstInt(ty_i64, load_size(size), 0);
+                        let end = compat::build_gep(self.builder, ty_i8, p, &[size64], cstr!());
+                        let cond = llvm::core::LLVMBuildICmp(
+                            self.builder,
+                            llvm::LLVMIntPredicate::LLVMIntUGT,
+                            end,
+                            self.get_value("data_end"),
+                            cstr!(),
+                        );
+                        self.build_branch_to_oob(cond, idx);
+                        let v = self.build_load_ptr(p, size);
+                        llvm::core::LLVMBuildStore(self.builder, v, addr_a);
+                        return;
+                    } else if n == BPF_IND {
+                        if bounds.ind_checks[idx] {
+                            // x + k + size > len
+                            let end = llvm::core::LLVMBuildZExt(self.builder, x, ty_i64, cstr!());
+                            let k_size = llvm::core::LLVMConstInt(
+                                ty_i64,
+                                insn.k as u64 + load_size(size),
+                                0,
+                            );
+                            let end = llvm::core::LLVMBuildAdd(self.builder, end, k_size, cstr!());
+                            self.build_bounds_check(end, idx);
+                        }
                         llvm::core::LLVMBuildAdd(self.builder, x, k, cstr!())
                     } else {
                         k
                     };
-                    let f = match bpf_size(insn.code) {
-                        BPF_W => self.get_function("ldw"),
-                        BPF_H => self.get_function("ldh"),
-                        BPF_B => self.get_function("ldb"),
-                        _ => unreachable!(),
-                    };
-                    let v = llvm::core::LLVMBuildCall(
-                        self.builder,
-                        f,
-                        [data, v].as_ptr() as *mut _,
-                        2,
-                        cstr!(),
-                    );
+                    let v = self.build_load(data, v, size);
                     llvm::core::LLVMBuildStore(self.builder, v, addr_a);
                 },
-                (BPF_W, BPF_LEN) => {
-                    panic!("not supported");
-                }
+                (BPF_W, BPF_LEN) => unsafe {
+                    let len = self.get_value("len");
+                    llvm::core::LLVMBuildStore(self.builder, len, addr_a);
+                },
                 (BPF_W, BPF_IMM) => unsafe {
                     // A = insn.k
                     llvm::core::LLVMBuildStore(self.builder, k, addr_a);
@@ -301,32 +1463,47 @@ impl Converter {
                 (BPF_W, BPF_MEM) => unsafe {
                     // A = mem[k];
                     let idx = k;
-                    let p = llvm::core::LLVMBuildInBoundsGEP(
-                        self.builder,
-                        addr_mem,
-                        [idx].as_ptr() as *mut _,
-                        1,
-                        cstr!(),
-                    );
-                    let v = llvm::core::LLVMBuildLoad(self.builder, p, cstr!());
+                    let p = compat::build_gep(self.builder, ty_i32, addr_mem, &[idx], cstr!());
+                    let v = compat::build_load(self.builder, ty_i32, p, cstr!());
                     llvm::core::LLVMBuildStore(self.builder, v, addr_a);
                 },
                 _ => panic!("InvalidLdInstruction"),
             },
 
             BPF_LDX => match (bpf_size(insn.code), bpf_mode(insn.code)) {
-                (BPF_W, BPF_LEN) => {
-                    panic!("not supported");
-                }
+                (BPF_W, BPF_LEN) => unsafe {
+                    let len = self.get_value("len");
+                    llvm::core::LLVMBuildStore(self.builder, len, addr_x);
+                },
                 // X = (data[k] & 0xf) << 2
                 (BPF_B, BPF_MSH) => unsafe {
-                    let v = llvm::core::LLVMBuildCall(
-                        self.builder,
-                        self.get_function("msh"),
-                        [data, k].as_ptr() as *mut _,
-                        2,
-                        cstr!(),
-                    );
+                    if let Some(end) = bounds.guards[idx] {
+                        if !self.skb_loads() {
+                            let ty_i64 = llvm::core::LLVMInt64TypeInContext(self.context);
+                            let end = llvm::core::LLVMConstInt(ty_i64, end, 0);
+                            self.build_bounds_check(end, idx);
+                        }
+                    }
+                    let v = if self.options.ebpf.is_some() {
+                        let v = if self.skb_loads() {
+                            self.build_skb_load(data, k, BPF_B)
+                        } else {
+                            self.build_load(data, k, BPF_B)
+                        };
+                        let mask = llvm::core::LLVMConstInt(ty_i32, 0xf, 0);
+                        let v = llvm::core::LLVMBuildAnd(self.builder, v, mask, cstr!());
+                        let two = llvm::core::LLVMConstInt(ty_i32, 2, 0);
+                        llvm::core::LLVMBuildShl(self.builder, v, two, cstr!())
+                    } else {
+                        let msh = self.get_function("msh");
+                        compat::build_call(
+                            self.builder,
+                            compat::function_type(msh),
+                            msh,
+                            &[data, k],
+                            cstr!(),
+                        )
+                    };
                     llvm::core::LLVMBuildStore(self.builder, v, addr_x);
                 },
                 // X = insn.k
@@ -336,14 +1513,8 @@ impl Converter {
                 // X = mem[k]
                 (BPF_W, BPF_MEM) => unsafe {
                     let idx = k;
-                    let p = llvm::core::LLVMBuildInBoundsGEP(
-                        self.builder,
-                        addr_mem,
-                        [idx].as_ptr() as *mut _,
-                        1,
-                        cstr!(),
-                    );
-                    let v = llvm::core::LLVMBuildLoad(self.builder, p, cstr!());
+                    let p = compat::build_gep(self.builder, ty_i32, addr_mem, &[idx], cstr!());
+                    let v = compat::build_load(self.builder, ty_i32, p, cstr!());
                     llvm::core::LLVMBuildStore(self.builder, v, addr_x);
                 },
                 _ => panic!("InvalidLdInstruction"),
@@ -352,13 +1523,7 @@ impl Converter {
             n @ BPF_ST | n @ BPF_STX => unsafe {
                 // mem[k] = a or x
                 let idx = k;
-                let p = llvm::core::LLVMBuildInBoundsGEP(
-                    self.builder,
-                    addr_mem,
-                    [idx].as_ptr() as *mut _,
-                    1,
-                    cstr!(),
-                );
+                let p = compat::build_gep(self.builder, ty_i32, addr_mem, &[idx], cstr!());
                 let v = if n == BPF_ST { a } else { x };
                 llvm::core::LLVMBuildStore(self.builder, v, p);
             },
@@ -367,6 +1532,26 @@ impl Converter {
                 unsafe {
                     llvm::core::LLVMBuildBr(self.builder, bbs[insn.k as usize + idx + 1]);
                 }
+            } else if let Some(chain) = self.switch_chain(insns, chained, idx) {
+                // jeq #k1, L1; jeq #k2, L2; ... => switch A [k1: L1, k2: L2, ...]
+                unsafe {
+                    let switch = llvm::core::LLVMBuildSwitch(
+                        self.builder,
+                        a,
+                        bbs[chain.default],
+                        chain.cases.len() as _,
+                    );
+                    for &(k, target, _) in &chain.cases {
+                        let k = llvm::core::LLVMConstInt(ty_i32, k as _, 0);
+                        llvm::core::LLVMAddCase(switch, k, bbs[target]);
+                    }
+                    if let Some(ref profile) = self.options.branch_profile {
+                        // default is the false branch of the last jeq
+                        let mut weights = vec![profile.counts[chain.last].1];
+                        weights.extend(chain.cases.iter().map(|&(_, _, i)| profile.counts[i].0));
+                        self.set_branch_weights(switch, &weights);
+                    }
+                }
             } else {
                 let jt_bb = bbs[insn.jt as usize + idx + 1];
                 let jf_bb = bbs[insn.jf as usize + idx + 1];
@@ -378,13 +1563,13 @@ impl Converter {
                 };
 
                 let cond = if bpf_op(insn.code) == BPF_JSET {
-                    // a & src > 0
+                    // a & src != 0
                     unsafe {
                         let a = llvm::core::LLVMBuildAnd(self.builder, a, src, cstr!());
                         let zero = llvm::core::LLVMConstInt(ty_i32, 0, 1);
                         llvm::core::LLVMBuildICmp(
                             self.builder,
-                            llvm::LLVMIntPredicate::LLVMIntSGT,
+                            llvm::LLVMIntPredicate::LLVMIntNE,
                             a,
                             zero,
                             cstr!(),
@@ -392,8 +1577,9 @@ impl Converter {
                     }
                 } else {
                     let pred = match bpf_op(insn.code) {
-                        BPF_JGT => llvm::LLVMIntPredicate::LLVMIntSGT,
-                        BPF_JGE => llvm::LLVMIntPredicate::LLVMIntSGE,
+                        // A and X are unsigned
+                        BPF_JGT => llvm::LLVMIntPredicate::LLVMIntUGT,
+                        BPF_JGE => llvm::LLVMIntPredicate::LLVMIntUGE,
                         BPF_JEQ => llvm::LLVMIntPredicate::LLVMIntEQ,
                         _ => panic!("InvalidJmpCondition"),
                     };
@@ -401,7 +1587,14 @@ impl Converter {
                     unsafe { llvm::core::LLVMBuildICmp(self.builder, pred, a, src, cstr!()) }
                 };
                 unsafe {
-                    llvm::core::LLVMBuildCondBr(self.builder, cond, jt_bb, jf_bb);
+                    if self.options.profile {
+                        self.build_count(self.num_insns + idx, cond);
+                    }
+                    let br = llvm::core::LLVMBuildCondBr(self.builder, cond, jt_bb, jf_bb);
+                    if let Some(ref profile) = self.options.branch_profile {
+                        let (taken, not_taken) = profile.counts[idx];
+                        self.set_branch_weights(br, &[taken, not_taken]);
+                    }
                 }
             },
 
@@ -415,22 +1608,40 @@ impl Converter {
                         _ => panic!("InvalidSrc"),
                     };
 
-                    match bpf_op(insn.code) {
+                    let op = bpf_op(insn.code);
+                    // the filter returns 0 when dividing by 0 (K = 0 is folded)
+                    if op == BPF_DIV || op == BPF_MOD {
+                        let cond = unsafe {
+                            llvm::core::LLVMBuildICmp(
+                                self.builder,
+                                llvm::LLVMIntPredicate::LLVMIntEQ,
+                                v,
+                                llvm::core::LLVMConstInt(ty_i32, 0, 0),
+                                cstr!(),
+                            )
+                        };
+                        self.build_branch_to_oob(cond, idx);
+                    }
+                    match op {
                         BPF_ADD => unsafe { llvm::core::LLVMBuildAdd(self.builder, a, v, cstr!()) },
                         BPF_SUB => unsafe { llvm::core::LLVMBuildSub(self.builder, a, v, cstr!()) },
                         BPF_MUL => unsafe { llvm::core::LLVMBuildMul(self.builder, a, v, cstr!()) },
                         BPF_DIV => unsafe {
-                            llvm::core::LLVMBuildSDiv(self.builder, a, v, cstr!())
+                            llvm::core::LLVMBuildUDiv(self.builder, a, v, cstr!())
                         },
                         BPF_MOD => unsafe {
-                            llvm::core::LLVMBuildSRem(self.builder, a, v, cstr!())
+                            llvm::core::LLVMBuildURem(self.builder, a, v, cstr!())
                         },
                         BPF_AND => unsafe { llvm::core::LLVMBuildAnd(self.builder, a, v, cstr!()) },
                         BPF_OR => unsafe { llvm::core::LLVMBuildOr(self.builder, a, v, cstr!()) },
                         BPF_XOR => unsafe { llvm::core::LLVMBuildXor(self.builder, a, v, cstr!()) },
-                        BPF_LSH => unsafe { llvm::core::LLVMBuildShl(self.builder, a, v, cstr!()) },
+                        BPF_LSH => unsafe {
+                            let shl = llvm::core::LLVMBuildShl(self.builder, a, v, cstr!());
+                            self.build_shift_result(shl, v)
+                        },
                         BPF_RSH => unsafe {
-                            llvm::core::LLVMBuildAShr(self.builder, a, v, cstr!())
+                            let lshr = llvm::core::LLVMBuildLShr(self.builder, a, v, cstr!());
+                            self.build_shift_result(lshr, v)
                         },
                         _ => panic!("InvalidAluOp"),
                     }
@@ -453,19 +1664,43 @@ impl Converter {
         }
     }
 
-    // optimization
-    fn optimize(&mut self) {
-        self.optimize_module();
-        self.optimize_lto();
+    // optimization: `default<O2>` and `lto<O2>`, or only the pipeline of `Options::passes`
+    fn optimize(&mut self) -> Result<(), String> {
+        if let Some(pipeline) = self.options.passes.clone() {
+            // the helpers are private so that the pipeline can inline and remove them
+            self.make_helpers_private();
+            let start = Instant::now();
+            self.optimize_module(&pipeline)?;
+            self.pass_timings.push((pipeline, start.elapsed()));
+            return Ok(());
+        }
+
+        // LLVM 5 runs the passes of the legacy pass manager instead of the pipelines
+        let (o2, lto) = if cfg!(feature = "llvm-5") {
+            ("legacy-O2", "legacy-lto")
+        } else {
+            ("default<O2>", "lto<O2>")
+        };
+        let start = Instant::now();
+        self.optimize_module("default<O2>")?;
+        self.pass_timings.push((o2.to_owned(), start.elapsed()));
+
+        let start = Instant::now();
+        self.optimize_lto()?;
+        self.pass_timings.push((lto.to_owned(), start.elapsed()));
+        Ok(())
     }
 
-    // based on merthc (https://bitbucket.org/tari/merthc) codes
-    fn optimize_lto(&self) {
-        use llvm::transforms::pass_manager_builder::*;
+    fn optimize_lto(&self) -> Result<(), String> {
+        self.make_helpers_private();
+        self.run_lto_passes()
+    }
+
+    // mark all functions but main (and llvm intrinsics) as private to remove
+    fn make_helpers_private(&self) {
         unsafe {
-            // mark all functions but main (and llvm intrinsics) as private to remove
             for k in self.functions.keys() {
-                if k != "main" {
+                if k != "main" && !k.starts_with("llvm.") {
                     llvm::core::LLVMSetLinkage(
                         self.get_function(k),
                         llvm::LLVMLinkage::LLVMPrivateLinkage,
@@ -473,7 +1708,12 @@ impl Converter {
                 }
             }
         }
+    }
 
+    // based on merthc (https://bitbucket.org/tari/merthc) codes
+    #[cfg(feature = "llvm-5")]
+    fn run_lto_passes(&self) -> Result<(), String> {
+        use llvm::transforms::pass_manager_builder::*;
         unsafe {
             let pm = llvm::core::LLVMCreatePassManager();
             let pmb = LLVMPassManagerBuilderCreate();
@@ -488,11 +1728,17 @@ impl Converter {
             llvm::core::LLVMRunPassManager(pm, self.module);
             llvm::core::LLVMDisposePassManager(pm);
         }
+        Ok(())
     }
 
-    fn optimize_module(&self) {
+    // LLVM 5 has only the legacy pass manager, whose -O2 passes are `default<O2>`
+    #[cfg(feature = "llvm-5")]
+    fn optimize_module(&self, pipeline: &str) -> Result<(), String> {
         use llvm::transforms::pass_manager_builder::*;
 
+        if pipeline != "default<O2>" {
+            return Err("pass pipelines other than default<O2> require LLVM 15+".to_owned());
+        }
         unsafe {
             // Per clang and rustc, we want to use both kinds.
             let fpm = llvm::core::LLVMCreateFunctionPassManagerForModule(self.module);
@@ -523,18 +1769,66 @@ impl Converter {
             llvm::core::LLVMDisposePassManager(fpm);
             llvm::core::LLVMDisposePassManager(mpm);
         }
+        Ok(())
+    }
+
+    // the pass manager builder is removed since LLVM 17, so newer versions run
+    // the pipelines of the new pass manager which correspond to the passes above
+    #[cfg(not(feature = "llvm-5"))]
+    fn run_lto_passes(&self) -> Result<(), String> {
+        self.run_passes("lto<O2>")
+    }
+
+    #[cfg(not(feature = "llvm-5"))]
+    fn optimize_module(&self, pipeline: &str) -> Result<(), String> {
+        self.run_passes(pipeline)
+    }
+
+    // the module is not modified if the pipeline cannot be parsed
+    #[cfg(not(feature = "llvm-5"))]
+    fn run_passes(&self, pipeline: &str) -> Result<(), String> {
+        use llvm::transforms::pass_builder::*;
+        let pipeline = std::ffi::CString::new(pipeline).map_err(|e| e.to_string())?;
+        unsafe {
+            let options = LLVMCreatePassBuilderOptions();
+            let err = LLVMRunPasses(self.module, pipeline.as_ptr(), ptr::null_mut(), options);
+            LLVMDisposePassBuilderOptions(options);
+            if !err.is_null() {
+                return Err(compat::error_message(err));
+            }
+        }
+        Ok(())
     }
 
     // compile program
     pub fn jit_compile(&mut self) -> Result<(), String> {
+        if self.options.ebpf.is_some() {
+            return Err("eBPF programs cannot be JIT-compiled".to_owned());
+        }
+        // the cache entry was removed since `build()`, so the module is compiled
+        if !self.is_cached() {
+            self.finish_optimization()?;
+        }
         unsafe {
             llvm::execution_engine::LLVMLinkInMCJIT();
             let mut engine: LLVMExecutionEngineRef = mem::uninitialized();
             let mut err_msg: *mut i8 = mem::uninitialized();
-            let mut options: LLVMMCJITCompilerOptions = mem::uninitialized();
+            let mut options: LLVMMCJITCompilerOptions = mem::zeroed();
             let options_size = mem::size_of::<LLVMMCJITCompilerOptions>();
             llvm::execution_engine::LLVMInitializeMCJITCompilerOptions(&mut options, options_size);
             options.OptLevel = 0;
+
+            if self.symbol != "main" {
+                let symbol = std::ffi::CString::new(self.symbol.as_str()).unwrap();
+                llvm::core::LLVMSetValueName(self.get_function("main"), symbol.as_ptr());
+            }
+            let mut sections = None;
+            if self.options.perf_map {
+                let (mm, s) = jit::create_memory_manager();
+                options.MCJMM = mm;
+                sections = Some(s);
+            }
+
             let result_code = llvm::execution_engine::LLVMCreateMCJITCompilerForModule(
                 &mut engine,
                 self.module,
@@ -550,7 +1844,44 @@ impl Converter {
                 );
             }
 
-            let func_addr = llvm::execution_engine::LLVMGetFunctionAddress(engine, cstr!("main"));
+            if self.options.gdb_jit {
+                jit::cbpf_register_gdb_listener(engine);
+            }
+            if let Some(ref path) = self.cache_path {
+                let path = std::ffi::CString::new(path.as_str()).unwrap();
+                let description = std::ffi::CString::new(self.cache_description.as_str()).unwrap();
+                self.object_cache = Some(objcache::cbpf_set_object_cache(
+                    engine,
+                    path.as_ptr(),
+                    description.as_ptr(),
+                ));
+            }
+            if self.options.trace {
+                llvm::execution_engine::LLVMAddGlobalMapping(
+                    engine,
+                    self.get_value("trace_hook"),
+                    trace::hook as *mut _,
+                );
+            }
+
+            let symbol = std::ffi::CString::new(self.symbol.as_str()).unwrap();
+            let func_addr = llvm::execution_engine::LLVMGetFunctionAddress(engine, symbol.as_ptr());
+            if func_addr == 0 {
+                return Err(format!("{} is not found", self.symbol));
+            }
+            if let Some(sections) = sections {
+                jit::write_perf_map(&*sections, &self.symbol).map_err(|e| e.to_string())?;
+            }
+            if self.options.profile {
+                let addr = llvm::execution_engine::LLVMGetGlobalValueAddress(
+                    engine,
+                    cstr!("cbpf.counters"),
+                );
+                if addr == 0 {
+                    return Err("cbpf.counters is not found".to_owned());
+                }
+                self.counters = Some(addr as *mut u64);
+            }
             let func: Func = mem::transmute(func_addr);
             self.engine = Some(engine);
             self.jit_func = Some(func);
@@ -559,26 +1890,88 @@ impl Converter {
     }
 
     pub unsafe fn run_jit_func(&self, data: &[u8]) -> i32 {
-        self.jit_func.expect("compile program first")(data.as_ptr() as *mut u8)
+        self.jit_func.expect("compile program first")(data.as_ptr() as *mut u8, data.len() as u32)
+    }
+}
+
+
+#[cfg(feature = "llvm")]
+struct JeqChain {
+    // (k, target, index of the jeq)
+    cases: Vec<(u32, usize, usize)>,
+    default: usize,
+    // index of the last jeq, whose false branch is the default
+    last: usize,
+}
+
+// collect consecutive `jeq #k` instructions starting from idx, each of which is the false
+// branch of the previous one. they all test the same A, so they can be a single switch.
+#[cfg(feature = "llvm")]
+fn jeq_chain(insns: &[BpfInsn], idx: usize) -> Option<JeqChain> {
+    let mut cases: Vec<(u32, usize, usize)> = vec![];
+    let mut num = 0;
+    let mut last = idx;
+    let mut i = idx;
+    while i < insns.len() && insns[i].code == BPF_JMP | BPF_JEQ | BPF_K {
+        let insn = insns[i];
+        // the first jeq wins if the same constant appears twice
+        if !cases.iter().any(|&(k, _, _)| k == insn.k) {
+            cases.push((insn.k, i + 1 + insn.jt as usize, i));
+        }
+        num += 1;
+        last = i;
+        i = i + 1 + insn.jf as usize;
+    }
+
+    if num < 2 {
+        return None;
     }
+    Some(JeqChain {
+        cases,
+        default: i,
+        last,
+    })
 }
 
+// whether each instruction is a `jeq #k` in the false branch of another `jeq #k`,
+// i.e. a member of a chain but not its head
+#[cfg(feature = "llvm")]
+fn jeq_chain_members(insns: &[BpfInsn]) -> Vec<bool> {
+    let mut chained = vec![false; insns.len()];
+    for (i, insn) in insns.iter().enumerate() {
+        if insn.code != BPF_JMP | BPF_JEQ | BPF_K {
+            continue;
+        }
+        let next = i + 1 + insn.jf as usize;
+        if next < insns.len() && insns[next].code == BPF_JMP | BPF_JEQ | BPF_K {
+            chained[next] = true;
+        }
+    }
+    chained
+}
 
+#[cfg(feature = "llvm")]
 impl Drop for Converter {
     fn drop(&mut self) {
         unsafe {
-            // it gives error... why??
-            // self.engine
-            //     .map(|e| llvm::execution_engine::LLVMDisposeExecutionEngine(e));
             llvm::core::LLVMDisposeBuilder(self.builder);
-            llvm::core::LLVMDisposeModule(self.module);
-            llvm::core::LLVMContextDispose(self.context);
+            // the module is owned by the engine (or by ORC JIT if it is null)
+            match self.engine {
+                Some(engine) => llvm::execution_engine::LLVMDisposeExecutionEngine(engine),
+                None if !self.module.is_null() => llvm::core::LLVMDisposeModule(self.module),
+                None => {}
+            }
+            self.object_cache
+                .map(|c| objcache::cbpf_dispose_object_cache(c));
+            if self.owns_context {
+                llvm::core::LLVMContextDispose(self.context);
+            }
         }
     }
 }
 
 
-#[cfg(test)]
+#[cfg(all(test, feature = "llvm"))]
 mod tests {
     use super::*;
     use cbpf::interpreter::{Interpreter, Simple};
@@ -734,6 +2127,677 @@ mod tests {
         check(&insns, &data, u32::max_value());
     }
 
+    #[test]
+    fn unsigned() {
+        // ld [0]; jgt #0x7fffffff, L1, L2; L1: rsh #28; div #3; ret a; L2: ret #0
+        let insns = [
+            BpfInsn::new(BPF_LD_W_ABS, 0, 0, 0),
+            BpfInsn::new(BPF_JGT_K, 0, 3, 0x7fff_ffff),
+            BpfInsn::new(BPF_RSH_K, 0, 0, 28),
+            BpfInsn::new(BPF_DIV_K, 0, 0, 3),
+            BpfInsn::new(BPF_RET_A, 0, 0, 0),
+            BpfInsn::new(BPF_RET_K, 0, 0, 0),
+        ];
+        for data in &[[0xf0, 0, 0, 0], [0x70, 0, 0, 0]] {
+            let cr = { Simple::run(&insns, data).unwrap() };
+            check(&insns, data, cr);
+        }
+    }
+
+    #[test]
+    fn zero_division_and_large_shift() {
+        // ldb [0]; tax; ld #op; <op> x; ret a
+        let program = |k, op| {
+            [
+                BpfInsn::new(BPF_LD_B_ABS, 0, 0, 0),
+                BpfInsn::new(BPF_MISC_TAX, 0, 0, 0),
+                BpfInsn::new(BPF_LD_IMM, 0, 0, k),
+                BpfInsn::new(op, 0, 0, 0),
+                BpfInsn::new(BPF_RET_A, 0, 0, 0),
+            ]
+        };
+        // (program, result of X = 0, result of X = 40)
+        let cases = [
+            (program(1001, BPF_DIV_X), 0, 25),
+            (program(1001, BPF_MOD_X), 0, 1),
+            (program(1001, BPF_LSH_X), 1001, 0),
+            (program(1001, BPF_RSH_X), 1001, 0),
+        ];
+        for &(ref insns, zero, forty) in &cases {
+            check(insns, &[0], zero);
+            check(insns, &[40], forty);
+            for x in &[0u8, 3, 31, 32, 40] {
+                let cr = { Simple::run(insns, &[*x]).unwrap() };
+                check(insns, &[*x], cr);
+            }
+        }
+    }
+
+    #[test]
+    fn jeq_chain_to_switch() {
+        // port 80 or 443 or 8080
+        let insns = [
+            BpfInsn::new(BPF_LD_H_ABS, 0, 0, 0),
+            BpfInsn::new(BPF_JEQ_K, 3, 0, 80),
+            BpfInsn::new(BPF_JEQ_K, 2, 0, 443),
+            BpfInsn::new(BPF_JEQ_K, 1, 0, 8080),
+            BpfInsn::new(BPF_RET_K, 0, 0, 0),
+            BpfInsn::new(BPF_RET_K, 0, 0, 1),
+        ];
+
+        let ir = convert(&insns).unwrap();
+        // only the head of the chain is a switch
+        assert_eq!(ir.matches("switch i32").count(), 1);
+        assert_eq!(jeq_chain_members(&insns), vec![false, false, true, true, false, false]);
+
+        for port in &[80u16, 443, 8080, 22] {
+            let data = [(port >> 8) as u8, *port as u8];
+            let cr = { Simple::run(&insns, &data).unwrap() };
+            check(&insns, &data, cr);
+        }
+    }
+
+    #[test]
+    fn function_dot() {
+        let insns = [
+            BpfInsn::new(BPF_LD_H_ABS, 0, 0, 0),
+            BpfInsn::new(BPF_JEQ_K, 2, 0, 80),
+            BpfInsn::new(BPF_JEQ_K, 1, 0, 443),
+            BpfInsn::new(BPF_RET_K, 0, 0, 0),
+            BpfInsn::new(BPF_RET_K, 0, 0, 1),
+        ];
+
+        let mut converter = Converter::new();
+        converter.convert(&insns, false).unwrap();
+        let dot = converter.to_dot();
+        assert!(dot.starts_with("digraph main {"));
+        assert!(dot.contains("[label=\"default\"]"));
+        assert!(dot.contains("[label=\"443\"]"));
+    }
+
+    #[test]
+    fn branch_weights() {
+        // ldh [12]; jeq #0x86dd, L1, L2; L1: ret #0; L2: ldh [0]; jeq #1, L3, L4; ...
+        let insns = [
+            BpfInsn::new(BPF_LD_H_ABS, 0, 0, 12),
+            BpfInsn::new(BPF_JEQ_K, 0, 1, 0x86dd),
+            BpfInsn::new(BPF_RET_K, 0, 0, 0),
+            BpfInsn::new(BPF_LD_H_ABS, 0, 0, 0),
+            BpfInsn::new(BPF_JEQ_K, 1, 0, 1),
+            BpfInsn::new(BPF_JEQ_K, 0, 1, 2),
+            BpfInsn::new(BPF_RET_K, 0, 0, 1),
+            BpfInsn::new(BPF_RET_K, 0, 0, 0),
+        ];
+        let mut profile = BranchProfile::default();
+        profile.counts = vec![(0, 0); insns.len()];
+        profile.counts[1] = (1, 99);
+        profile.counts[4] = (30, 70);
+        profile.counts[5] = (20, 50);
+
+        // a profile of another program is rejected
+        let mut short = profile.clone();
+        short.counts.pop();
+        let options = Options {
+            branch_profile: Some(short),
+            ..Default::default()
+        };
+        assert!(Converter::with_options(options).convert(&insns, false).is_err());
+
+        let options = Options {
+            branch_profile: Some(profile),
+            ..Default::default()
+        };
+        let mut converter = Converter::with_options(options);
+        let ir = converter.convert(&insns, false).unwrap();
+        assert!(ir.contains("!{!\"branch_weights\", i32 1, i32 99}"));
+        // switch: default, 1, 2
+        assert!(ir.contains("!{!\"branch_weights\", i32 50, i32 30, i32 20}"));
+
+        let data = [0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x08, 0x00];
+        let cr = { Simple::run(&insns, &data).unwrap() };
+        converter.jit_compile().unwrap();
+        unsafe {
+            assert_eq!(converter.run_jit_func(&data) as u32, cr);
+        }
+    }
+
+    #[test]
+    fn out_of_bounds() {
+        // ldh [12]; ldb [13]; ret a
+        let insns = [
+            BpfInsn::new(BPF_LD_H_ABS, 0, 0, 12),
+            BpfInsn::new(BPF_LD_B_ABS, 0, 0, 13),
+            BpfInsn::new(BPF_RET_A, 0, 0, 0),
+        ];
+        let data = [0x08; 13];
+        check(&insns, &data, 0);
+        let data = [0x08; 14];
+        check(&insns, &data, 0x08);
+
+        // ldx msh [0]; ldw [x + 2]; ret a
+        let insns = [
+            BpfInsn::new(BPF_LDX_B_MSH, 0, 0, 0),
+            BpfInsn::new(BPF_LD_W_IND, 0, 0, 2),
+            BpfInsn::new(BPF_RET_A, 0, 0, 0),
+        ];
+        let data = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09];
+        check(&insns, &data, 0);
+        let data = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a];
+        check(&insns, &data, 0x0708090a);
+    }
+
+    #[test]
+    fn packet_length() {
+        // ld len; ldx len; add x; ret a
+        let insns = [
+            BpfInsn::new(BPF_LD | BPF_W | BPF_LEN, 0, 0, 0),
+            BpfInsn::new(BPF_LDX | BPF_W | BPF_LEN, 0, 0, 0),
+            BpfInsn::new(BPF_ADD_X, 0, 0, 0),
+            BpfInsn::new(BPF_RET_A, 0, 0, 0),
+        ];
+        check(&insns, &[0; 42], 84);
+    }
+
+    #[test]
+    fn pass_pipelines() {
+        // ldh [12]; jeq #0x800, L1, L2; L1: ret #1; L2: ret #0
+        let insns = [
+            BpfInsn::new(BPF_LD_H_ABS, 0, 0, 12),
+            BpfInsn::new(BPF_JEQ_K, 0, 1, 0x0800),
+            BpfInsn::new(BPF_RET_K, 0, 0, 1),
+            BpfInsn::new(BPF_RET_K, 0, 0, 0),
+        ];
+
+        let mut converter = Converter::new();
+        converter.convert(&insns, true).unwrap();
+        let pipelines: Vec<_> = converter
+            .pass_timings()
+            .iter()
+            .map(|&(ref p, _)| p.as_str())
+            .collect();
+        if cfg!(feature = "llvm-5") {
+            assert_eq!(pipelines, ["legacy-O2", "legacy-lto"]);
+        } else {
+            assert_eq!(pipelines, ["default<O2>", "lto<O2>"]);
+        }
+
+        let mut converter = Converter::new();
+        converter.convert(&insns, false).unwrap();
+        assert!(converter.pass_timings().is_empty());
+
+        let options = Options {
+            passes: Some("default<O3>".to_owned()),
+            ..Default::default()
+        };
+        let mut converter = Converter::with_options(options);
+        let r = converter.convert(&insns, true);
+        if cfg!(feature = "llvm-5") {
+            assert!(r.is_err());
+        } else {
+            r.unwrap();
+            // lto<O2> is not appended
+            let pipelines: Vec<_> = converter
+                .pass_timings()
+                .iter()
+                .map(|&(ref p, _)| p.as_str())
+                .collect();
+            assert_eq!(pipelines, ["default<O3>"]);
+            converter.jit_compile().unwrap();
+            let mut data = [0; 14];
+            data[12] = 0x08;
+            unsafe {
+                assert_eq!(converter.run_jit_func(&data), 1);
+                assert_eq!(converter.run_jit_func(&[0x08; 14]), 0);
+            }
+        }
+
+        // the helpers are inlined by the requested pipeline
+        let options = Options {
+            passes: Some("default<O2>,lto<O2>".to_owned()),
+            ..Default::default()
+        };
+        let msh = [
+            BpfInsn::new(BPF_LDX_B_MSH, 0, 0, 0),
+            BpfInsn::new(BPF_MISC | BPF_TXA, 0, 0, 0),
+            BpfInsn::new(BPF_RET_A, 0, 0, 0),
+        ];
+        let mut converter = Converter::with_options(options);
+        let r = converter.convert(&msh, true);
+        if !cfg!(feature = "llvm-5") {
+            assert!(!r.unwrap().contains("@msh"));
+            assert_eq!(converter.pass_timings().len(), 1);
+        }
+
+        let options = Options {
+            passes: Some("no-such-pass".to_owned()),
+            ..Default::default()
+        };
+        assert!(Converter::with_options(options).convert(&insns, true).is_err());
+    }
+
+    #[test]
+    fn debug_info() {
+        let insns = [
+            BpfInsn::new(BPF_LD_H_ABS, 0, 0, 12),
+            BpfInsn::new(BPF_JEQ_K, 0, 1, 0x0800),
+            BpfInsn::new(BPF_RET_K, 0, 0, 0xffff),
+            BpfInsn::new(BPF_RET_K, 0, 0, 0),
+        ];
+
+        let options = Options {
+            debug_info: true,
+            ..Default::default()
+        };
+        let mut converter = Converter::with_options(options);
+        let ir = converter.convert(&insns, false).unwrap();
+        assert!(ir.contains("!DIFile(filename: \"filter.bpf\""));
+        assert!(ir.contains("!DILocation(line: 4,"));
+        assert!(!ir.contains("llvm.donothing"));
+
+        converter.jit_compile().unwrap();
+        unsafe {
+            assert_eq!(converter.run_jit_func(&[0; 14]), 0);
+        }
+    }
+
+    #[test]
+    fn perf_map() {
+        use std::io::Read;
+
+        let insns = [BpfInsn::new(BPF_RET_K, 0, 0, 1)];
+        let options = Options {
+            name: "ret1".to_owned(),
+            perf_map: true,
+            ..Default::default()
+        };
+        let mut converter = Converter::with_options(options);
+        converter.convert(&insns, true).unwrap();
+        converter.jit_compile().unwrap();
+        unsafe {
+            assert_eq!(converter.run_jit_func(&[]), 1);
+        }
+
+        let path = format!("/tmp/perf-{}.map", unsafe { libc::getpid() });
+        let mut map = String::new();
+        std::fs::File::open(path)
+            .unwrap()
+            .read_to_string(&mut map)
+            .unwrap();
+        assert!(map.lines().any(|l| l.ends_with(&jit::symbol_name("ret1", &insns))));
+    }
+
+    #[test]
+    fn emit_object() {
+        use std::io::Read;
+
+        let insns = [BpfInsn::new(BPF_RET_K, 0, 0, 1)];
+        let path = std::env::temp_dir().join(format!("cbpf-{}.o", unsafe { libc::getpid() }));
+        let path = path.to_string_lossy().into_owned();
+        let mut converter = Converter::new();
+        converter.convert(&insns, true).unwrap();
+        converter.emit_object("filter_ret1", &path).unwrap();
+        assert!(converter.get_ir().contains("@filter_ret1("));
+
+        let mut object = vec![];
+        std::fs::File::open(&path)
+            .unwrap()
+            .read_to_end(&mut object)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        if cfg!(target_os = "linux") {
+            assert_eq!(&object[..4], b"\x7fELF");
+        }
+    }
+
+    // the object is a module whose memory is imported, so it runs without wasm-ld
+    #[test]
+    #[cfg(not(feature = "llvm-5"))]
+    fn wasm_object() {
+        use std::io::Read;
+        use wasmi::memory_units::Pages;
+        use wasmi::{Error, ImportsBuilder, MemoryDescriptor, MemoryInstance, MemoryRef,
+                    ModuleImportResolver, ModuleInstance, NopExternals, RuntimeValue};
+
+        struct Env(MemoryRef);
+        impl ModuleImportResolver for Env {
+            fn resolve_memory(&self, name: &str, _: &MemoryDescriptor) -> Result<MemoryRef, Error> {
+                assert_eq!(name, "__linear_memory");
+                Ok(self.0.clone())
+            }
+        }
+
+        // ldh [12]; jeq #0x800, L1, L2; L1: ldxb 4*([14]&0xf); ldh [x + 16]; ret a; L2: ret #0
+        let insns = [
+            BpfInsn::new(BPF_LD_H_ABS, 0, 0, 12),
+            BpfInsn::new(BPF_JEQ_K, 0, 3, 0x800),
+            BpfInsn::new(BPF_LDX | BPF_B | BPF_MSH, 0, 0, 14),
+            BpfInsn::new(BPF_LD | BPF_H | BPF_IND, 0, 0, 16),
+            BpfInsn::new(BPF_RET_A, 0, 0, 0),
+            BpfInsn::new(BPF_RET_K, 0, 0, 0),
+        ];
+        let path = std::env::temp_dir().join(format!("cbpf-{}.wasm", unsafe { libc::getpid() }));
+        let path = path.to_string_lossy().into_owned();
+        let mut converter = Converter::new();
+        converter.convert(&insns, true).unwrap();
+        converter.emit_wasm_object("filter", &path).unwrap();
+        let mut object = vec![];
+        std::fs::File::open(&path)
+            .unwrap()
+            .read_to_end(&mut object)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(&object[..4], b"\0asm");
+
+        let memory = MemoryInstance::alloc(Pages(1), None).unwrap();
+        let env = Env(memory.clone());
+        let module = wasmi::Module::from_buffer(&object).unwrap();
+        let imports = ImportsBuilder::new().with_resolver("env", &env);
+        let instance = ModuleInstance::new(&module, &imports)
+            .unwrap()
+            .assert_no_start();
+        let mut ip = vec![0u8; 40];
+        ip[12] = 0x08;
+        ip[14] = 0x45;
+        ip[31] = 0x2a;
+        let mut options = ip.clone();
+        options[14] = 0x4f;
+        for packet in &[ip.clone(), options, ip[..20].to_vec(), vec![]] {
+            let ptr = 100;
+            memory.set(ptr, packet).unwrap();
+            let args = [
+                RuntimeValue::I32(ptr as i32),
+                RuntimeValue::I32(packet.len() as i32),
+            ];
+            let result = instance
+                .invoke_export("filter", &args, &mut NopExternals)
+                .unwrap();
+            let expected = Simple::run(&insns, packet).unwrap();
+            assert_eq!(result, Some(RuntimeValue::I32(expected as i32)));
+        }
+    }
+
+    #[test]
+    fn ebpf_object() {
+        use std::io::Read;
+
+        // ldh [12]; jeq #0x800, L1, L2; L1: ldxb 4*([14]&0xf); ldb [x + 23]; ret a; L2: ret #0
+        let insns = [
+            BpfInsn::new(BPF_LD_H_ABS, 0, 0, 12),
+            BpfInsn::new(BPF_JEQ_K, 0, 3, 0x800),
+            BpfInsn::new(BPF_LDX | BPF_B | BPF_MSH, 0, 0, 14),
+            BpfInsn::new(BPF_LD | BPF_B | BPF_IND, 0, 0, 23),
+            BpfInsn::new(BPF_RET_A, 0, 0, 0),
+            BpfInsn::new(BPF_RET_K, 0, 0, 0),
+        ];
+        let path = std::env::temp_dir().join(format!("cbpf-{}-bpf.o", unsafe { libc::getpid() }));
+        let path = path.to_string_lossy().into_owned();
+        for &(program_type, big_endian) in &[
+            (ProgramType::Xdp, false),
+            (ProgramType::Classifier, true),
+        ] {
+            let target = EbpfTarget {
+                big_endian,
+                ..EbpfTarget::new(program_type)
+            };
+            let options = Options {
+                ebpf: Some(target.clone()),
+                ..Default::default()
+            };
+            let mut converter = Converter::with_options(options);
+            converter.convert(&insns, true).unwrap();
+            assert!(converter.jit_compile().is_err());
+            converter.emit_object("cbpf_filter", &path).unwrap();
+
+            let mut object = vec![];
+            std::fs::File::open(&path)
+                .unwrap()
+                .read_to_end(&mut object)
+                .unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(object[5], if big_endian { 2 } else { 1 });
+
+            let sections = elf_sections(&object).unwrap();
+            let license = sections.iter().find(|s| s.name == "license").unwrap();
+            assert_eq!(license.data, b"Dual MIT/GPL\0");
+            let prog = sections.iter().find(|s| s.name == target.section()).unwrap();
+            assert!(!prog.data.is_empty() && prog.data.len() % 8 == 0);
+
+            // opcode, dst, src, off
+            let insns: Vec<_> = prog.data
+                .chunks(8)
+                .map(|i| {
+                    let (dst, src) = if big_endian {
+                        (i[1] >> 4, i[1] & 0xf)
+                    } else {
+                        (i[1] & 0xf, i[1] >> 4)
+                    };
+                    let off = if big_endian {
+                        (i[2] as u16) << 8 | i[3] as u16
+                    } else {
+                        (i[3] as u16) << 8 | i[2] as u16
+                    };
+                    (i[0], dst, src, off)
+                })
+                .collect();
+            // data and data_end are read from the context (r1) with 32-bit loads
+            let (data, data_end) = target.data_offsets().unwrap();
+            for &off in &[data, data_end] {
+                assert!(
+                    insns
+                        .iter()
+                        .any(|&(op, _, src, o)| op == 0x61 && src == 1 && o as u64 == off)
+                );
+            }
+            // no calls, and exits
+            assert!(insns.iter().all(|&(op, _, _, _)| op != 0x85));
+            assert!(insns.iter().any(|&(op, _, _, _)| op == 0x95));
+        }
+
+        // xdp_md has no packet length
+        let options = Options {
+            ebpf: Some(EbpfTarget::new(ProgramType::Xdp)),
+            ..Default::default()
+        };
+        let insns = [
+            BpfInsn::new(BPF_LD | BPF_W | BPF_LEN, 0, 0, 0),
+            BpfInsn::new(BPF_RET_A, 0, 0, 0),
+        ];
+        assert!(Converter::with_options(options).convert(&insns, true).is_err());
+    }
+
+    // emit the eBPF object and return (maps section, program)
+    fn emit_ebpf(target: &EbpfTarget, insns: &[BpfInsn]) -> (Vec<u8>, Vec<u8>) {
+        use std::io::Read;
+
+        let path = std::env::temp_dir().join(format!("cbpf-{}-xdp.o", unsafe { libc::getpid() }));
+        let path = path.to_string_lossy().into_owned();
+        let options = Options {
+            ebpf: Some(target.clone()),
+            ..Default::default()
+        };
+        let mut converter = Converter::with_options(options);
+        converter.convert(insns, true).unwrap();
+        converter.emit_object("xdp_filter", &path).unwrap();
+        let mut object = vec![];
+        std::fs::File::open(&path)
+            .unwrap()
+            .read_to_end(&mut object)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let sections = elf_sections(&object).unwrap();
+        let find = |name: &str| {
+            sections
+                .iter()
+                .find(|s| s.name == name)
+                .map(|s| s.data.clone())
+                .unwrap_or_default()
+        };
+        (find("maps"), find(target.section()))
+    }
+
+    // struct xdp_md of the packet at ebpfvm::PACKET
+    fn xdp_md(len: usize, big_endian: bool) -> Vec<u8> {
+        let mut ctx = vec![];
+        for &v in &[ebpfvm::PACKET, ebpfvm::PACKET + len as u64] {
+            let v = v as u32;
+            if big_endian {
+                ctx.extend_from_slice(&[(v >> 24) as u8, (v >> 16) as u8, (v >> 8) as u8, v as u8]);
+            } else {
+                ctx.extend_from_slice(&[v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8]);
+            }
+        }
+        // data_meta, ingress_ifindex, rx_queue_index, egress_ifindex
+        ctx.extend_from_slice(&[0; 16]);
+        ctx
+    }
+
+    #[test]
+    fn xdp_program() {
+        // tcp dst port 80
+        let insns = [
+            BpfInsn::new(BPF_LD_H_ABS, 0, 0, 12),
+            BpfInsn::new(BPF_JEQ_K, 0, 8, 0x800),
+            BpfInsn::new(BPF_LD | BPF_B | BPF_ABS, 0, 0, 23),
+            BpfInsn::new(BPF_JEQ_K, 0, 6, 6),
+            BpfInsn::new(BPF_LD_H_ABS, 0, 0, 20),
+            BpfInsn::new(BPF_JMP | BPF_JSET | BPF_K, 4, 0, 0x1fff),
+            BpfInsn::new(BPF_LDX | BPF_B | BPF_MSH, 0, 0, 14),
+            BpfInsn::new(BPF_LD | BPF_H | BPF_IND, 0, 0, 16),
+            BpfInsn::new(BPF_JEQ_K, 0, 1, 80),
+            BpfInsn::new(BPF_RET_K, 0, 0, 0x40000),
+            BpfInsn::new(BPF_RET_K, 0, 0, 0),
+        ];
+        let mut http = vec![0u8; 54];
+        http[12] = 0x08;
+        http[14] = 0x45;
+        http[23] = 6;
+        http[37] = 80;
+        let mut ssh = http.clone();
+        ssh[37] = 22;
+        // IP options make the port out of bounds
+        let mut options = http.clone();
+        options[14] = 0x4f;
+        let mut arp = http.clone();
+        arp[13] = 0x06;
+        let packets = vec![http.clone(), ssh, options, arp, http[..30].to_vec(), vec![]];
+
+        let map = RedirectMap {
+            name: "tx_port".to_owned(),
+            map_type: MapType::Devmap,
+            max_entries: 8,
+            key: 3,
+        };
+        for &big_endian in &[false, true] {
+            let target = EbpfTarget {
+                big_endian,
+                accept: Action::Redirect(map.clone()),
+                reject: Action::Pass,
+                iproute2_maps: true,
+                ..EbpfTarget::new(ProgramType::Xdp)
+            };
+            let (maps, prog) = emit_ebpf(&target, &insns);
+            assert_eq!(maps.len(), 36);
+            let map_type = if big_endian { maps[3] } else { maps[0] };
+            assert_eq!(map_type, 14);
+
+            let vm = ebpfvm::Vm::new(&prog, big_endian);
+            for packet in &packets {
+                let mut redirected = None;
+                let v = vm.run(&xdp_md(packet.len(), big_endian), packet, |id, args| {
+                    assert_eq!(id as u64, ebpf::BPF_FUNC_REDIRECT_MAP);
+                    redirected = Some((args[1], args[2]));
+                    // XDP_REDIRECT
+                    4
+                });
+                if Simple::run(&insns, packet).unwrap() != 0 {
+                    assert_eq!(v, 4);
+                    assert_eq!(redirected, Some((3, 0)));
+                } else {
+                    // XDP_PASS
+                    assert_eq!(v, 2);
+                    assert_eq!(redirected, None);
+                }
+            }
+        }
+
+        // drop tcp in raw IP frames: ldb [9]; jeq #6, L1, L2; L1: ret #1; L2: ret #0
+        let insns = [
+            BpfInsn::new(BPF_LD | BPF_B | BPF_ABS, 0, 0, 9),
+            BpfInsn::new(BPF_JEQ_K, 0, 1, 6),
+            BpfInsn::new(BPF_RET_K, 0, 0, 1),
+            BpfInsn::new(BPF_RET_K, 0, 0, 0),
+        ];
+        let target = EbpfTarget {
+            accept: Action::Drop,
+            reject: Action::Pass,
+            offset: linktype_offset(101).unwrap(),
+            ..EbpfTarget::new(ProgramType::Xdp)
+        };
+        let (maps, prog) = emit_ebpf(&target, &insns);
+        assert!(maps.is_empty());
+        let vm = ebpfvm::Vm::new(&prog, false);
+        let run = |packet: &[u8]| vm.run(&xdp_md(packet.len(), false), packet, |_, _| panic!());
+        // XDP_DROP, XDP_PASS
+        assert_eq!(run(&http), 1);
+        assert_eq!(run(&http[..23]), 2);
+        let mut udp = http.clone();
+        udp[23] = 17;
+        assert_eq!(run(&udp), 2);
+    }
+
+    #[test]
+    fn socket_filter() {
+        // ldh [12]; jeq #0x800, L1, L2; L1: ldxb 4*([14]&0xf); ldh [x + 16]; jeq #80, L3, L2;
+        // L3: ld len; ret a; L2: ret #0
+        let insns = [
+            BpfInsn::new(BPF_LD_H_ABS, 0, 0, 12),
+            BpfInsn::new(BPF_JEQ_K, 0, 5, 0x800),
+            BpfInsn::new(BPF_LDX | BPF_B | BPF_MSH, 0, 0, 14),
+            BpfInsn::new(BPF_LD | BPF_H | BPF_IND, 0, 0, 16),
+            BpfInsn::new(BPF_JEQ_K, 0, 2, 80),
+            BpfInsn::new(BPF_LD | BPF_W | BPF_LEN, 0, 0, 0),
+            BpfInsn::new(BPF_RET_A, 0, 0, 0),
+            BpfInsn::new(BPF_RET_K, 0, 0, 0),
+        ];
+        let mut http = vec![0u8; 54];
+        http[12] = 0x08;
+        http[14] = 0x45;
+        http[37] = 80;
+        let mut ssh = http.clone();
+        ssh[37] = 22;
+        let mut options = http.clone();
+        options[14] = 0x4f;
+        let packets = vec![http.clone(), ssh, options, http[..37].to_vec(), vec![]];
+
+        for &big_endian in &[false, true] {
+            let target = EbpfTarget {
+                big_endian,
+                ..EbpfTarget::new(ProgramType::SocketFilter)
+            };
+            let (maps, prog) = emit_ebpf(&target, &insns);
+            assert!(maps.is_empty());
+            assert!(!prog.is_empty() && prog.len() % 8 == 0);
+            let ops: Vec<_> = prog.chunks(8).map(|i| i[0]).collect();
+            // ldabsh, ldabsb (msh), ldindh, and only the length is read from the context
+            assert!(ops.contains(&0x28) && ops.contains(&0x30) && ops.contains(&0x48));
+            assert_eq!(ops.iter().filter(|&&op| op == 0x61).count(), 1);
+
+            let vm = ebpfvm::Vm::new(&prog, big_endian);
+            for packet in &packets {
+                // struct __sk_buff starts with len
+                let len = packet.len() as u32;
+                let ctx = if big_endian {
+                    [(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8]
+                } else {
+                    [len as u8, (len >> 8) as u8, (len >> 16) as u8, (len >> 24) as u8]
+                };
+                let v = vm.run(&ctx, packet, |_, _| panic!());
+                assert_eq!(v, Simple::run(&insns, packet).unwrap() as u64);
+            }
+        }
+    }
+
     #[test]
     fn test2() {
         let insns = [
@@ -869,4 +2933,61 @@ mod tests {
         check(&insns, &data, cr);
     }
 
+    #[test]
+    fn object_cache() {
+        let insns = [
+            BpfInsn::new(BPF_LD_H_ABS, 0, 0, 12),
+            BpfInsn::new(BPF_JEQ_K, 0, 1, 0x0800),
+            BpfInsn::new(BPF_RET_K, 0, 0, 0xffff),
+            BpfInsn::new(BPF_RET_K, 0, 0, 0),
+        ];
+        let dir = std::env::temp_dir().join(format!("cbpf-cache-{}", unsafe { libc::getpid() }));
+        let options = Options {
+            cache_dir: Some(dir.to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        let mut data = [0u8; 14];
+        data[12] = 0x08;
+
+        let ir = Converter::new().convert(&insns, true).unwrap();
+
+        // compiled, loaded from the cache, and recompiled after the entry is broken
+        for &(cached, corrupt) in &[(false, false), (true, true), (false, false), (true, false)] {
+            let mut converter = Converter::with_options(options.clone());
+            converter.build(&insns, true).unwrap();
+            assert_eq!(converter.is_cached(), cached);
+            // the optimizer does not run on a cache hit
+            assert_eq!(converter.pass_timings().is_empty(), cached);
+            converter.jit_compile().unwrap();
+            unsafe {
+                assert_eq!(converter.run_jit_func(&data), 0xffff);
+            }
+            assert!(converter.is_cached());
+            assert_eq!(converter.pass_timings().is_empty(), cached);
+            // but the IR is optimized when it is needed
+            assert_eq!(converter.get_ir(), ir);
+            assert!(!converter.pass_timings().is_empty());
+            if corrupt {
+                let path = converter.cache_path.clone().unwrap();
+                std::fs::File::create(&path).unwrap();
+            }
+        }
+
+        // the entry of another program is not loaded even under the name of this one
+        let mut converter = Converter::with_options(options.clone());
+        converter.build(&insns, true).unwrap();
+        let other = [BpfInsn::new(BPF_RET_K, 0, 0, 1)];
+        let mut foreign = Converter::with_options(options.clone());
+        foreign.build(&other, true).unwrap();
+        assert!(!foreign.is_cached());
+        let path = foreign.cache_path.clone().unwrap();
+        std::fs::copy(converter.cache_path.clone().unwrap(), &path).unwrap();
+        assert!(!foreign.is_cached());
+        foreign.jit_compile().unwrap();
+        unsafe {
+            assert_eq!(foreign.run_jit_func(&data), 1);
+        }
+        assert!(foreign.is_cached());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
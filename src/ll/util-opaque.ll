@@ -0,0 +1,28 @@
+; Function Attrs: noinline nounwind optnone ssp uwtable
+define i32 @ldb(ptr, i32) #0 {
+  %3 = alloca ptr, align 8
+  %4 = alloca i32, align 4
+  store ptr %0, ptr %3, align 8
+  store i32 %1, ptr %4, align 4
+  %5 = load ptr, ptr %3, align 8
+  %6 = load i32, ptr %4, align 4
+  %7 = sext i32 %6 to i64
+  %8 = getelementptr inbounds i8, ptr %5, i64 %7
+  %9 = load i8, ptr %8, align 1
+  %10 = zext i8 %9 to i32
+  ret i32 %10
+}
+
+; Function Attrs: noinline nounwind optnone ssp uwtable
+define i32 @msh(ptr, i32) #0 {
+  %3 = alloca ptr, align 8
+  %4 = alloca i32, align 4
+  store ptr %0, ptr %3, align 8
+  store i32 %1, ptr %4, align 4
+  %5 = load ptr, ptr %3, align 8
+  %6 = load i32, ptr %4, align 4
+  %7 = call i32 @ldb(ptr %5, i32 %6)
+  %8 = and i32 %7, 15
+  %9 = shl i32 %8, 2
+  ret i32 %9
+}
@@ -1,19 +1,3 @@
-// TODO: big endian archtecture support
-int be(int x){
-   return  ((x & 0xff) << 24) | (((x >> 8) & 0xff) << 16) |
-           (((x >> 16) & 0xff) << 8) | ((x >> 24) & 0xff);
-}
-
-int ldw(unsigned char* x, int offset){
-    int v = *(int *)(x+offset);
-    return be(v);
-}
-
-int ldh(unsigned char* x, int offset){
-    int v = ((*(x+offset)) << 8) | (*(x+offset+1));
-    return v;
-}
-
 int ldb(unsigned char* x, int offset){
     return *(x+offset);
 }
@@ -0,0 +1,152 @@
+// persistent cache of JIT-compiled objects
+//
+// MCJIT asks its ObjectCache (src/shim/cache.cpp) for the object of a module before
+// compiling it, and hands over the object after compiling. The object is stored in
+// `<cache dir>/<key>.o` after the description of everything the code depends on:
+// the program, the options, the version of this crate and LLVM, and the target.
+// The key is the hash of the description, and the object is loaded only if the stored
+// description is the same, so a hash collision or a foreign file is compiled again.
+// The cache directory must be accessible only by its owner.
+
+use cbpf::opcode::BpfInsn;
+use jit;
+use llvm::execution_engine::LLVMExecutionEngineRef;
+use std::fs::{self, DirBuilder};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::Path;
+use Options;
+
+extern "C" {
+    // src/shim/cache.cpp
+    pub fn cbpf_set_object_cache(
+        engine: LLVMExecutionEngineRef,
+        path: *const ::libc::c_char,
+        description: *const ::libc::c_char,
+    ) -> *mut ::libc::c_void;
+    pub fn cbpf_dispose_object_cache(cache: *mut ::libc::c_void);
+    fn cbpf_check_cached_object(
+        path: *const ::libc::c_char,
+        description: *const ::libc::c_char,
+    ) -> bool;
+}
+
+static LLVM_VERSION: &'static str = env!("CBPF_LLVM_VERSION");
+
+fn target_triple() -> String {
+    unsafe {
+        let triple = ::llvm::target_machine::LLVMGetDefaultTargetTriple();
+        let s = ::std::ffi::CStr::from_ptr(triple)
+            .to_string_lossy()
+            .into_owned();
+        ::llvm::core::LLVMDisposeMessage(triple);
+        s
+    }
+}
+
+// description of the compilation, from which the key is computed
+pub fn describe(insns: &[BpfInsn], options: &Options, optimization: bool) -> String {
+    let mut options = options.clone();
+    options.cache_dir = None;
+    let mut s = format!(
+        "cbpf-to-llvm-ir {}\nllvm {}\n{}\n{:016x}\noptimization: {}\n{:?}\n",
+        env!("CARGO_PKG_VERSION"),
+        LLVM_VERSION,
+        target_triple(),
+        jit::hash(insns),
+        optimization,
+        options
+    );
+    // the debug info refers to the current directory
+    if options.debug_info {
+        if let Ok(dir) = ::std::env::current_dir() {
+            s += &format!("{}\n", dir.display());
+        }
+    }
+    s
+}
+
+// name of the entry of the description
+pub fn key(description: &str) -> String {
+    format!("{:016x}", jit::fnv1a(0xcbf2_9ce4_8422_2325, description.as_bytes()))
+}
+
+// create the cache directory, or check that an existing one is private
+pub fn create_dir(dir: &str) -> Result<(), String> {
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .map_err(|e| e.to_string())?;
+    let metadata = fs::metadata(dir).map_err(|e| e.to_string())?;
+    if metadata.uid() != unsafe { ::libc::getuid() } || metadata.mode() & 0o077 != 0 {
+        return Err(format!(
+            "the cache directory {} must be accessible only by its owner",
+            dir
+        ));
+    }
+    Ok(())
+}
+
+pub fn path(dir: &str, key: &str) -> String {
+    Path::new(dir)
+        .join(format!("{}.o", key))
+        .to_string_lossy()
+        .into_owned()
+}
+
+// whether a valid object compiled from the description is at `path`. a broken one is removed
+pub fn exists(path: &str, description: &str) -> bool {
+    let path = ::std::ffi::CString::new(path).unwrap();
+    let description = ::std::ffi::CString::new(description).unwrap();
+    unsafe { cbpf_check_cached_object(path.as_ptr(), description.as_ptr()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cbpf::opcode::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn cache_key() {
+        let insns = [BpfInsn::new(BPF_RET_K, 0, 0, 0)];
+        let options = Options::default();
+        let d = describe(&insns, &options, true);
+        assert_eq!(d, describe(&insns, &options, true));
+        assert!(d != describe(&insns, &options, false));
+        assert!(d != describe(&[BpfInsn::new(BPF_RET_K, 0, 0, 1)], &options, true));
+
+        let traced = Options {
+            trace: true,
+            ..Default::default()
+        };
+        assert!(d != describe(&insns, &traced, true));
+        // the location of the cache does not matter
+        let cached = Options {
+            cache_dir: Some("/tmp/cbpf".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(d, describe(&insns, &cached, true));
+
+        let k = key(&d);
+        assert_eq!(k.len(), 16);
+        assert_eq!(path("/tmp/cbpf", &k), format!("/tmp/cbpf/{}.o", k));
+    }
+
+    #[test]
+    fn private_dir() {
+        let dir = ::std::env::temp_dir().join(format!("cbpf-dir-{}", unsafe { ::libc::getpid() }));
+        let private = dir.join("private");
+        let private = private.to_str().unwrap();
+        create_dir(private).unwrap();
+        assert_eq!(fs::metadata(private).unwrap().mode() & 0o777, 0o700);
+        create_dir(private).unwrap();
+
+        // an existing directory which others can access is rejected
+        let shared = dir.join("shared");
+        fs::create_dir(&shared).unwrap();
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(create_dir(shared.to_str().unwrap()).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,981 @@
+// cBPF-level optimizer
+//
+// libpcap emits a lot of redundant code (constant spills through M[], reloads of the
+// same packet field, jumps over jumps ...). This pass removes it before we build any IR.
+// The output is still a plain cBPF program which can be loaded into the kernel.
+//
+// Since cBPF only has forward jumps, every analysis here is a single pass over the
+// instructions (forward or backward) without any fixpoint iteration.
+
+use cbpf::opcode::*;
+
+// the maximum number of rounds; each round strictly shrinks or simplifies the program
+// so this is just a safety net
+const MAX_ROUNDS: usize = 64;
+
+pub fn optimize(insns: &[BpfInsn]) -> Vec<BpfInsn> {
+    let mut prog = match Program::new(insns) {
+        Some(prog) => prog,
+        // we do not touch programs which the kernel would reject
+        None => return insns.to_vec(),
+    };
+
+    for _ in 0..MAX_ROUNDS {
+        let mut changed = prog.fold();
+        prog.compact();
+        changed |= prog.eliminate_dead_code();
+        prog.compact();
+        if !changed {
+            break;
+        }
+    }
+
+    prog.to_insns()
+}
+
+// symbolic name of a runtime value
+#[derive(Clone, PartialEq, Debug)]
+enum Sym {
+    // packet load with absolute offset (code, k)
+    Pkt(u16, u32),
+    // packet load with indirect offset (code, k, X)
+    Ind(u16, u32, Box<Sym>),
+    // (data[k] & 0xf) << 2
+    Msh(u32),
+    Len,
+    // value computed by the instruction of the index.
+    // each instruction is executed at most once, so this uniquely identifies the value
+    Def(usize),
+}
+
+#[derive(Clone, PartialEq, Debug)]
+struct Val {
+    sym: Option<Sym>,
+    konst: Option<u32>,
+}
+
+impl Val {
+    fn unknown() -> Self {
+        Val {
+            sym: None,
+            konst: None,
+        }
+    }
+
+    fn konst(k: u32) -> Self {
+        Val {
+            sym: None,
+            konst: Some(k),
+        }
+    }
+
+    fn sym(sym: Sym) -> Self {
+        Val {
+            sym: Some(sym),
+            konst: None,
+        }
+    }
+
+    fn is_known(&self) -> bool {
+        self.sym.is_some() || self.konst.is_some()
+    }
+
+    // true if both values are the same at runtime
+    fn same(&self, other: &Val) -> bool {
+        (self.konst.is_some() && self.konst == other.konst)
+            || (self.sym.is_some() && self.sym == other.sym)
+    }
+
+    fn join(&self, other: &Val) -> Val {
+        Val {
+            sym: if self.sym == other.sym {
+                self.sym.clone()
+            } else {
+                None
+            },
+            konst: if self.konst == other.konst {
+                self.konst
+            } else {
+                None
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct State {
+    a: Val,
+    x: Val,
+    mem: Vec<Val>,
+}
+
+impl State {
+    fn entry() -> Self {
+        // A, X and M[] start at 0 (see frontend.rs)
+        State {
+            a: Val::konst(0),
+            x: Val::konst(0),
+            mem: vec![Val::konst(0); BPF_MEMWORDS as usize],
+        }
+    }
+
+    fn join(&self, other: &State) -> State {
+        State {
+            a: self.a.join(&other.a),
+            x: self.x.join(&other.x),
+            mem: self.mem
+                .iter()
+                .zip(other.mem.iter())
+                .map(|(a, b)| a.join(b))
+                .collect(),
+        }
+    }
+
+    // name an unknown value so that copies of it can be tracked
+    fn name(v: &mut Val, idx: usize) {
+        if !v.is_known() {
+            *v = Val::sym(Sym::Def(idx));
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Node {
+    insn: BpfInsn,
+    // absolute indices of the successors. for non-jump instructions both are idx + 1
+    jt: usize,
+    jf: usize,
+    removed: bool,
+}
+
+struct Program {
+    nodes: Vec<Node>,
+}
+
+fn is_jump(insn: &BpfInsn) -> bool {
+    bpf_class(insn.code) == BPF_JMP
+}
+
+fn is_ja(insn: &BpfInsn) -> bool {
+    is_jump(insn) && bpf_op(insn.code) == BPF_JA
+}
+
+fn is_ret(insn: &BpfInsn) -> bool {
+    bpf_class(insn.code) == BPF_RET
+}
+
+fn uses_mem(insn: &BpfInsn) -> bool {
+    match bpf_class(insn.code) {
+        BPF_LD | BPF_LDX => bpf_mode(insn.code) == BPF_MEM,
+        BPF_ST | BPF_STX => true,
+        _ => false,
+    }
+}
+
+fn is_load_size(code: u16) -> bool {
+    match bpf_size(code) {
+        BPF_W | BPF_H | BPF_B => true,
+        _ => false,
+    }
+}
+
+// true if the kernel accepts the opcode. the passes below only handle these
+fn is_valid(insn: &BpfInsn) -> bool {
+    let code = insn.code;
+    match bpf_class(code) {
+        BPF_RET => match bpf_rval(code) {
+            BPF_A | BPF_K => true,
+            _ => false,
+        },
+        BPF_LD => match bpf_mode(code) {
+            BPF_ABS | BPF_IND => is_load_size(code),
+            BPF_IMM | BPF_LEN | BPF_MEM => true,
+            _ => false,
+        },
+        BPF_LDX => match bpf_mode(code) {
+            BPF_MSH | BPF_IMM | BPF_LEN | BPF_MEM => true,
+            _ => false,
+        },
+        BPF_ST | BPF_STX => true,
+        BPF_ALU => match bpf_op(code) {
+            BPF_ADD | BPF_SUB | BPF_MUL | BPF_DIV | BPF_MOD | BPF_AND | BPF_OR | BPF_XOR
+            | BPF_LSH | BPF_RSH | BPF_NEG => true,
+            _ => false,
+        },
+        BPF_JMP => match bpf_op(code) {
+            BPF_JA | BPF_JEQ | BPF_JGT | BPF_JGE | BPF_JSET => true,
+            _ => false,
+        },
+        BPF_MISC => match bpf_miscop(code) {
+            BPF_TAX | BPF_TXA => true,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+// evaluate a conditional jump
+fn eval_jump(op: u16, a: u32, src: u32) -> bool {
+    match op {
+        BPF_JEQ => a == src,
+        BPF_JGT => a > src,
+        BPF_JGE => a >= src,
+        BPF_JSET => a & src != 0,
+        _ => unreachable!(),
+    }
+}
+
+// evaluate an ALU operation. returns None if it should be left to the runtime
+fn eval_alu(op: u16, a: u32, src: u32) -> Option<u32> {
+    match op {
+        BPF_ADD => Some(a.wrapping_add(src)),
+        BPF_SUB => Some(a.wrapping_sub(src)),
+        BPF_MUL => Some(a.wrapping_mul(src)),
+        BPF_DIV if src != 0 => Some(a / src),
+        BPF_MOD if src != 0 => Some(a % src),
+        BPF_AND => Some(a & src),
+        BPF_OR => Some(a | src),
+        BPF_XOR => Some(a ^ src),
+        BPF_LSH if src < 32 => Some(a << src),
+        BPF_RSH if src < 32 => Some(a >> src),
+        BPF_NEG => Some(a.wrapping_neg()),
+        _ => None,
+    }
+}
+
+// true if `A op src` does not change A
+fn is_identity(op: u16, src: u32) -> bool {
+    match (op, src) {
+        (BPF_ADD, 0) | (BPF_SUB, 0) | (BPF_OR, 0) | (BPF_XOR, 0) | (BPF_LSH, 0) | (BPF_RSH, 0) => {
+            true
+        }
+        (BPF_MUL, 1) | (BPF_DIV, 1) => true,
+        (BPF_AND, 0xffff_ffff) => true,
+        _ => false,
+    }
+}
+
+impl Program {
+    fn new(insns: &[BpfInsn]) -> Option<Self> {
+        let n = insns.len();
+        if n == 0 || !is_ret(&insns[n - 1]) {
+            return None;
+        }
+
+        let mut nodes = Vec::with_capacity(n);
+        for (i, insn) in insns.iter().enumerate() {
+            if !is_valid(insn) {
+                return None;
+            }
+            let (jt, jf) = if is_ja(insn) {
+                let t = (i + 1).checked_add(insn.k as usize)?;
+                (t, t)
+            } else if is_jump(insn) {
+                (i + 1 + insn.jt as usize, i + 1 + insn.jf as usize)
+            } else {
+                (i + 1, i + 1)
+            };
+            if !is_ret(insn) && (jt >= n || jf >= n) {
+                return None;
+            }
+            if uses_mem(insn) && insn.k as usize >= BPF_MEMWORDS as usize {
+                return None;
+            }
+            nodes.push(Node {
+                insn: *insn,
+                jt,
+                jf,
+                removed: false,
+            });
+        }
+        Some(Program { nodes })
+    }
+
+    fn successors(&self, i: usize) -> Vec<usize> {
+        let node = &self.nodes[i];
+        if node.removed {
+            vec![i + 1]
+        } else if is_ret(&node.insn) {
+            vec![]
+        } else if is_jump(&node.insn) && !is_ja(&node.insn) {
+            vec![node.jt, node.jf]
+        } else {
+            vec![node.jt]
+        }
+    }
+
+    fn rewrite(&mut self, i: usize, code: u16, k: u32) {
+        let node = &mut self.nodes[i];
+        node.insn = BpfInsn::new(code, 0, 0, k);
+        if !is_jump(&node.insn) {
+            node.jt = i + 1;
+            node.jf = i + 1;
+        }
+    }
+
+    fn rewrite_ja(&mut self, i: usize, target: usize) {
+        if target == i + 1 {
+            self.nodes[i].removed = true;
+        } else {
+            self.rewrite(i, BPF_JMP | BPF_JA, 0);
+            self.nodes[i].jt = target;
+            self.nodes[i].jf = target;
+        }
+    }
+
+    // follow the edge `from` -> `to` through jumps whose result is known by `state`.
+    // jumps do not modify any register, so skipping them is always safe
+    fn thread(&self, from: usize, mut to: usize, state: &State) -> usize {
+        let conditional = !is_ja(&self.nodes[from].insn) && is_jump(&self.nodes[from].insn);
+        loop {
+            let node = &self.nodes[to];
+            if node.removed || !is_jump(&node.insn) {
+                return to;
+            }
+            let next = if is_ja(&node.insn) {
+                node.jt
+            } else {
+                match self.decide(&node.insn, state) {
+                    Some(true) => node.jt,
+                    Some(false) => node.jf,
+                    None => return to,
+                }
+            };
+            // jt and jf are only 8 bits wide
+            if conditional && next - (from + 1) > 0xff {
+                return to;
+            }
+            to = next;
+        }
+    }
+
+    // result of a conditional jump if it is known at compile time
+    fn decide(&self, insn: &BpfInsn, state: &State) -> Option<bool> {
+        let op = bpf_op(insn.code);
+        let src = if bpf_src(insn.code) == BPF_X {
+            if op == BPF_JEQ && state.a.same(&state.x) {
+                return Some(true);
+            }
+            state.x.konst
+        } else {
+            Some(insn.k)
+        };
+        match (state.a.konst, src) {
+            (Some(a), Some(src)) => Some(eval_jump(op, a, src)),
+            _ => None,
+        }
+    }
+
+    fn merge(states: &mut Vec<Option<State>>, i: usize, state: State) {
+        let merged = match states[i].take() {
+            Some(s) => s.join(&state),
+            None => state,
+        };
+        states[i] = Some(merged);
+    }
+
+    // constant propagation, redundant load elimination, jump folding and jump threading
+    fn fold(&mut self) -> bool {
+        let n = self.nodes.len();
+        let mut changed = false;
+        let mut states: Vec<Option<State>> = vec![None; n];
+        states[0] = Some(State::entry());
+
+        for i in 0..n {
+            let mut s = match states[i].take() {
+                Some(s) => s,
+                // unreachable, will be removed by compact()
+                None => continue,
+            };
+            if self.nodes[i].removed {
+                Self::merge(&mut states, i + 1, s);
+                continue;
+            }
+
+            let insn = self.nodes[i].insn;
+            let code = insn.code;
+            let k = insn.k;
+            let mut remove = false;
+
+            match bpf_class(code) {
+                BPF_LD => {
+                    let v = match bpf_mode(code) {
+                        BPF_IMM => Val::konst(k),
+                        BPF_ABS => Val::sym(Sym::Pkt(code, k)),
+                        BPF_IND => match (s.x.konst.and_then(|x| x.checked_add(k)), &s.x.sym) {
+                            (Some(off), _) => {
+                                let code = BPF_LD | bpf_size(code) | BPF_ABS;
+                                self.rewrite(i, code, off);
+                                changed = true;
+                                Val::sym(Sym::Pkt(code, off))
+                            }
+                            (None, &Some(ref x)) => {
+                                Val::sym(Sym::Ind(code, k, Box::new(x.clone())))
+                            }
+                            (None, &None) => Val::sym(Sym::Def(i)),
+                        },
+                        BPF_LEN => Val::sym(Sym::Len),
+                        BPF_MEM => {
+                            State::name(&mut s.mem[k as usize], i);
+                            let v = s.mem[k as usize].clone();
+                            if let Some(c) = v.konst {
+                                self.rewrite(i, BPF_LD | BPF_IMM, c);
+                                changed = true;
+                            }
+                            v
+                        }
+                        _ => Val::sym(Sym::Def(i)),
+                    };
+                    if s.a.same(&v) {
+                        remove = true;
+                    } else {
+                        s.a = v;
+                    }
+                }
+
+                BPF_LDX => {
+                    let v = match bpf_mode(code) {
+                        BPF_IMM => Val::konst(k),
+                        BPF_MSH => Val::sym(Sym::Msh(k)),
+                        BPF_LEN => Val::sym(Sym::Len),
+                        BPF_MEM => {
+                            State::name(&mut s.mem[k as usize], i);
+                            let v = s.mem[k as usize].clone();
+                            if let Some(c) = v.konst {
+                                self.rewrite(i, BPF_LDX | BPF_IMM, c);
+                                changed = true;
+                            }
+                            v
+                        }
+                        _ => Val::sym(Sym::Def(i)),
+                    };
+                    if s.x.same(&v) {
+                        remove = true;
+                    } else {
+                        s.x = v;
+                    }
+                }
+
+                BPF_ST => {
+                    State::name(&mut s.a, i);
+                    if s.mem[k as usize].same(&s.a) {
+                        remove = true;
+                    } else {
+                        s.mem[k as usize] = s.a.clone();
+                    }
+                }
+
+                BPF_STX => {
+                    State::name(&mut s.x, i);
+                    if s.mem[k as usize].same(&s.x) {
+                        remove = true;
+                    } else {
+                        s.mem[k as usize] = s.x.clone();
+                    }
+                }
+
+                BPF_ALU => {
+                    let op = bpf_op(code);
+                    let mut src = if op == BPF_NEG {
+                        Some(0)
+                    } else if bpf_src(code) == BPF_X {
+                        s.x.konst
+                    } else {
+                        Some(k)
+                    };
+                    // division by zero makes the filter return 0, leave it to the runtime
+                    if src == Some(0) && (op == BPF_DIV || op == BPF_MOD) {
+                        src = None;
+                    }
+
+                    match (s.a.konst, src) {
+                        (Some(a), Some(src)) if eval_alu(op, a, src).is_some() => {
+                            let v = eval_alu(op, a, src).unwrap();
+                            self.rewrite(i, BPF_LD | BPF_IMM, v);
+                            changed = true;
+                            s.a = Val::konst(v);
+                        }
+                        (_, Some(src)) if op != BPF_NEG && is_identity(op, src) => {
+                            remove = true;
+                        }
+                        (_, Some(src)) => {
+                            // the kernel rejects lsh #k and rsh #k with k >= 32
+                            let shift = op == BPF_LSH || op == BPF_RSH;
+                            if op != BPF_NEG && bpf_src(code) == BPF_X && !(shift && src >= 32) {
+                                self.rewrite(i, BPF_ALU | op | BPF_K, src);
+                                changed = true;
+                            }
+                            s.a = Val::sym(Sym::Def(i));
+                        }
+                        (_, None) => {
+                            s.a = Val::sym(Sym::Def(i));
+                        }
+                    }
+                }
+
+                BPF_MISC => {
+                    if bpf_miscop(code) == BPF_TAX {
+                        State::name(&mut s.a, i);
+                        if s.x.same(&s.a) {
+                            remove = true;
+                        } else {
+                            s.x = s.a.clone();
+                        }
+                    } else {
+                        State::name(&mut s.x, i);
+                        if s.a.same(&s.x) {
+                            remove = true;
+                        } else {
+                            s.a = s.x.clone();
+                        }
+                    }
+                }
+
+                BPF_RET => {
+                    if bpf_rval(code) == BPF_A {
+                        if let Some(a) = s.a.konst {
+                            self.rewrite(i, BPF_RET | BPF_K, a);
+                            changed = true;
+                        }
+                    }
+                    continue;
+                }
+
+                BPF_JMP => {
+                    let op = bpf_op(code);
+                    if op == BPF_JA {
+                        let target = self.nodes[i].jt;
+                        let target = self.thread(i, target, &s);
+                        if is_ret(&self.nodes[target].insn) && !self.nodes[target].removed {
+                            // jump to return, just return here
+                            let ret = self.nodes[target].insn;
+                            self.rewrite(i, ret.code, ret.k);
+                            changed = true;
+                            continue;
+                        }
+                        if target != self.nodes[i].jt || target == i + 1 {
+                            self.rewrite_ja(i, target);
+                            changed = true;
+                        }
+                        Self::merge(&mut states, target, s);
+                        continue;
+                    }
+
+                    if bpf_src(code) == BPF_X {
+                        if let Some(x) = s.x.konst {
+                            let (jt, jf) = (self.nodes[i].jt, self.nodes[i].jf);
+                            self.rewrite(i, BPF_JMP | op | BPF_K, x);
+                            self.nodes[i].jt = jt;
+                            self.nodes[i].jf = jf;
+                            changed = true;
+                        }
+                    }
+                    let insn = self.nodes[i].insn;
+
+                    if let Some(cond) = self.decide(&insn, &s) {
+                        let target = if cond {
+                            self.nodes[i].jt
+                        } else {
+                            self.nodes[i].jf
+                        };
+                        self.rewrite_ja(i, target);
+                        changed = true;
+                        Self::merge(&mut states, target, s);
+                        continue;
+                    }
+
+                    // what we learn from the true branch of jeq
+                    let mut st = s.clone();
+                    if op == BPF_JEQ {
+                        if bpf_src(insn.code) == BPF_K {
+                            st.a.konst = Some(insn.k);
+                        } else if st.a.konst.is_some() {
+                            st.x.konst = st.a.konst;
+                        } else {
+                            st.a.konst = st.x.konst;
+                        }
+                    }
+                    let sf = s;
+
+                    let (jt, jf) = (self.nodes[i].jt, self.nodes[i].jf);
+                    let jt = self.thread(i, jt, &st);
+                    let jf = self.thread(i, jf, &sf);
+                    if jt != self.nodes[i].jt || jf != self.nodes[i].jf {
+                        self.nodes[i].jt = jt;
+                        self.nodes[i].jf = jf;
+                        changed = true;
+                    }
+                    if jt == jf {
+                        self.rewrite_ja(i, jt);
+                        changed = true;
+                        Self::merge(&mut states, jt, st.join(&sf));
+                    } else {
+                        Self::merge(&mut states, jt, st);
+                        Self::merge(&mut states, jf, sf);
+                    }
+                    continue;
+                }
+
+                _ => unreachable!(),
+            }
+
+            if remove {
+                self.nodes[i].removed = true;
+                changed = true;
+            }
+            Self::merge(&mut states, i + 1, s);
+        }
+
+        changed
+    }
+
+    // remove instructions whose result is never used.
+    // liveness is a bitset of M[0..16], A (bit 16) and X (bit 17)
+    fn eliminate_dead_code(&mut self) -> bool {
+        const A: u32 = 1 << 16;
+        const X: u32 = 1 << 17;
+
+        let n = self.nodes.len();
+        let mut changed = false;
+        let mut live_in = vec![0u32; n];
+
+        for i in (0..n).rev() {
+            let live_out = self.successors(i)
+                .iter()
+                .fold(0, |acc, &succ| acc | live_in[succ]);
+            let node = self.nodes[i];
+            if node.removed {
+                live_in[i] = live_out;
+                continue;
+            }
+
+            let code = node.insn.code;
+            let mem = if uses_mem(&node.insn) {
+                1 << node.insn.k
+            } else {
+                0
+            };
+            // (defs, uses, may fail at runtime)
+            let (def, uses, fallible) = match bpf_class(code) {
+                BPF_LD => match bpf_mode(code) {
+                    BPF_ABS => (A, 0, true),
+                    BPF_IND => (A, X, true),
+                    BPF_MEM => (A, mem, false),
+                    _ => (A, 0, false),
+                },
+                BPF_LDX => match bpf_mode(code) {
+                    BPF_MSH => (X, 0, true),
+                    BPF_MEM => (X, mem, false),
+                    _ => (X, 0, false),
+                },
+                BPF_ST => (mem, A, false),
+                BPF_STX => (mem, X, false),
+                BPF_ALU => {
+                    let op = bpf_op(code);
+                    let div = op == BPF_DIV || op == BPF_MOD;
+                    if op == BPF_NEG || bpf_src(code) == BPF_K {
+                        (A, A, div && node.insn.k == 0)
+                    } else {
+                        (A, A | X, div)
+                    }
+                }
+                BPF_MISC => if bpf_miscop(code) == BPF_TAX {
+                    (X, A, false)
+                } else {
+                    (A, X, false)
+                },
+                BPF_JMP => if bpf_op(code) == BPF_JA {
+                    (0, 0, false)
+                } else if bpf_src(code) == BPF_K {
+                    (0, A, false)
+                } else {
+                    (0, A | X, false)
+                },
+                BPF_RET => if bpf_rval(code) == BPF_A {
+                    (0, A, false)
+                } else {
+                    (0, 0, false)
+                },
+                _ => unreachable!(),
+            };
+
+            if def != 0 && live_out & def == 0 && !fallible {
+                self.nodes[i].removed = true;
+                changed = true;
+                live_in[i] = live_out;
+            } else {
+                live_in[i] = (live_out & !def) | uses;
+            }
+        }
+
+        changed
+    }
+
+    // drop removed and unreachable instructions
+    fn compact(&mut self) {
+        let n = self.nodes.len();
+        let mut reachable = vec![false; n];
+        reachable[0] = true;
+        for i in 0..n {
+            if reachable[i] {
+                for succ in self.successors(i) {
+                    reachable[succ] = true;
+                }
+            }
+        }
+
+        let kept: Vec<bool> = (0..n)
+            .map(|i| reachable[i] && !self.nodes[i].removed)
+            .collect();
+        let mut new_idx = vec![0; n + 1];
+        let mut next = kept.iter().filter(|&&k| k).count();
+        new_idx[n] = next;
+        for i in (0..n).rev() {
+            if kept[i] {
+                next -= 1;
+            }
+            // removed instructions fall through to the next kept one
+            new_idx[i] = next;
+        }
+
+        let nodes = self.nodes
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| kept[i])
+            .map(|(_, node)| Node {
+                insn: node.insn,
+                jt: new_idx[node.jt],
+                jf: new_idx[node.jf],
+                removed: false,
+            })
+            .enumerate()
+            .map(|(i, mut node)| {
+                if !is_jump(&node.insn) {
+                    node.jt = i + 1;
+                    node.jf = i + 1;
+                }
+                node
+            })
+            .collect();
+        self.nodes = nodes;
+    }
+
+    fn to_insns(&self) -> Vec<BpfInsn> {
+        self.nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| {
+                let insn = node.insn;
+                if is_ja(&insn) {
+                    BpfInsn::new(insn.code, 0, 0, (node.jt - i - 1) as u32)
+                } else if is_jump(&insn) {
+                    BpfInsn::new(
+                        insn.code,
+                        (node.jt - i - 1) as u8,
+                        (node.jf - i - 1) as u8,
+                        insn.k,
+                    )
+                } else {
+                    insn
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cbpf::interpreter::{Interpreter, Simple};
+
+    fn packets() -> Vec<Vec<u8>> {
+        let mut ipv4_tcp = vec![0u8; 64];
+        ipv4_tcp[12] = 0x08; // ethertype ipv4
+        ipv4_tcp[14] = 0x45; // ihl = 5
+        ipv4_tcp[23] = 0x06; // tcp
+        ipv4_tcp[36] = 0x00; // dst port 80
+        ipv4_tcp[37] = 0x50;
+
+        let mut ipv6 = vec![0u8; 64];
+        ipv6[12] = 0x86;
+        ipv6[13] = 0xdd;
+        ipv6[20] = 0x06;
+        ipv6[55] = 0x50;
+
+        let mut arp = vec![0xffu8; 42];
+        arp[12] = 0x08;
+        arp[13] = 0x06;
+
+        vec![ipv4_tcp, ipv6, arp, vec![0x08, 0x00], vec![]]
+    }
+
+    fn check(insns: &[BpfInsn]) -> Vec<BpfInsn> {
+        let optimized = optimize(insns);
+        assert!(optimized.len() <= insns.len());
+        assert!(is_ret(optimized.last().unwrap()));
+        for data in packets() {
+            assert_eq!(
+                Simple::run(insns, &data).ok(),
+                Simple::run(&optimized, &data).ok()
+            );
+        }
+        optimized
+    }
+
+    #[test]
+    fn constant_propagation() {
+        // ld #2; st M[0]; ldx M[0]; ld #10; add x; ret a
+        let insns = [
+            BpfInsn::new(BPF_LD_IMM, 0, 0, 2),
+            BpfInsn::new(BPF_ST, 0, 0, 0),
+            BpfInsn::new(BPF_LDX_MEM, 0, 0, 0),
+            BpfInsn::new(BPF_LD_IMM, 0, 0, 10),
+            BpfInsn::new(BPF_ADD_X, 0, 0, 0),
+            BpfInsn::new(BPF_RET_A, 0, 0, 0),
+        ];
+        let optimized = check(&insns);
+        assert_eq!(optimized.len(), 1);
+        assert_eq!(optimized[0].code, BPF_RET_K);
+        assert_eq!(optimized[0].k, 12);
+    }
+
+    #[test]
+    fn unwritten_mem() {
+        // ldx M[3]; ld M[5]; add x; ret a
+        let insns = [
+            BpfInsn::new(BPF_LDX_MEM, 0, 0, 3),
+            BpfInsn::new(BPF_LD_MEM, 0, 0, 5),
+            BpfInsn::new(BPF_ADD_X, 0, 0, 0),
+            BpfInsn::new(BPF_RET_A, 0, 0, 0),
+        ];
+        let optimized = check(&insns);
+        assert_eq!(optimized.len(), 1);
+        assert_eq!(optimized[0].code, BPF_RET_K);
+        assert_eq!(optimized[0].k, 0);
+    }
+
+    #[test]
+    fn large_shift() {
+        // ldx #40; ldh [12]; lsh x; rsh x; ret a
+        let insns = [
+            BpfInsn::new(BPF_LDX_IMM, 0, 0, 40),
+            BpfInsn::new(BPF_LD_H_ABS, 0, 0, 12),
+            BpfInsn::new(BPF_LSH_X, 0, 0, 0),
+            BpfInsn::new(BPF_RSH_X, 0, 0, 0),
+            BpfInsn::new(BPF_RET_A, 0, 0, 0),
+        ];
+        let optimized = check(&insns);
+        assert!(optimized.iter().all(|i| i.code != BPF_LSH_K && i.code != BPF_RSH_K));
+        assert_eq!(optimized.iter().filter(|i| i.code == BPF_LSH_X).count(), 1);
+    }
+
+    #[test]
+    fn redundant_load() {
+        // ldh [12]; jeq #0x86dd, L1, L2; L1: ret #1; L2: ldh [12]; jeq #0x800, L3, L4; ...
+        let insns = [
+            BpfInsn::new(BPF_LD_H_ABS, 0, 0, 12),
+            BpfInsn::new(BPF_JEQ_K, 0, 1, 0x86dd),
+            BpfInsn::new(BPF_RET_K, 0, 0, 1),
+            BpfInsn::new(BPF_LD_H_ABS, 0, 0, 12),
+            BpfInsn::new(BPF_JEQ_K, 0, 1, 0x0800),
+            BpfInsn::new(BPF_RET_K, 0, 0, 2),
+            BpfInsn::new(BPF_RET_K, 0, 0, 0),
+        ];
+        let optimized = check(&insns);
+        assert_eq!(optimized.len(), 6);
+    }
+
+    #[test]
+    fn dead_store() {
+        // ldh [12]; st M[1]; ldb [23]; st M[1]; ld M[1]; ret a
+        let insns = [
+            BpfInsn::new(BPF_LD_H_ABS, 0, 0, 12),
+            BpfInsn::new(BPF_ST, 0, 0, 1),
+            BpfInsn::new(BPF_LD_B_ABS, 0, 0, 23),
+            BpfInsn::new(BPF_ST, 0, 0, 1),
+            BpfInsn::new(BPF_LD_MEM, 0, 0, 1),
+            BpfInsn::new(BPF_RET_A, 0, 0, 0),
+        ];
+        let optimized = check(&insns);
+        // the packet loads must be kept since they may fail
+        assert_eq!(optimized.len(), 3);
+        assert!(optimized.iter().all(|insn| !uses_mem(insn)));
+    }
+
+    #[test]
+    fn jump_threading() {
+        // ldh [12]; jeq #0x800, L1, L3; L1: jeq #0x800, L2, L4; L2: ja L5; ...
+        let insns = [
+            BpfInsn::new(BPF_LD_H_ABS, 0, 0, 12),
+            BpfInsn::new(BPF_JEQ_K, 0, 3, 0x0800),
+            BpfInsn::new(BPF_JEQ_K, 0, 1, 0x0800),
+            BpfInsn::new(BPF_JMP_JA, 0, 0, 2),
+            BpfInsn::new(BPF_RET_K, 0, 0, 3),
+            BpfInsn::new(BPF_RET_K, 0, 0, 0),
+            BpfInsn::new(BPF_RET_K, 0, 0, 2),
+        ];
+        let optimized = check(&insns);
+        assert_eq!(optimized.len(), 4);
+        assert!(optimized.iter().all(|insn| !is_ja(insn)));
+    }
+
+    #[test]
+    fn indirect_load() {
+        // ldx #14; ldh [x + 2]; jeq #0x800, L1, L2; L1: ldx msh [14]; ldb [x + 14]; ldb [x + 14]; ret a
+        let insns = [
+            BpfInsn::new(BPF_LDX_IMM, 0, 0, 14),
+            BpfInsn::new(BPF_LD_H_IND, 0, 0, 2),
+            BpfInsn::new(BPF_JEQ_K, 0, 4, 0x0800),
+            BpfInsn::new(BPF_LDX_B_MSH, 0, 0, 14),
+            BpfInsn::new(BPF_LD_B_IND, 0, 0, 14),
+            BpfInsn::new(BPF_LD_B_IND, 0, 0, 14),
+            BpfInsn::new(BPF_RET_A, 0, 0, 0),
+            BpfInsn::new(BPF_RET_K, 0, 0, 0),
+        ];
+        let optimized = check(&insns);
+        assert_eq!(optimized.len(), 6);
+        assert_eq!(optimized[0].code, BPF_LD_H_ABS);
+        assert_eq!(optimized[0].k, 16);
+    }
+
+    #[test]
+    fn keep_invalid_program() {
+        let insns = [
+            BpfInsn::new(BPF_LD_IMM, 0, 0, 2),
+            BpfInsn::new(BPF_JEQ_K, 0, 5, 2),
+        ];
+        assert_eq!(optimize(&insns).len(), 2);
+
+        // invalid opcodes are returned unchanged
+        let invalid: Vec<Vec<BpfInsn>> = vec![
+            // ld #1; jmp|0x50|k; ret #0
+            vec![
+                BpfInsn::new(BPF_LD_IMM, 0, 0, 1),
+                BpfInsn::new(BPF_JMP | 0x50 | BPF_K, 0, 0, 1),
+                BpfInsn::new(BPF_RET_K, 0, 0, 0),
+            ],
+            // ld [0] of an invalid size
+            vec![
+                BpfInsn::new(BPF_LD | 0x18 | BPF_ABS, 0, 0, 0),
+                BpfInsn::new(BPF_RET_A, 0, 0, 0),
+            ],
+            // ldx [0]; st M[0]; ret #0
+            vec![
+                BpfInsn::new(BPF_LDX | BPF_W | BPF_ABS, 0, 0, 0),
+                BpfInsn::new(BPF_ST, 0, 0, 0),
+                BpfInsn::new(BPF_RET_K, 0, 0, 0),
+            ],
+            // ld M[16]; ret a
+            vec![
+                BpfInsn::new(BPF_LD_MEM, 0, 0, 16),
+                BpfInsn::new(BPF_RET_A, 0, 0, 0),
+            ],
+        ];
+        let fields = |insns: &[BpfInsn]| -> Vec<_> {
+            insns.iter().map(|i| (i.code, i.jt, i.jf, i.k)).collect()
+        };
+        for insns in &invalid {
+            assert_eq!(fields(&optimize(insns)), fields(insns));
+        }
+    }
+}
@@ -0,0 +1,605 @@
+// ORC JIT session hosting many filters
+//
+// MCJIT (`Converter::jit_compile()`) creates an execution engine per filter. A `JitSession`
+// has one ORC JIT stack, to which each filter is added as a module and from which it is
+// removed (and its code memory is freed) when the filter is dropped. `CompiledFilter`
+// compiles its filters in a session shared by the process (`shared_session()`).
+// The ORC C API of LLVM 5 is not thread safe, so the stack is guarded by a mutex.
+// Conversion to IR and optimization, which do not touch the stack, run in parallel
+// on the worker threads of the session (`compile_async()`).
+// The API is removed in LLVM 12, so LLVM 15+ uses LLJIT instead, in which each filter
+// is tracked by a resource tracker and converted in its own thread-safe context.
+// LLJIT is thread safe, so its code generation also runs in parallel.
+
+use cbpf::opcode::BpfInsn;
+#[cfg(feature = "llvm-5")]
+use llvm::orc::*;
+#[cfg(not(feature = "llvm-5"))]
+use llvm::orc2::*;
+#[cfg(not(feature = "llvm-5"))]
+use llvm::orc2::lljit::*;
+use ::llvm::target_machine::*;
+#[cfg(feature = "llvm-5")]
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+#[cfg(not(feature = "llvm-5"))]
+use compat;
+use {aot, jit, trace, Converter, Func, Options};
+
+#[cfg(feature = "llvm-5")]
+type JitRef = LLVMOrcJITStackRef;
+#[cfg(not(feature = "llvm-5"))]
+type JitRef = LLVMOrcLLJITRef;
+
+#[cfg(feature = "llvm-5")]
+type ModuleHandle = LLVMOrcModuleHandle;
+#[cfg(not(feature = "llvm-5"))]
+type ModuleHandle = LLVMOrcResourceTrackerRef;
+
+struct Stack {
+    stack: Mutex<JitRef>,
+    next_id: AtomicUsize,
+}
+
+// the stack is accessed only with the lock (except LLJIT, see `with_stack()`)
+unsafe impl Send for Stack {}
+unsafe impl Sync for Stack {}
+
+impl Stack {
+    // the ORC C API of LLVM 5 is used only with the lock
+    #[cfg(feature = "llvm-5")]
+    fn with_stack<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(JitRef) -> R,
+    {
+        let stack = self.stack.lock().unwrap();
+        f(*stack)
+    }
+
+    // LLJIT is thread safe, so the lock is held only to read the pointer, and threads
+    // generate the code of their modules in parallel
+    #[cfg(not(feature = "llvm-5"))]
+    fn with_stack<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(JitRef) -> R,
+    {
+        let stack = *self.stack.lock().unwrap();
+        f(stack)
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        // the target machine is owned by the stack
+        unsafe {
+            dispose(*self.stack.lock().unwrap());
+        }
+    }
+}
+
+#[cfg(feature = "llvm-5")]
+unsafe fn dispose(stack: JitRef) {
+    LLVMOrcDisposeInstance(stack);
+}
+
+#[cfg(not(feature = "llvm-5"))]
+unsafe fn dispose(stack: JitRef) {
+    let err = LLVMOrcDisposeLLJIT(stack);
+    if !err.is_null() {
+        ::llvm::error::LLVMConsumeError(err);
+    }
+}
+
+#[cfg(feature = "llvm-5")]
+impl Stack {
+    fn error(&self, stack: LLVMOrcJITStackRef) -> String {
+        unsafe {
+            CStr::from_ptr(LLVMOrcGetErrorMsg(stack))
+                .to_string_lossy()
+                .into_owned()
+        }
+    }
+}
+
+// context of a filter on LLVM 15+, which is shared by the converter and LLJIT
+#[cfg(not(feature = "llvm-5"))]
+struct ThreadSafeContext(LLVMOrcThreadSafeContextRef);
+
+#[cfg(not(feature = "llvm-5"))]
+impl Drop for ThreadSafeContext {
+    fn drop(&mut self) {
+        // the context is disposed when LLJIT also releases it
+        unsafe {
+            LLVMOrcDisposeThreadSafeContext(self.0);
+        }
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+pub struct JitSession {
+    stack: Arc<Stack>,
+    jobs: Option<Sender<Job>>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+pub struct OrcFilter {
+    stack: Arc<Stack>,
+    // the module is moved to the stack when it is compiled
+    converter: Mutex<Converter>,
+    // dropped after the converter, whose context it owns
+    #[cfg(not(feature = "llvm-5"))]
+    context: ThreadSafeContext,
+    insns: Vec<BpfInsn>,
+    symbol: String,
+    handle: Mutex<Option<ModuleHandle>>,
+    // address of the function, 0 until compiled
+    func: AtomicUsize,
+    // error of the compilation, which is not retried since the module is gone
+    error: Mutex<Option<String>>,
+    lazy: bool,
+}
+
+// the converter is accessed only with the lock
+unsafe impl Send for OrcFilter {}
+unsafe impl Sync for OrcFilter {}
+
+// resolve external symbols of the filters: the trace hook and the C library
+#[cfg(feature = "llvm-5")]
+extern "C" fn resolve(name: *const ::libc::c_char, _ctx: *mut ::libc::c_void) -> u64 {
+    let name = unsafe { CStr::from_ptr(name) };
+    let name = name.to_string_lossy();
+    // symbols have a leading '_' on some platforms
+    if name.trim_start_matches('_') == trace::HOOK_NAME {
+        return trace::hook as usize as u64;
+    }
+    let name = CString::new(name.trim_start_matches('_')).unwrap();
+    unsafe { ::libc::dlsym(::libc::RTLD_DEFAULT, name.as_ptr()) as u64 }
+}
+
+// the session of `CompiledFilter`, created when the first filter is compiled.
+// it has no worker thread since `CompiledFilter::new()` compiles on the calling thread
+pub fn shared_session() -> Result<&'static JitSession, String> {
+    static SESSION: OnceLock<Result<JitSession, String>> = OnceLock::new();
+    match *SESSION.get_or_init(|| JitSession::new(0)) {
+        Ok(ref session) => Ok(session),
+        Err(ref e) => Err(e.clone()),
+    }
+}
+
+impl JitSession {
+    // `threads` is the number of threads which compile filters in `compile_async()`
+    pub fn new(threads: usize) -> Result<Self, String> {
+        unsafe {
+            let tm = aot::target_machine(
+                LLVMRelocMode::LLVMRelocDefault,
+                LLVMCodeModel::LLVMCodeModelJITDefault,
+            )?;
+            let stack = create_stack(tm)?;
+
+            let stack = Arc::new(Stack {
+                stack: Mutex::new(stack),
+                next_id: AtomicUsize::new(0),
+            });
+            let (tx, rx) = channel::<Job>();
+            let rx = Arc::new(Mutex::new(rx));
+            let workers = (0..threads)
+                .map(|_| {
+                    let rx = rx.clone();
+                    thread::spawn(move || loop {
+                        let job = rx.lock().unwrap().recv();
+                        match job {
+                            Ok(job) => job(),
+                            // the session is dropped
+                            Err(_) => break,
+                        }
+                    })
+                })
+                .collect();
+
+            Ok(JitSession {
+                stack,
+                jobs: Some(tx),
+                workers,
+            })
+        }
+    }
+
+    // convert the program and add it to the session.
+    // with `lazy`, the code is generated when the filter is run for the first time
+    pub fn compile(
+        &self,
+        insns: &[BpfInsn],
+        options: Options,
+        optimization: bool,
+        lazy: bool,
+    ) -> Result<OrcFilter, String> {
+        compile(&self.stack, insns, options, optimization, lazy)
+    }
+
+    // `compile()` on a worker thread
+    pub fn compile_async(
+        &self,
+        insns: &[BpfInsn],
+        options: Options,
+        optimization: bool,
+        lazy: bool,
+    ) -> Receiver<Result<OrcFilter, String>> {
+        let (tx, rx) = channel();
+        let stack = self.stack.clone();
+        let insns = insns.to_vec();
+        let job: Job = Box::new(move || {
+            let _ = tx.send(compile(&stack, &insns, options, optimization, lazy));
+        });
+        match self.jobs {
+            Some(ref jobs) if !self.workers.is_empty() => {
+                jobs.send(job).unwrap();
+            }
+            // no worker thread
+            _ => job(),
+        }
+        rx
+    }
+}
+
+#[cfg(feature = "llvm-5")]
+unsafe fn create_stack(tm: LLVMTargetMachineRef) -> Result<JitRef, String> {
+    Ok(LLVMOrcCreateInstance(tm))
+}
+
+// LLJIT whose main JITDylib resolves the trace hook and the symbols of the process
+#[cfg(not(feature = "llvm-5"))]
+unsafe fn create_stack(tm: LLVMTargetMachineRef) -> Result<JitRef, String> {
+    let builder = LLVMOrcCreateLLJITBuilder();
+    LLVMOrcLLJITBuilderSetJITTargetMachineBuilder(
+        builder,
+        LLVMOrcJITTargetMachineBuilderCreateFromTargetMachine(tm),
+    );
+    let mut jit = ptr::null_mut();
+    let err = LLVMOrcCreateLLJIT(&mut jit, builder);
+    if !err.is_null() {
+        return Err(compat::error_message(err));
+    }
+
+    let dylib = LLVMOrcLLJITGetMainJITDylib(jit);
+    let mut generator = ptr::null_mut();
+    let err = LLVMOrcCreateDynamicLibrarySearchGeneratorForProcess(
+        &mut generator,
+        LLVMOrcLLJITGetGlobalPrefix(jit),
+        None,
+        ptr::null_mut(),
+    );
+    if !err.is_null() {
+        dispose(jit);
+        return Err(compat::error_message(err));
+    }
+    LLVMOrcJITDylibAddGenerator(dylib, generator);
+
+    let name = CString::new(trace::HOOK_NAME).unwrap();
+    let mut hook = LLVMOrcCSymbolMapPair {
+        Name: LLVMOrcLLJITMangleAndIntern(jit, name.as_ptr()),
+        Sym: LLVMJITEvaluatedSymbol {
+            Address: trace::hook as usize as u64,
+            Flags: LLVMJITSymbolFlags {
+                GenericFlags: LLVMJITSymbolGenericFlags::LLVMJITSymbolGenericFlagsExported as u8,
+                TargetFlags: 0,
+            },
+        },
+    };
+    let symbols = LLVMOrcAbsoluteSymbols(&mut hook, 1);
+    let err = LLVMOrcJITDylibDefine(dylib, symbols);
+    if !err.is_null() {
+        LLVMOrcDisposeMaterializationUnit(symbols);
+        dispose(jit);
+        return Err(compat::error_message(err));
+    }
+    Ok(jit)
+}
+
+impl Drop for JitSession {
+    fn drop(&mut self) {
+        // workers exit when the channel is closed
+        self.jobs = None;
+        for w in self.workers.drain(..) {
+            let _ = w.join();
+        }
+    }
+}
+
+fn compile(
+    stack: &Arc<Stack>,
+    insns: &[BpfInsn],
+    options: Options,
+    optimization: bool,
+    lazy: bool,
+) -> Result<OrcFilter, String> {
+    if options.perf_map || options.gdb_jit || options.cache_dir.is_some() {
+        return Err("perf_map, gdb_jit and cache_dir are not supported by ORC JIT".to_owned());
+    }
+    if options.ebpf.is_some() {
+        return Err("eBPF programs cannot be JIT-compiled".to_owned());
+    }
+    let id = stack.next_id.fetch_add(1, Ordering::SeqCst);
+    let symbol = format!("{}_{}", jit::symbol_name(&options.name, insns), id);
+    #[cfg(feature = "llvm-5")]
+    let mut converter = Converter::with_options(options);
+    #[cfg(not(feature = "llvm-5"))]
+    let context = unsafe { ThreadSafeContext(LLVMOrcCreateNewThreadSafeContext()) };
+    #[cfg(not(feature = "llvm-5"))]
+    let mut converter = unsafe {
+        Converter::with_context(
+            options,
+            LLVMOrcThreadSafeContextGetContext(context.0),
+            false,
+        )
+    };
+    converter.build(insns, optimization)?;
+
+    unsafe {
+        // the symbols of all filters are in one namespace, so make them unique
+        // or invisible from other modules
+        converter.internalize_helpers();
+        let name = CString::new(symbol.as_str()).unwrap();
+        ::llvm::core::LLVMSetValueName(converter.get_function("main"), name.as_ptr());
+        converter.symbol = symbol.clone();
+        if converter.options.profile {
+            let counters =
+                ::llvm::core::LLVMGetNamedGlobal(converter.module, cstr!("cbpf.counters"));
+            let name = CString::new(format!("{}.counters", symbol)).unwrap();
+            ::llvm::core::LLVMSetValueName(counters, name.as_ptr());
+        }
+    }
+
+    let filter = OrcFilter {
+        stack: stack.clone(),
+        converter: Mutex::new(converter),
+        #[cfg(not(feature = "llvm-5"))]
+        context,
+        insns: insns.to_vec(),
+        symbol,
+        handle: Mutex::new(None),
+        func: AtomicUsize::new(0),
+        error: Mutex::new(None),
+        lazy,
+    };
+    if !lazy {
+        filter.jit_compile()?;
+    }
+    Ok(filter)
+}
+
+impl OrcFilter {
+    // add the module to the stack and resolve the function
+    fn jit_compile(&self) -> Result<usize, String> {
+        // the lock of the converter serializes the compilation of this filter
+        let mut converter = self.converter.lock().unwrap();
+        // compiled by another thread
+        let func = self.func.load(Ordering::Acquire);
+        if func != 0 {
+            return Ok(func);
+        }
+        let mut error = self.error.lock().unwrap();
+        if let Some(ref e) = *error {
+            return Err(e.clone());
+        }
+
+        let result = self.stack.with_stack(|stack| unsafe {
+            let handle = self.add_module(stack, converter.module);
+            // the module is owned by the stack from now on
+            converter.module = ptr::null_mut();
+            handle.and_then(|handle| {
+                *self.handle.lock().unwrap() = Some(handle);
+                let func = lookup(stack, &self.symbol)?;
+                if converter.options.profile {
+                    let counters = lookup(stack, &format!("{}.counters", self.symbol))?;
+                    converter.counters = Some(counters as *mut u64);
+                }
+                Ok(func)
+            })
+        });
+        match result {
+            Ok(func) => self.func.store(func, Ordering::Release),
+            Err(ref e) => *error = Some(e.clone()),
+        }
+        result
+    }
+
+    #[cfg(feature = "llvm-5")]
+    unsafe fn add_module(
+        &self,
+        stack: JitRef,
+        module: ::llvm::prelude::LLVMModuleRef,
+    ) -> Result<ModuleHandle, String> {
+        let module = LLVMOrcMakeSharedModule(module);
+        let mut handle = 0;
+        let r = LLVMOrcAddEagerlyCompiledIR(
+            stack,
+            &mut handle,
+            module,
+            Some(resolve),
+            ptr::null_mut(),
+        );
+        LLVMOrcDisposeSharedModuleRef(module);
+        if r != LLVMOrcErrorCode::LLVMOrcErrSuccess {
+            return Err(self.stack.error(stack));
+        }
+        Ok(handle)
+    }
+
+    // the module is compiled when its symbols are looked up
+    #[cfg(not(feature = "llvm-5"))]
+    unsafe fn add_module(
+        &self,
+        stack: JitRef,
+        module: ::llvm::prelude::LLVMModuleRef,
+    ) -> Result<ModuleHandle, String> {
+        let tracker = LLVMOrcJITDylibCreateResourceTracker(LLVMOrcLLJITGetMainJITDylib(stack));
+        let module = LLVMOrcCreateNewThreadSafeModule(module, self.context.0);
+        let err = LLVMOrcLLJITAddLLVMIRModuleWithRT(stack, tracker, module);
+        if !err.is_null() {
+            LLVMOrcReleaseResourceTracker(tracker);
+            return Err(compat::error_message(err));
+        }
+        Ok(tracker)
+    }
+
+    // fails only if the code of a `lazy` filter cannot be generated. the error is
+    // returned again by the later runs
+    pub fn run(&self, data: &[u8]) -> Result<u32, String> {
+        let mut func = self.func.load(Ordering::Acquire);
+        if func == 0 {
+            func = self.jit_compile()?;
+        }
+        let func: Func = unsafe { ::std::mem::transmute(func) };
+        Ok(func(data.as_ptr() as *mut u8, data.len() as u32) as u32)
+    }
+
+    // whether the code is generated (it is always true unless `lazy`)
+    pub fn is_compiled(&self) -> bool {
+        !self.lazy || self.func.load(Ordering::Acquire) != 0
+    }
+
+    pub fn insns(&self) -> &[BpfInsn] {
+        &self.insns
+    }
+
+    // execution count of each instruction (only with `Options::profile`)
+    pub fn counters(&self) -> Option<Vec<u64>> {
+        self.converter.lock().unwrap().counters()
+    }
+
+    // number of times each conditional jump is taken (only with `Options::profile`)
+    pub fn taken_counters(&self) -> Option<Vec<u64>> {
+        self.converter.lock().unwrap().taken_counters()
+    }
+
+    pub fn reset_counters(&self) {
+        self.converter.lock().unwrap().reset_counters()
+    }
+}
+
+#[cfg(feature = "llvm-5")]
+unsafe fn lookup(stack: JitRef, name: &str) -> Result<usize, String> {
+    let mut mangled = ptr::null_mut();
+    let name = CString::new(name).unwrap();
+    LLVMOrcGetMangledSymbol(stack, &mut mangled, name.as_ptr());
+    let mut addr = 0;
+    let r = LLVMOrcGetSymbolAddress(stack, &mut addr, mangled);
+    LLVMOrcDisposeMangledSymbol(mangled);
+    if r != LLVMOrcErrorCode::LLVMOrcErrSuccess || addr == 0 {
+        Err(format!("{} is not found", name.to_string_lossy()))
+    } else {
+        Ok(addr as usize)
+    }
+}
+
+#[cfg(not(feature = "llvm-5"))]
+unsafe fn lookup(stack: JitRef, name: &str) -> Result<usize, String> {
+    let name = CString::new(name).unwrap();
+    let mut addr = 0;
+    let err = LLVMOrcLLJITLookup(stack, &mut addr, name.as_ptr());
+    if !err.is_null() {
+        return Err(compat::error_message(err));
+    }
+    Ok(addr as usize)
+}
+
+#[cfg(feature = "llvm-5")]
+unsafe fn remove_module(stack: JitRef, handle: ModuleHandle) {
+    LLVMOrcRemoveModule(stack, handle);
+}
+
+#[cfg(not(feature = "llvm-5"))]
+unsafe fn remove_module(_stack: JitRef, tracker: ModuleHandle) {
+    let err = LLVMOrcResourceTrackerRemove(tracker);
+    if !err.is_null() {
+        ::llvm::error::LLVMConsumeError(err);
+    }
+    LLVMOrcReleaseResourceTracker(tracker);
+}
+
+impl Drop for OrcFilter {
+    fn drop(&mut self) {
+        // free the code before the context of the module is disposed
+        if let Some(handle) = *self.handle.lock().unwrap() {
+            self.stack.with_stack(|stack| unsafe { remove_module(stack, handle) });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cbpf::opcode::*;
+    use filter::CompiledFilter;
+
+    fn program(k: u32) -> Vec<BpfInsn> {
+        // ldb [0]; jeq #k, L1, L2; L1: ret #1; L2: ret #0
+        vec![
+            BpfInsn::new(BPF_LD_B_ABS, 0, 0, 0),
+            BpfInsn::new(BPF_JEQ_K, 0, 1, k),
+            BpfInsn::new(BPF_RET_K, 0, 0, 1),
+            BpfInsn::new(BPF_RET_K, 0, 0, 0),
+        ]
+    }
+
+    #[test]
+    fn session() {
+        let session = JitSession::new(2).unwrap();
+        let f1 = session
+            .compile(&program(1), Options::default(), true, false)
+            .unwrap();
+        let f2 = session
+            .compile(&program(2), Options::default(), false, true)
+            .unwrap();
+        assert!(!f2.is_compiled());
+        assert_eq!(
+            (f1.run(&[1]), f1.run(&[2]), f1.run(&[])),
+            (Ok(1), Ok(0), Ok(0))
+        );
+        assert_eq!((f2.run(&[1]), f2.run(&[2])), (Ok(0), Ok(1)));
+        assert!(f2.is_compiled());
+        drop(f1);
+        assert_eq!(f2.run(&[2]), Ok(1));
+
+        let options = Options {
+            profile: true,
+            ..Default::default()
+        };
+        let receivers: Vec<_> = (0..8)
+            .map(|k| session.compile_async(&program(k), options.clone(), k % 2 == 0, false))
+            .collect();
+        for (k, rx) in receivers.into_iter().enumerate() {
+            let f = rx.recv().unwrap().unwrap();
+            assert_eq!(f.run(&[k as u8]), Ok(1));
+            assert_eq!(f.counters(), Some(vec![1, 1, 1, 0]));
+        }
+    }
+
+    #[test]
+    fn lazy_error() {
+        let session = JitSession::new(1).unwrap();
+        let mut f = session
+            .compile(&program(1), Options::default(), true, true)
+            .unwrap();
+        // the function cannot be found after the module is compiled
+        f.symbol = "cbpf_missing".to_owned();
+        let err = f.run(&[1]).unwrap_err();
+        assert_eq!(f.run(&[1]), Err(err));
+        assert!(!f.is_compiled());
+    }
+    #[test]
+    fn compiled_filter() {
+        // `CompiledFilter` is compiled in the shared session
+        let session = shared_session().unwrap();
+        let before = session.stack.next_id.load(Ordering::SeqCst);
+        let filter = CompiledFilter::new(&program(1), Options::default(), true).unwrap();
+        assert!(session.stack.next_id.load(Ordering::SeqCst) > before);
+        assert_eq!((filter.run(&[1]), filter.run(&[2])), (1, 0));
+    }
+}
@@ -0,0 +1,103 @@
+// profile-guided branch weights
+//
+// A branch profile has the number of times each conditional jump is taken and not taken,
+// which is recorded by running the filter compiled with `Options::profile`.
+// It is saved as text:
+//
+//   cbpf-branch-profile <hash of the program>
+//   <insn index> <taken> <not taken>
+//   ...
+
+use cbpf::opcode::*;
+use jit;
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct BranchProfile {
+    // (taken, not taken) of each instruction. (0, 0) if it is not a conditional jump
+    pub counts: Vec<(u64, u64)>,
+}
+
+pub fn is_conditional_jump(insn: &BpfInsn) -> bool {
+    bpf_class(insn.code) == BPF_JMP && bpf_op(insn.code) != BPF_JA
+}
+
+impl BranchProfile {
+    // executed[i]: execution count of the i-th instruction,
+    // taken[i]: number of times the i-th instruction jumped to jt.
+    // the counters may be read while the filter is running, and `taken` is read after
+    // `executed`, so it can be larger
+    pub fn from_counters(insns: &[BpfInsn], executed: &[u64], taken: &[u64]) -> Self {
+        let counts = insns
+            .iter()
+            .enumerate()
+            .map(|(i, insn)| {
+                if is_conditional_jump(insn) {
+                    (taken[i], executed[i].saturating_sub(taken[i]))
+                } else {
+                    (0, 0)
+                }
+            })
+            .collect();
+        BranchProfile { counts }
+    }
+
+    pub fn to_text(&self, insns: &[BpfInsn]) -> String {
+        let mut s = format!("cbpf-branch-profile {:016x}\n", jit::hash(insns));
+        for (i, insn) in insns.iter().enumerate() {
+            if is_conditional_jump(insn) {
+                let (taken, not_taken) = self.counts[i];
+                s += &format!("{} {} {}\n", i, taken, not_taken);
+            }
+        }
+        s
+    }
+
+    pub fn parse(text: &str, insns: &[BpfInsn]) -> Result<Self, String> {
+        let mut lines = text.lines();
+        let header = format!("cbpf-branch-profile {:016x}", jit::hash(insns));
+        if lines.next().map(|l| l.trim()) != Some(header.as_str()) {
+            return Err("the profile is not for this program".to_owned());
+        }
+
+        let mut counts = vec![(0, 0); insns.len()];
+        for line in lines {
+            let fields = line.split_whitespace()
+                .map(|f| f.parse::<u64>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| format!("invalid line \"{}\": {}", line, e))?;
+            if fields.is_empty() {
+                continue;
+            }
+            if fields.len() != 3 || fields[0] as usize >= insns.len() {
+                return Err(format!("invalid line \"{}\"", line));
+            }
+            counts[fields[0] as usize] = (fields[1], fields[2]);
+        }
+        Ok(BranchProfile { counts })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text() {
+        let insns = [
+            BpfInsn::new(BPF_LD_H_ABS, 0, 0, 12),
+            BpfInsn::new(BPF_JEQ_K, 0, 1, 0x86dd),
+            BpfInsn::new(BPF_RET_K, 0, 0, 0xffff),
+            BpfInsn::new(BPF_RET_K, 0, 0, 0),
+        ];
+        let profile = BranchProfile::from_counters(&insns, &[10, 10, 1, 9], &[0, 1, 0, 0]);
+        assert_eq!(profile.counts, vec![(0, 0), (1, 9), (0, 0), (0, 0)]);
+        // taken is read later than executed
+        let racy = BranchProfile::from_counters(&insns, &[10, 10, 1, 9], &[0, 11, 0, 0]);
+        assert_eq!(racy.counts[1], (11, 0));
+
+        let text = profile.to_text(&insns);
+        assert!(text.ends_with("\n1 1 9\n"));
+        assert_eq!(BranchProfile::parse(&text, &insns), Ok(profile));
+        assert!(BranchProfile::parse(&text, &insns[1..]).is_err());
+    }
+}
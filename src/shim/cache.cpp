@@ -0,0 +1,127 @@
+// on-disk cache of objects compiled by MCJIT.
+// LLVM 5 C API does not expose ObjectCache.
+
+#include "llvm-c/ExecutionEngine.h"
+#include "llvm/ExecutionEngine/ExecutionEngine.h"
+#include "llvm/ExecutionEngine/ObjectCache.h"
+#include "llvm/Object/ObjectFile.h"
+#include "llvm/Support/MemoryBuffer.h"
+
+#include <cerrno>
+#include <cstdio>
+#include <cstdlib>
+#include <string>
+#include <unistd.h>
+
+namespace {
+
+bool is_valid_object(const llvm::MemoryBuffer &buf) {
+    auto obj = llvm::object::ObjectFile::createObjectFile(buf.getMemBufferRef());
+    if (!obj) {
+        llvm::consumeError(obj.takeError());
+        return false;
+    }
+    return true;
+}
+
+// an entry is the description of the compilation, a NUL and the object.
+// returns the object if the entry at `path` was compiled from `description`. `broken` is set
+// if it was, but the object is broken
+std::unique_ptr<llvm::MemoryBuffer> read_entry(const std::string &path,
+                                               const std::string &description, bool *broken) {
+    *broken = false;
+    auto buf = llvm::MemoryBuffer::getFile(path);
+    if (!buf) {
+        return nullptr;
+    }
+    llvm::StringRef entry = (*buf)->getBuffer();
+    size_t header = description.size() + 1;
+    if (entry.size() < header || entry.substr(0, header - 1) != description ||
+        entry[header - 1] != '\0') {
+        return nullptr;
+    }
+    // copied so that the object is aligned
+    auto obj = llvm::MemoryBuffer::getMemBufferCopy(entry.substr(header), path);
+    if (!is_valid_object(*obj)) {
+        *broken = true;
+        return nullptr;
+    }
+    return obj;
+}
+
+bool write_all(int fd, const char *p, size_t left) {
+    while (left > 0) {
+        ssize_t n = write(fd, p, left);
+        if (n < 0) {
+            if (errno == EINTR) {
+                continue;
+            }
+            return false;
+        }
+        p += n;
+        left -= n;
+    }
+    return true;
+}
+
+// the engine has only one module, so the cache has only one entry at `path`
+class FileObjectCache : public llvm::ObjectCache {
+public:
+    FileObjectCache(const char *path, const char *description)
+        : path(path), description(description) {}
+
+    void notifyObjectCompiled(const llvm::Module *, llvm::MemoryBufferRef obj) override {
+        // write to a temporary file and rename it so that other processes never see
+        // a partially written entry. threads may compile the same object at the same
+        // time, so each call has its own file
+        std::string tmp = path + ".tmp.XXXXXX";
+        int fd = mkstemp(&tmp[0]);
+        if (fd < 0) {
+            return;
+        }
+        bool written = write_all(fd, description.c_str(), description.size() + 1) &&
+                       write_all(fd, obj.getBufferStart(), obj.getBufferSize());
+        if (close(fd) != 0 || !written) {
+            std::remove(tmp.c_str());
+            return;
+        }
+        if (std::rename(tmp.c_str(), path.c_str()) != 0) {
+            std::remove(tmp.c_str());
+        }
+    }
+
+    // recompile if the entry is broken or was compiled from another description
+    std::unique_ptr<llvm::MemoryBuffer> getObject(const llvm::Module *) override {
+        bool broken;
+        return read_entry(path, description, &broken);
+    }
+
+private:
+    std::string path;
+    std::string description;
+};
+
+} // namespace
+
+// whether the entry at `path` was compiled from `description`. it is removed if it is broken
+extern "C" bool cbpf_check_cached_object(const char *path, const char *description) {
+    bool broken;
+    if (read_entry(path, description, &broken)) {
+        return true;
+    }
+    if (broken) {
+        std::remove(path);
+    }
+    return false;
+}
+
+extern "C" void *cbpf_set_object_cache(LLVMExecutionEngineRef engine, const char *path,
+                                       const char *description) {
+    auto cache = new FileObjectCache(path, description);
+    llvm::unwrap(engine)->setObjectCache(cache);
+    return cache;
+}
+
+extern "C" void cbpf_dispose_object_cache(void *cache) {
+    delete static_cast<FileObjectCache *>(cache);
+}
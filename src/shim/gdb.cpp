@@ -0,0 +1,11 @@
+// register JIT-compiled code to GDB through the GDB JIT interface.
+// LLVM 5 C API does not expose JIT event listeners.
+
+#include "llvm-c/ExecutionEngine.h"
+#include "llvm/ExecutionEngine/ExecutionEngine.h"
+#include "llvm/ExecutionEngine/JITEventListener.h"
+
+extern "C" void cbpf_register_gdb_listener(LLVMExecutionEngineRef engine) {
+    llvm::unwrap(engine)->RegisterJITEventListener(
+        llvm::JITEventListener::createGDBRegistrationListener());
+}
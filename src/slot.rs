@@ -0,0 +1,173 @@
+// atomically replaceable filter
+//
+// Readers run the current filter without locks. A writer publishes a new filter by
+// swapping the pointer and then frees the old one after all readers which may be
+// running it have left.
+// Readers register themselves in the counter of the current epoch (one of two).
+// After swapping the pointer, the writer moves to the next epoch and waits for
+// the counter of the previous epoch to become zero. Readers which register after that
+// always see the new pointer.
+
+use cbpf::opcode::BpfInsn;
+use filter::CompiledFilter;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use Options;
+
+pub struct FilterSlot {
+    current: AtomicPtr<CompiledFilter>,
+    epoch: AtomicUsize,
+    readers: [AtomicUsize; 2],
+    // writers are serialized
+    writer: Mutex<()>,
+}
+
+// leaves the epoch when dropped, so that a panicking reader does not block writers forever
+struct Reader<'a> {
+    count: &'a AtomicUsize,
+}
+
+impl<'a> Drop for Reader<'a> {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl FilterSlot {
+    pub fn new(filter: CompiledFilter) -> Self {
+        FilterSlot {
+            current: AtomicPtr::new(Box::into_raw(Box::new(filter))),
+            epoch: AtomicUsize::new(0),
+            readers: [AtomicUsize::new(0), AtomicUsize::new(0)],
+            writer: Mutex::new(()),
+        }
+    }
+
+    // call `f` with the current filter. the filter is not freed until `f` returns
+    pub fn with<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&CompiledFilter) -> R,
+    {
+        let _reader = loop {
+            let epoch = self.epoch.load(Ordering::SeqCst);
+            self.readers[epoch & 1].fetch_add(1, Ordering::SeqCst);
+            let reader = Reader {
+                count: &self.readers[epoch & 1],
+            };
+            // the writer may have missed us
+            if self.epoch.load(Ordering::SeqCst) == epoch {
+                break reader;
+            }
+        };
+        f(unsafe { &*self.current.load(Ordering::SeqCst) })
+    }
+
+    pub fn run(&self, data: &[u8]) -> u32 {
+        self.with(|f| f.run(data))
+    }
+
+    // publish the filter, and free the previous one once no reader is running it
+    pub fn store(&self, filter: CompiledFilter) {
+        let _lock = self.writer.lock().unwrap();
+        let new = Box::into_raw(Box::new(filter));
+        let old = self.current.swap(new, Ordering::SeqCst);
+        let epoch = self.epoch.fetch_add(1, Ordering::SeqCst);
+        while self.readers[epoch & 1].load(Ordering::SeqCst) != 0 {
+            thread::yield_now();
+        }
+        unsafe {
+            drop(Box::from_raw(old));
+        }
+    }
+
+    // compile the program and publish it. the filter in use is not changed on error.
+    // readers are not blocked during compilation, so this can be called from
+    // a background thread while the slot is in use
+    pub fn replace(
+        &self,
+        insns: &[BpfInsn],
+        options: Options,
+        optimization: bool,
+    ) -> Result<(), String> {
+        let filter = CompiledFilter::new(insns, options, optimization)?;
+        self.store(filter);
+        Ok(())
+    }
+}
+
+impl Drop for FilterSlot {
+    fn drop(&mut self) {
+        unsafe {
+            drop(Box::from_raw(self.current.load(Ordering::SeqCst)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cbpf::opcode::*;
+    use std::panic;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicBool;
+
+    fn ret(k: u32) -> [BpfInsn; 1] {
+        [BpfInsn::new(BPF_RET_K, 0, 0, k)]
+    }
+
+    #[test]
+    fn hot_swap() {
+        let filter = CompiledFilter::new(&ret(0), Options::default(), true).unwrap();
+        let slot = Arc::new(FilterSlot::new(filter));
+        let done = Arc::new(AtomicBool::new(false));
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let slot = slot.clone();
+                let done = done.clone();
+                thread::spawn(move || {
+                    // verdicts never go back
+                    let mut last = 0;
+                    while !done.load(Ordering::SeqCst) {
+                        let v = slot.run(&[]);
+                        assert!(v >= last && v <= 10);
+                        last = v;
+                    }
+                })
+            })
+            .collect();
+
+        for k in 1..11 {
+            slot.replace(&ret(k), Options::default(), k % 2 == 0).unwrap();
+            assert_eq!(slot.run(&[]), k);
+        }
+        done.store(true, Ordering::SeqCst);
+        for r in readers {
+            r.join().unwrap();
+        }
+
+        // the filter is not replaced on error
+        let options = Options {
+            branch_profile: Some(Default::default()),
+            ..Default::default()
+        };
+        assert!(slot.replace(&ret(11), options, true).is_err());
+        assert_eq!(slot.run(&[]), 10);
+    }
+
+    #[test]
+    fn panicking_reader() {
+        let filter = CompiledFilter::new(&ret(0), Options::default(), true).unwrap();
+        let slot = FilterSlot::new(filter);
+        let r = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            slot.with(|_| panic!("reader panics"));
+        }));
+        assert!(r.is_err());
+
+        // the writer does not wait for the panicked reader
+        let filter = CompiledFilter::new(&ret(1), Options::default(), true).unwrap();
+        slot.store(filter);
+        assert_eq!(slot.run(&[]), 1);
+    }
+}
@@ -0,0 +1,127 @@
+// execution trace
+//
+// With `Options::trace`, the generated code calls `cbpf_trace_hook(idx, A, X)` before
+// each instruction. When JIT-compiled, the hook records the steps in a thread local buffer
+// between `start()` and `finish()`, and does nothing otherwise.
+// `interpret()` produces the same trace with the interpreter (interp.rs), so the two can be
+// compared.
+
+use cbpf::opcode::BpfInsn;
+use disasm::disasm;
+use interp::Interpreter;
+use std::cell::{Cell, RefCell};
+
+#[cfg(feature = "llvm")]
+pub static HOOK_NAME: &'static str = "cbpf_trace_hook";
+
+// registers before executing the instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceStep {
+    pub idx: usize,
+    pub a: u32,
+    pub x: u32,
+}
+
+thread_local!(static TRACE: RefCell<Vec<TraceStep>> = RefCell::new(vec![]));
+// a traced filter may also run outside of `run_traced()` (e.g. in a `FilterSlot`), where
+// nobody would drain the buffer
+thread_local!(static RECORDING: Cell<bool> = Cell::new(false));
+
+pub extern "C" fn hook(idx: u32, a: u32, x: u32) {
+    if !RECORDING.with(|r| r.get()) {
+        return;
+    }
+    TRACE.with(|t| {
+        t.borrow_mut().push(TraceStep {
+            idx: idx as usize,
+            a,
+            x,
+        })
+    });
+}
+
+pub fn start() {
+    TRACE.with(|t| t.borrow_mut().clear());
+    RECORDING.with(|r| r.set(true));
+}
+
+pub fn finish() -> Vec<TraceStep> {
+    RECORDING.with(|r| r.set(false));
+    TRACE.with(|t| ::std::mem::replace(&mut *t.borrow_mut(), vec![]))
+}
+
+// run the program with the interpreter and return the trace and the result.
+// the result is None if the program is invalid
+pub fn interpret(insns: &[BpfInsn], data: &[u8]) -> (Vec<TraceStep>, Option<u32>) {
+    let interpreter = match Interpreter::new(insns, true) {
+        Ok(interpreter) => interpreter,
+        Err(_) => return (vec![], None),
+    };
+    start();
+    let result = interpreter.run(data);
+    (finish(), Some(result))
+}
+
+// one line for each step: "idx: disassembly  A=... X=..."
+pub fn format(insns: &[BpfInsn], steps: &[TraceStep]) -> String {
+    steps
+        .iter()
+        .map(|s| {
+            format!(
+                "{:4}: {:<32} A={:#010x} X={:#010x}\n",
+                s.idx,
+                disasm(&insns[s.idx]),
+                s.a,
+                s.x
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cbpf::opcode::*;
+
+    #[test]
+    fn interpret_trace() {
+        // ldh [12]; jeq #0x800, L1, L2; L1: ldx msh [14]; txa; ret a; L2: ret #0
+        let insns = [
+            BpfInsn::new(BPF_LD_H_ABS, 0, 0, 12),
+            BpfInsn::new(BPF_JEQ_K, 0, 3, 0x0800),
+            BpfInsn::new(BPF_LDX_B_MSH, 0, 0, 14),
+            BpfInsn::new(BPF_MISC_TXA, 0, 0, 0),
+            BpfInsn::new(BPF_RET_A, 0, 0, 0),
+            BpfInsn::new(BPF_RET_K, 0, 0, 0),
+        ];
+        let mut data = [0u8; 15];
+        data[12] = 0x08;
+        data[14] = 0x45;
+
+        let (steps, result) = interpret(&insns, &data);
+        assert_eq!(result, Some(20));
+        let path: Vec<_> = steps.iter().map(|s| s.idx).collect();
+        assert_eq!(path, vec![0, 1, 2, 3, 4]);
+        assert_eq!(steps[4], TraceStep { idx: 4, a: 20, x: 20 });
+        assert_eq!(
+            format(&insns, &steps[1..2]),
+            format!("   1: {:<32} A=0x00000800 X=0x00000000\n", "jeq 2048 0 3")
+        );
+
+        // out of bounds
+        let (steps, result) = interpret(&insns, &data[..13]);
+        assert_eq!(result, Some(0));
+        assert_eq!(steps.len(), 1);
+
+        // ld M[16], ldx [0] and ld msh are invalid
+        let invalid = [
+            BpfInsn::new(BPF_LD_MEM, 0, 0, 16),
+            BpfInsn::new(BPF_LDX | BPF_W | BPF_ABS, 0, 0, 0),
+            BpfInsn::new(BPF_LD | BPF_B | BPF_MSH, 0, 0, 0),
+        ];
+        for insn in &invalid {
+            let insns = [*insn, BpfInsn::new(BPF_RET_A, 0, 0, 0)];
+            assert_eq!(interpret(&insns, &data), (vec![], None));
+        }
+    }
+}
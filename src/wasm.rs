@@ -0,0 +1,455 @@
+// WebAssembly backend
+//
+// The program is encoded directly into a wasm module (no LLVM) which exports
+// `filter(ptr: i32, len: i32) -> i32` and its linear memory "memory".
+// The host writes the packet into the memory at `ptr` and calls `filter`.
+// The semantics are those of frontend.rs.
+//
+// cBPF only jumps forward, so the body is a nest of blocks, one for each jump target:
+// the instructions before the i-th target are in the innermost i blocks, and a jump to
+// the target is `br` to the end of its block.
+
+use cbpf::opcode::{BpfInsn, BPF_MEMWORDS};
+use cfg;
+use frontend::{self, AluOp, Cond, Insn, Reg, Src};
+
+// locals of filter: ptr, len (params), A, X, M[16]
+const PTR: u32 = 0;
+const LEN: u32 = 1;
+const A: u32 = 2;
+const X: u32 = 3;
+const M: u32 = 4;
+
+// functions: filter, ld_h, ld_w
+const LD_H: u32 = 1;
+const LD_W: u32 = 2;
+
+mod op {
+    pub const UNREACHABLE: u8 = 0x00;
+    pub const BLOCK: u8 = 0x02;
+    pub const IF: u8 = 0x04;
+    pub const END: u8 = 0x0b;
+    pub const BR: u8 = 0x0c;
+    pub const BR_IF: u8 = 0x0d;
+    pub const RETURN: u8 = 0x0f;
+    pub const CALL: u8 = 0x10;
+    pub const SELECT: u8 = 0x1b;
+    pub const LOCAL_GET: u8 = 0x20;
+    pub const LOCAL_SET: u8 = 0x21;
+    pub const I32_LOAD8_U: u8 = 0x2d;
+    pub const I32_CONST: u8 = 0x41;
+    pub const I64_CONST: u8 = 0x42;
+    pub const I32_EQZ: u8 = 0x45;
+    pub const I32_EQ: u8 = 0x46;
+    pub const I32_NE: u8 = 0x47;
+    pub const I32_LT_U: u8 = 0x49;
+    pub const I32_GT_U: u8 = 0x4b;
+    pub const I32_GE_U: u8 = 0x4f;
+    pub const I64_GT_U: u8 = 0x56;
+    pub const I32_ADD: u8 = 0x6a;
+    pub const I32_SUB: u8 = 0x6b;
+    pub const I32_MUL: u8 = 0x6c;
+    pub const I32_DIV_U: u8 = 0x6e;
+    pub const I32_REM_U: u8 = 0x70;
+    pub const I32_AND: u8 = 0x71;
+    pub const I32_OR: u8 = 0x72;
+    pub const I32_XOR: u8 = 0x73;
+    pub const I32_SHL: u8 = 0x74;
+    pub const I32_SHR_U: u8 = 0x76;
+    pub const I64_ADD: u8 = 0x7c;
+    pub const I64_EXTEND_I32_U: u8 = 0xad;
+}
+
+const TYPE_I32: u8 = 0x7f;
+const BLOCK_VOID: u8 = 0x40;
+
+fn uleb(buf: &mut Vec<u8>, mut v: u64) {
+    loop {
+        let b = (v & 0x7f) as u8;
+        v >>= 7;
+        if v == 0 {
+            buf.push(b);
+            return;
+        }
+        buf.push(b | 0x80);
+    }
+}
+
+fn sleb(buf: &mut Vec<u8>, mut v: i64) {
+    loop {
+        let b = (v & 0x7f) as u8;
+        v >>= 7;
+        if (v == 0 && b & 0x40 == 0) || (v == -1 && b & 0x40 != 0) {
+            buf.push(b);
+            return;
+        }
+        buf.push(b | 0x80);
+    }
+}
+
+struct Code {
+    buf: Vec<u8>,
+}
+
+impl Code {
+    fn op(&mut self, op: u8) -> &mut Self {
+        self.buf.push(op);
+        self
+    }
+
+    fn local(&mut self, op: u8, idx: u32) -> &mut Self {
+        self.buf.push(op);
+        uleb(&mut self.buf, idx as u64);
+        self
+    }
+
+    fn i32(&mut self, v: u32) -> &mut Self {
+        self.buf.push(op::I32_CONST);
+        sleb(&mut self.buf, v as i32 as i64);
+        self
+    }
+
+    fn i64(&mut self, v: u64) -> &mut Self {
+        self.buf.push(op::I64_CONST);
+        sleb(&mut self.buf, v as i64);
+        self
+    }
+
+    fn br(&mut self, op: u8, depth: usize) -> &mut Self {
+        self.buf.push(op);
+        uleb(&mut self.buf, depth as u64);
+        self
+    }
+
+    fn call(&mut self, function: u32) -> &mut Self {
+        self.buf.push(op::CALL);
+        uleb(&mut self.buf, function as u64);
+        self
+    }
+
+    // i32.load8_u with the offset
+    fn load8(&mut self, offset: u32) -> &mut Self {
+        self.buf.push(op::I32_LOAD8_U);
+        uleb(&mut self.buf, 0);
+        uleb(&mut self.buf, offset as u64);
+        self
+    }
+
+    // return 0 if the i64 on the stack > len
+    fn bounds_check(&mut self) -> &mut Self {
+        self.local(op::LOCAL_GET, LEN)
+            .op(op::I64_EXTEND_I32_U)
+            .op(op::I64_GT_U)
+            .op(op::IF)
+            .op(BLOCK_VOID)
+            .i32(0)
+            .op(op::RETURN)
+            .op(op::END)
+    }
+
+    // return 0 if the i32 on the stack is 0
+    fn zero_check(&mut self) -> &mut Self {
+        self.op(op::I32_EQZ)
+            .op(op::IF)
+            .op(BLOCK_VOID)
+            .i32(0)
+            .op(op::RETURN)
+            .op(op::END)
+    }
+
+    // push the big endian value of `size` bytes at the address on the stack
+    fn load(&mut self, size: u64) -> &mut Self {
+        match size {
+            1 => self.load8(0),
+            2 => self.call(LD_H),
+            _ => self.call(LD_W),
+        }
+    }
+}
+
+fn alu_op(op: AluOp) -> u8 {
+    match op {
+        AluOp::Add => op::I32_ADD,
+        AluOp::Sub => op::I32_SUB,
+        AluOp::Mul => op::I32_MUL,
+        AluOp::Div => op::I32_DIV_U,
+        AluOp::Mod => op::I32_REM_U,
+        AluOp::And => op::I32_AND,
+        AluOp::Or => op::I32_OR,
+        AluOp::Xor => op::I32_XOR,
+        AluOp::Lsh => op::I32_SHL,
+        AluOp::Rsh => op::I32_SHR_U,
+    }
+}
+
+fn local(reg: Reg) -> u32 {
+    match reg {
+        Reg::A => A,
+        Reg::X => X,
+    }
+}
+
+// body of filter
+fn body(insns: &[BpfInsn]) -> Result<Vec<u8>, String> {
+    let decoded = frontend::decode(insns)?;
+    // jump targets in order. targets[i] is the end of the i-th innermost block
+    let targets: Vec<usize> = cfg::leaders(insns)
+        .into_iter()
+        .enumerate()
+        .filter(|&(i, leader)| i > 0 && leader)
+        .map(|(i, _)| i)
+        .collect();
+    let mut code = Code { buf: vec![] };
+    for _ in &targets {
+        code.op(op::BLOCK).op(BLOCK_VOID);
+    }
+
+    // number of targets passed so far
+    let mut passed = 0;
+    for (idx, insn) in decoded.into_iter().enumerate() {
+        if passed < targets.len() && targets[passed] == idx {
+            code.op(op::END);
+            passed += 1;
+        }
+        // every jump target is a leader
+        let depth = |t: usize| targets.binary_search(&t).unwrap() - passed;
+        let src = |code: &mut Code, src: Src| match src {
+            Src::X => {
+                code.local(op::LOCAL_GET, X);
+            }
+            Src::K(k) => {
+                code.i32(k);
+            }
+        };
+        match insn {
+            Insn::RetA => {
+                code.local(op::LOCAL_GET, A).op(op::RETURN);
+            }
+            Insn::RetK(k) => {
+                code.i32(k).op(op::RETURN);
+            }
+            Insn::LdAbs { size, k } => {
+                code.i64(k as u64 + size).bounds_check();
+                code.local(op::LOCAL_GET, PTR)
+                    .i32(k)
+                    .op(op::I32_ADD)
+                    .load(size)
+                    .local(op::LOCAL_SET, A);
+            }
+            Insn::LdInd { size, k } => {
+                // X + k does not wrap around
+                code.local(op::LOCAL_GET, X)
+                    .op(op::I64_EXTEND_I32_U)
+                    .i64(k as u64 + size)
+                    .op(op::I64_ADD)
+                    .bounds_check();
+                code.local(op::LOCAL_GET, PTR)
+                    .local(op::LOCAL_GET, X)
+                    .op(op::I32_ADD)
+                    .i32(k)
+                    .op(op::I32_ADD)
+                    .load(size)
+                    .local(op::LOCAL_SET, A);
+            }
+            Insn::LdxMsh(k) => {
+                code.i64(k as u64 + 1).bounds_check();
+                code.local(op::LOCAL_GET, PTR)
+                    .i32(k)
+                    .op(op::I32_ADD)
+                    .load8(0)
+                    .i32(0xf)
+                    .op(op::I32_AND)
+                    .i32(2)
+                    .op(op::I32_SHL)
+                    .local(op::LOCAL_SET, X);
+            }
+            Insn::Imm(reg, k) => {
+                code.i32(k).local(op::LOCAL_SET, local(reg));
+            }
+            Insn::Len(reg) => {
+                code.local(op::LOCAL_GET, LEN).local(op::LOCAL_SET, local(reg));
+            }
+            Insn::LdMem(reg, i) => {
+                code.local(op::LOCAL_GET, M + i as u32)
+                    .local(op::LOCAL_SET, local(reg));
+            }
+            Insn::St(reg, i) => {
+                code.local(op::LOCAL_GET, local(reg))
+                    .local(op::LOCAL_SET, M + i as u32);
+            }
+            Insn::Alu(alu, s) => {
+                match alu {
+                    AluOp::Div | AluOp::Mod => {
+                        src(&mut code, s);
+                        code.zero_check().local(op::LOCAL_GET, A);
+                        src(&mut code, s);
+                        code.op(alu_op(alu));
+                    }
+                    // wasm takes the shift count modulo 32
+                    AluOp::Lsh | AluOp::Rsh => {
+                        code.local(op::LOCAL_GET, A);
+                        src(&mut code, s);
+                        code.op(alu_op(alu)).i32(0);
+                        src(&mut code, s);
+                        code.i32(32).op(op::I32_LT_U).op(op::SELECT);
+                    }
+                    _ => {
+                        code.local(op::LOCAL_GET, A);
+                        src(&mut code, s);
+                        code.op(alu_op(alu));
+                    }
+                }
+                code.local(op::LOCAL_SET, A);
+            }
+            Insn::Neg => {
+                code.i32(0)
+                    .local(op::LOCAL_GET, A)
+                    .op(op::I32_SUB)
+                    .local(op::LOCAL_SET, A);
+            }
+            Insn::Ja(t) => {
+                code.br(op::BR, depth(t));
+            }
+            Insn::Jmp { cond, src: s, jt, jf } => {
+                code.local(op::LOCAL_GET, A);
+                src(&mut code, s);
+                match cond {
+                    Cond::Eq => code.op(op::I32_EQ),
+                    Cond::Gt => code.op(op::I32_GT_U),
+                    Cond::Ge => code.op(op::I32_GE_U),
+                    Cond::Set => code.op(op::I32_AND).i32(0).op(op::I32_NE),
+                };
+                code.br(op::BR_IF, depth(jt)).br(op::BR, depth(jf));
+            }
+            Insn::Tax => {
+                code.local(op::LOCAL_GET, A).local(op::LOCAL_SET, X);
+            }
+            Insn::Txa => {
+                code.local(op::LOCAL_GET, X).local(op::LOCAL_SET, A);
+            }
+        }
+    }
+    // every path returns
+    code.op(op::UNREACHABLE).op(op::END);
+    Ok(code.buf)
+}
+
+// ld_h(addr) and ld_w(addr): big endian loads
+fn helper(size: u32) -> Vec<u8> {
+    let mut code = Code { buf: vec![] };
+    for i in 0..size {
+        code.local(op::LOCAL_GET, 0).load8(i);
+        let shift = (size - 1 - i) * 8;
+        if shift > 0 {
+            code.i32(shift).op(op::I32_SHL);
+        }
+        if i > 0 {
+            code.op(op::I32_OR);
+        }
+    }
+    code.op(op::END);
+    code.buf
+}
+
+fn section(module: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    module.push(id);
+    uleb(module, contents.len() as u64);
+    module.extend_from_slice(contents);
+}
+
+fn name(buf: &mut Vec<u8>, name: &str) {
+    uleb(buf, name.len() as u64);
+    buf.extend_from_slice(name.as_bytes());
+}
+
+// wasm module of the program. the program must end with a return
+pub fn to_wasm(insns: &[BpfInsn]) -> Result<Vec<u8>, String> {
+    let mut module = b"\0asm\x01\0\0\0".to_vec();
+    // types: (i32, i32) -> i32, (i32) -> i32
+    section(
+        &mut module,
+        1,
+        &[2, 0x60, 2, TYPE_I32, TYPE_I32, 1, TYPE_I32, 0x60, 1, TYPE_I32, 1, TYPE_I32],
+    );
+    // functions: filter, ld_h, ld_w
+    section(&mut module, 3, &[3, 0, 1, 1]);
+    // memory: at least 1 page, growable by the host
+    section(&mut module, 5, &[1, 0, 1]);
+    // exports
+    let mut exports = vec![2];
+    name(&mut exports, "filter");
+    exports.extend_from_slice(&[0, 0]);
+    name(&mut exports, "memory");
+    exports.extend_from_slice(&[2, 0]);
+    section(&mut module, 7, &exports);
+    // code
+    let mut filter = vec![];
+    // A, X and M[] are i32 locals
+    uleb(&mut filter, 1);
+    uleb(&mut filter, 2 + BPF_MEMWORDS as u64);
+    filter.push(TYPE_I32);
+    filter.extend(body(insns)?);
+    let mut code = vec![3];
+    for f in &[filter, [&[0][..], &helper(2)[..]].concat(), [&[0][..], &helper(4)[..]].concat()] {
+        uleb(&mut code, f.len() as u64);
+        code.extend_from_slice(f);
+    }
+    section(&mut module, 10, &code);
+    Ok(module)
+}
+
+// run the module with wasmi. the packet is not at the start of the memory
+#[cfg(test)]
+pub fn run_wasmi(module: &[u8], packet: &[u8]) -> u32 {
+    use wasmi::{ImportsBuilder, ModuleInstance, NopExternals, RuntimeValue};
+
+    let module = wasmi::Module::from_buffer(module).unwrap();
+    let instance = ModuleInstance::new(&module, &ImportsBuilder::default())
+        .unwrap()
+        .assert_no_start();
+    let memory = instance
+        .export_by_name("memory")
+        .unwrap()
+        .as_memory()
+        .unwrap()
+        .clone();
+    let ptr = 100;
+    memory.set(ptr, packet).unwrap();
+    let args = [
+        RuntimeValue::I32(ptr as i32),
+        RuntimeValue::I32(packet.len() as i32),
+    ];
+    match instance
+        .invoke_export("filter", &args, &mut NopExternals)
+        .unwrap()
+    {
+        Some(RuntimeValue::I32(v)) => v as u32,
+        v => panic!("unexpected result: {:?}", v),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cbpf::opcode::*;
+
+    #[test]
+    fn leb128() {
+        let mut buf = vec![];
+        uleb(&mut buf, 624485);
+        assert_eq!(buf, [0xe5, 0x8e, 0x26]);
+        buf.clear();
+        sleb(&mut buf, -123456);
+        assert_eq!(buf, [0xc0, 0xbb, 0x78]);
+        buf.clear();
+        sleb(&mut buf, 0xffff_ffffu32 as i32 as i64);
+        assert_eq!(buf, [0x7f]);
+    }
+
+    // the modules are run by frontend.rs
+    #[test]
+    fn invalid() {
+        let insns = [BpfInsn::new(BPF_LD | BPF_W | BPF_MEM, 0, 0, 16)];
+        assert!(to_wasm(&insns).is_err());
+    }
+}
@@ -0,0 +1,95 @@
+// link filters compiled by `Converter::emit_object()` into one program,
+// as `cbpf2ir build -f ip=... -f arp=...` does
+#![cfg(feature = "llvm")]
+
+extern crate cbpf;
+extern crate cbpf_to_llvm_ir;
+extern crate libc;
+
+mod common;
+
+use cbpf::opcode::*;
+use cbpf_to_llvm_ir::{c_header, write_archive, Converter};
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::process::Command;
+
+// ldh [12]; jeq #ethertype, L1, L2; L1: ret #1; L2: ret #0
+fn ethertype(ethertype: u32) -> Vec<BpfInsn> {
+    vec![
+        BpfInsn::new(BPF_LD_H_ABS, 0, 0, 12),
+        BpfInsn::new(BPF_JEQ_K, 0, 1, ethertype),
+        BpfInsn::new(BPF_RET_K, 0, 0, 1),
+        BpfInsn::new(BPF_RET_K, 0, 0, 0),
+    ]
+}
+
+static MAIN: &'static str = r#"
+#include "filters.h"
+
+int main(void) {
+    uint8_t ip[14] = {0}, arp[14] = {0};
+    ip[12] = 0x08;
+    arp[12] = 0x08;
+    arp[13] = 0x06;
+    return !(filter_ip(ip, 14) == 1 && filter_ip(arp, 14) == 0 &&
+             filter_arp(arp, 14) == 1 && filter_arp(ip, 14) == 0 &&
+             filter_ip(ip, 13) == 0);
+}
+"#;
+
+#[test]
+fn link_two_filters() {
+    let cc = match common::cc() {
+        Some(cc) => cc,
+        None => {
+            eprintln!("link_two_filters: skipped since no C compiler is found (set CC)");
+            return;
+        }
+    };
+
+    let dir = env::temp_dir().join(format!("cbpf-aot-{}", unsafe { libc::getpid() }));
+    fs::create_dir_all(&dir).unwrap();
+    let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+    fs::File::create(path("main.c"))
+        .unwrap()
+        .write_all(MAIN.as_bytes())
+        .unwrap();
+
+    // unoptimized objects define the same helpers, and optimized ones have deleted some
+    for &optimization in &[false, true] {
+        let filters = vec![
+            ("ip".to_owned(), "ip".to_owned()),
+            ("arp".to_owned(), "arp".to_owned()),
+        ];
+        let mut objects = vec![];
+        for &(ref name, k) in &[("ip", 0x0800), ("arp", 0x0806)] {
+            let mut converter = Converter::new();
+            converter.convert(&ethertype(k), optimization).unwrap();
+            let object = path(&format!("{}.o", name));
+            converter
+                .emit_object(&format!("filter_{}", name), &object)
+                .unwrap();
+            objects.push(object);
+        }
+        write_archive(&path("libfilters.a"), &objects).unwrap();
+        fs::File::create(path("filters.h"))
+            .unwrap()
+            .write_all(c_header("FILTERS_H", &filters).as_bytes())
+            .unwrap();
+
+        let status = Command::new(&cc)
+            .arg(path("main.c"))
+            .arg(path("libfilters.a"))
+            .arg("-o")
+            .arg(path("main"))
+            .status()
+            .unwrap();
+        assert!(status.success(), "failed to link (optimization: {})", optimization);
+        let status = Command::new(path("main")).status().unwrap();
+        assert!(status.success(), "wrong verdicts (optimization: {})", optimization);
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,13 @@
+// helpers shared by the integration tests
+
+use std::env;
+use std::process::Command;
+
+// C compiler to build the test programs, if any
+pub fn cc() -> Option<String> {
+    let cc = env::var("CC").unwrap_or_else(|_| "cc".to_owned());
+    match Command::new(&cc).arg("--version").output() {
+        Ok(ref output) if output.status.success() => Some(cc),
+        _ => None,
+    }
+}
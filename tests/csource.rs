@@ -0,0 +1,144 @@
+// C programs generated by `to_c()`, compiled with the system C compiler
+
+extern crate cbpf;
+extern crate cbpf_to_llvm_ir;
+extern crate libc;
+
+mod common;
+
+use cbpf::interpreter::{Interpreter, Simple};
+use cbpf::opcode::*;
+use cbpf_to_llvm_ir::to_c;
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::process::Command;
+
+fn programs() -> Vec<Vec<BpfInsn>> {
+    vec![
+        // ldh [12]; jeq #0x800, L1, L4; L1: ldxb 4*([14]&0xf); ldh [x+16];
+        // jge #40, L2, L4; L2: ld len; ret a; L4: ret #0
+        vec![
+            BpfInsn::new(BPF_LD_H_ABS, 0, 0, 12),
+            BpfInsn::new(BPF_JEQ_K, 0, 5, 0x0800),
+            BpfInsn::new(BPF_LDX_B_MSH, 0, 0, 14),
+            BpfInsn::new(BPF_LD_H_IND, 0, 0, 16),
+            BpfInsn::new(BPF_JGE_K, 0, 2, 40),
+            BpfInsn::new(BPF_LD | BPF_W | BPF_LEN, 0, 0, 0),
+            BpfInsn::new(BPF_RET_A, 0, 0, 0),
+            BpfInsn::new(BPF_RET_K, 0, 0, 0),
+        ],
+        // unsigned arithmetic
+        vec![
+            BpfInsn::new(BPF_LD_W_ABS, 0, 0, 0),
+            BpfInsn::new(BPF_ST, 0, 0, 3),
+            BpfInsn::new(BPF_RSH_K, 0, 0, 4),
+            BpfInsn::new(BPF_DIV_K, 0, 0, 3),
+            BpfInsn::new(BPF_MISC_TAX, 0, 0, 0),
+            BpfInsn::new(BPF_LD_MEM, 0, 0, 3),
+            BpfInsn::new(BPF_MOD_X, 0, 0, 0),
+            BpfInsn::new(BPF_ALU_NEG, 0, 0, 0),
+            BpfInsn::new(BPF_JGT_K, 0, 2, 0x8000_0000),
+            BpfInsn::new(BPF_LSH_K, 0, 0, 1),
+            BpfInsn::new(BPF_JMP_JA, 0, 0, 1),
+            BpfInsn::new(BPF_XOR_K, 0, 0, 0xdead_beef),
+            BpfInsn::new(BPF_JSET_X, 0, 1, 0),
+            BpfInsn::new(BPF_RET_A, 0, 0, 0),
+            BpfInsn::new(BPF_RET_K, 0, 0, 1),
+        ],
+        // division by zero
+        vec![
+            BpfInsn::new(BPF_LD_B_ABS, 0, 0, 0),
+            BpfInsn::new(BPF_MISC_TAX, 0, 0, 0),
+            BpfInsn::new(BPF_LD_IMM, 0, 0, 100),
+            BpfInsn::new(BPF_DIV_X, 0, 0, 0),
+            BpfInsn::new(BPF_RET_A, 0, 0, 0),
+        ],
+    ]
+}
+
+fn packets() -> Vec<Vec<u8>> {
+    let mut ipv4 = vec![0u8; 60];
+    ipv4[12] = 0x08;
+    ipv4[14] = 0x45;
+    ipv4[34] = 0x01;
+    let mut short = ipv4.clone();
+    short.truncate(35);
+    vec![
+        ipv4,
+        short,
+        vec![0xff, 0xff, 0xff, 0xff],
+        vec![0x80, 0x00, 0x00, 0x07],
+        vec![0x12, 0x34, 0x56, 0x78],
+        vec![0],
+        vec![],
+    ]
+}
+
+// compile the C source with the system cc and compare the results with `Simple`
+#[test]
+fn compile_with_cc() {
+    let cc = match common::cc() {
+        Some(cc) => cc,
+        None => {
+            eprintln!("compile_with_cc: skipped since no C compiler is found (set CC)");
+            return;
+        }
+    };
+
+    let dir = env::temp_dir().join(format!("cbpf-c-{}", unsafe { libc::getpid() }));
+    fs::create_dir_all(&dir).unwrap();
+    let source = dir.join("filter.c");
+    let exe = dir.join("filter");
+
+    let mut c = String::new();
+    let mut expected = String::new();
+    for (i, insns) in programs().iter().enumerate() {
+        c += &to_c(insns, &format!("filter_{}", i)).unwrap();
+        for packet in packets() {
+            expected += &format!("{}\n", Simple::run(insns, &packet).unwrap());
+        }
+    }
+    c += "#include <stdio.h>\n\nint main(void)\n{\n";
+    for (i, packet) in packets().iter().enumerate() {
+        let bytes: Vec<_> = packet.iter().map(|b| b.to_string()).collect();
+        c += &format!(
+            "    static const uint8_t p{}[] = {{{}}};\n",
+            i,
+            if bytes.is_empty() {
+                "0".to_owned()
+            } else {
+                bytes.join(", ")
+            }
+        );
+    }
+    for i in 0..programs().len() {
+        for (j, packet) in packets().iter().enumerate() {
+            c += &format!(
+                "    printf(\"%u\\n\", filter_{}(p{}, {}));\n",
+                i,
+                j,
+                packet.len()
+            );
+        }
+    }
+    c += "    return 0;\n}\n";
+    fs::File::create(&source)
+        .unwrap()
+        .write_all(c.as_bytes())
+        .unwrap();
+
+    let status = Command::new(&cc)
+        .arg("-std=c99")
+        .arg("-Wall")
+        .arg("-Werror")
+        .arg("-o")
+        .arg(&exe)
+        .arg(&source)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let output = Command::new(&exe).output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stdout), expected);
+    fs::remove_dir_all(&dir).unwrap();
+}